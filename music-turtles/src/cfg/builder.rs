@@ -0,0 +1,198 @@
+// Building music strings in code, as an alternative to writing them out as text.
+
+use crate::cfg::{Articulation, ChordQuality, MetaControl, MusicPrimitive, MusicString, MusicTransform, NonTerminal, Symbol, Terminal, TerminalNote};
+use crate::composition::{Instrument, Pitch, Volume};
+use crate::time::{Beat, MusicTime, TimeCompression, BPM};
+
+/// Builds a `MusicString` one primitive at a time, giving the same tree that scanning the
+/// equivalent text would. For example, `:4c :_<2> {::i=bass :3c | :4e}` is
+/// ```ignore
+/// MusicStringBuilder::new()
+///     .note(Pitch(4, 3), Beat::whole(1))
+///     .rest(Beat::whole(2))
+///     .split(vec![
+///         MusicStringBuilder::new().instrument(Instrument::Bass).note(Pitch(3, 3), Beat::whole(1)).build(),
+///         MusicStringBuilder::new().note(Pitch(4, 7), Beat::whole(1)).build(),
+///     ])
+///     .build()
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MusicStringBuilder {
+    primitives: Vec<MusicPrimitive>,
+}
+
+impl MusicStringBuilder {
+    pub fn new() -> Self {
+        MusicStringBuilder::default()
+    }
+
+    pub fn push(mut self, primitive: MusicPrimitive) -> Self {
+        self.primitives.push(primitive);
+        self
+    }
+
+    /// Add everything in `string`, as though it was written here.
+    pub fn extend(mut self, string: MusicString) -> Self {
+        self.primitives.extend(string.0);
+        self
+    }
+
+    fn terminal(self, terminal: Terminal) -> Self {
+        self.push(MusicPrimitive::Simple(Symbol::T(terminal)))
+    }
+
+    fn music(self, note: TerminalNote, duration: Beat, articulation: Articulation) -> Self {
+        self.terminal(Terminal::Music { note, duration: MusicTime(0, duration), articulation, implicit_duration: false })
+    }
+
+    fn transform(self, transform: MusicTransform, content: MusicString) -> Self {
+        self.push(MusicPrimitive::Transform { transform, content })
+    }
+
+    /// `:4c<duration>`
+    pub fn note(self, pitch: Pitch, duration: Beat) -> Self {
+        self.music(TerminalNote::Note { pitch }, duration, Articulation::Normal)
+    }
+
+    /// `:4c<duration>.` or `:4c<duration>_`
+    pub fn articulated_note(self, pitch: Pitch, duration: Beat, articulation: Articulation) -> Self {
+        self.music(TerminalNote::Note { pitch }, duration, articulation)
+    }
+
+    /// `:cM<duration>`
+    pub fn chord(self, root: Pitch, quality: ChordQuality, duration: Beat) -> Self {
+        self.music(TerminalNote::Chord { root, quality, inversion: 0, spread: false }, duration, Articulation::Normal)
+    }
+
+    /// `:_<duration>`
+    pub fn rest(self, duration: Beat) -> Self {
+        self.music(TerminalNote::Rest, duration, Articulation::Normal)
+    }
+
+    /// `:?max_step<duration>`
+    pub fn random_walk(self, max_step: u8, duration: Beat) -> Self {
+        self.music(TerminalNote::RandomWalk { max_step }, duration, Articulation::Normal)
+    }
+
+    /// A non-terminal, to be rewritten by a grammar.
+    pub fn non_terminal(self, name: &str) -> Self {
+        self.push(MusicPrimitive::Simple(Symbol::NT(NonTerminal::Custom(name.to_string()))))
+    }
+
+    pub fn meta(self, control: MetaControl) -> Self {
+        self.terminal(Terminal::Meta(control))
+    }
+
+    /// `::i=`
+    pub fn instrument(self, instrument: Instrument) -> Self {
+        self.meta(MetaControl::ChangeInstrument(instrument))
+    }
+
+    /// `::v=`
+    pub fn volume(self, volume: Volume) -> Self {
+        self.meta(MetaControl::ChangeVolume(volume))
+    }
+
+    /// `::t=`
+    pub fn tempo(self, bpm: BPM) -> Self {
+        self.meta(MetaControl::ChangeTempo(bpm))
+    }
+
+    /// `::dur=`, though notes from this builder always have their own duration.
+    pub fn default_duration(self, duration: Beat) -> Self {
+        self.meta(MetaControl::DefaultDuration(duration))
+    }
+
+    /// `|`
+    pub fn bar_check(self) -> Self {
+        self.terminal(Terminal::BarCheck)
+    }
+
+    /// `{ a | b }`: the branches play at the same time.
+    pub fn split(self, branches: Vec<MusicString>) -> Self {
+        self.push(MusicPrimitive::Split { branches, ragged: false })
+    }
+
+    /// `{< a | b }`: a split whose branches may have different lengths.
+    pub fn ragged_split(self, branches: Vec<MusicString>) -> Self {
+        self.push(MusicPrimitive::Split { branches, ragged: true })
+    }
+
+    /// `[num][content]`
+    pub fn repeat(self, num: usize, content: MusicString) -> Self {
+        self.transform(MusicTransform::Repeat { num, step: 0, alternate: false, vary_volume: false }, content)
+    }
+
+    /// `[num+step][content]`: repeats that are each `step` semitones above the last.
+    pub fn stepped_repeat(self, num: usize, step: i8, content: MusicString) -> Self {
+        self.transform(MusicTransform::Repeat { num, step, alternate: false, vary_volume: false }, content)
+    }
+
+    /// `[num~][content]`: repeats that go backwards every other time.
+    pub fn alternating_repeat(self, num: usize, content: MusicString) -> Self {
+        self.transform(MusicTransform::Repeat { num, step: 0, alternate: true, vary_volume: false }, content)
+    }
+
+    /// `[num!][content]`: repeats that are each a little louder or softer, see `ComposeOptions::seed`.
+    pub fn varied_repeat(self, num: usize, content: MusicString) -> Self {
+        self.transform(MusicTransform::Repeat { num, step: 0, alternate: false, vary_volume: true }, content)
+    }
+
+    /// `[Tsemitones][content]`
+    pub fn transpose(self, semitones: i8, content: MusicString) -> Self {
+        self.transform(MusicTransform::Transpose { semitones }, content)
+    }
+
+    /// `content` with its length scaled by `factor`, so `[>>2][content]` is a factor of 1/2.
+    pub fn compress(self, factor: TimeCompression, content: MusicString) -> Self {
+        self.transform(MusicTransform::Compression { factor }, content)
+    }
+
+    pub fn build(self) -> MusicString {
+        MusicString(self.primitives)
+    }
+}
+
+impl From<MusicStringBuilder> for MusicString {
+    fn from(builder: MusicStringBuilder) -> Self {
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::builder::MusicStringBuilder;
+    use crate::cfg::{ChordQuality, MusicString};
+    use crate::composition::{Instrument, Pitch, Volume};
+    use crate::time::{Beat, TimeSignature};
+
+    #[test]
+    fn test_built_string_composes_like_parsed() {
+        let parsed = MusicString::from_str(
+            ":4c<1> :_<2> [2][:4e<1/2> :4g<1/2>] [T2][:4c<1>] {::i=bass ::v=50 :3c<2> | :cM<2>}"
+        ).unwrap();
+        let built = MusicStringBuilder::new()
+            .note(Pitch(4, 3), Beat::whole(1))
+            .rest(Beat::whole(2))
+            .repeat(2, MusicStringBuilder::new()
+                .note(Pitch(4, 7), Beat::new(1, 2))
+                .note(Pitch(4, 10), Beat::new(1, 2))
+                .build())
+            .transpose(2, MusicStringBuilder::new().note(Pitch(4, 3), Beat::whole(1)).build())
+            .split(vec![
+                MusicStringBuilder::new()
+                    .instrument(Instrument::Bass)
+                    .volume(Volume(50))
+                    .note(Pitch(3, 3), Beat::whole(2))
+                    .build(),
+                MusicStringBuilder::new().chord(Pitch(4, 3), ChordQuality::Major, Beat::whole(2)).build(),
+            ])
+            .build();
+        assert_eq!(built.to_string(), parsed.to_string());
+        assert_eq!(
+            built.compose(TimeSignature::common(), None).unwrap(),
+            parsed.compose(TimeSignature::common(), None).unwrap()
+        );
+    }
+}
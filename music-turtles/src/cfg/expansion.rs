@@ -0,0 +1,159 @@
+// Rewriting that remembers how each non-terminal was rewritten, so that a small change
+// to a grammar only redoes the parts of the string that it affects.
+
+use rand::Rng;
+use crate::cfg::{Grammar, MusicPrimitive, MusicString, NonTerminal, Production, Symbol, RANDOM_WALK_START};
+
+/// A non-terminal rewritten `depth` times, remembering the production picked for it
+/// and what each non-terminal in that production was rewritten into.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    non_terminal: NonTerminal,
+    /// which rewrite this is, for `DepthCondition`s
+    iteration: usize,
+    depth: usize,
+    /// `None` when `depth` is 0, so it's left as it is
+    rewritten: Option<(MusicString, Vec<Expansion>)>,
+}
+
+impl Expansion {
+    fn new<R: Rng + ?Sized>(non_terminal: NonTerminal, iteration: usize, depth: usize, grammar: &Grammar, rng: &mut R) -> Self {
+        let rewritten = (depth > 0).then(|| match grammar.get_production_random_at(&non_terminal, iteration, rng) {
+            Some(Production(_nt, body, _condition)) => {
+                let children = non_terminals(body).into_iter()
+                    .map(|nt| Expansion::new(nt, iteration + 1, depth - 1, grammar, rng))
+                    .collect();
+                (body.clone(), children)
+            }
+            // like `parallel_rewrite`, a non-terminal with no productions is dropped
+            None => (MusicString(vec![]), vec![]),
+        });
+        Expansion { non_terminal, iteration, depth, rewritten }
+    }
+
+    /// Rewrite the non-terminals in `changed` again, keeping how everything else was rewritten.
+    /// Returns how many were rewritten again.
+    fn update<R: Rng + ?Sized>(&mut self, grammar: &Grammar, changed: &[NonTerminal], rng: &mut R) -> usize {
+        if self.depth > 0 && changed.contains(&self.non_terminal) {
+            *self = Expansion::new(self.non_terminal.clone(), self.iteration, self.depth, grammar, rng);
+            return 1;
+        }
+        match &mut self.rewritten {
+            Some((_body, children)) => children.iter_mut()
+                .map(|child| child.update(grammar, changed, rng))
+                .sum(),
+            None => 0,
+        }
+    }
+
+    fn to_music_string(&self) -> MusicString {
+        match &self.rewritten {
+            Some((body, children)) => splice(body, &mut children.iter().map(|c| c.to_music_string())),
+            None => MusicString(vec![MusicPrimitive::Simple(Symbol::NT(self.non_terminal.clone()))]),
+        }
+    }
+}
+
+/// An axiom rewritten with a grammar, like `MusicString::parallel_rewrite_n` with `random` set,
+/// that can be brought up to date with a changed grammar by rewriting only the non-terminals
+/// whose productions changed. Everything else keeps the productions it was given before.
+#[derive(Debug, Clone)]
+pub struct ExpandedString {
+    axiom: MusicString,
+    expansions: Vec<Expansion>,
+}
+
+impl ExpandedString {
+    pub fn new<R: Rng + ?Sized>(axiom: &MusicString, grammar: &Grammar, iterations: usize, rng: &mut R) -> Self {
+        let expansions = non_terminals(axiom).into_iter()
+            .map(|nt| Expansion::new(nt, 0, iterations, grammar, rng))
+            .collect();
+        ExpandedString { axiom: axiom.clone(), expansions }
+    }
+
+    /// Catch up with `grammar`, which has different productions for the `changed` non-terminals
+    /// (see `Grammar::changed_non_terminals`). Returns how many non-terminals were rewritten again.
+    pub fn update<R: Rng + ?Sized>(&mut self, grammar: &Grammar, changed: &[NonTerminal], rng: &mut R) -> usize {
+        self.expansions.iter_mut()
+            .map(|expansion| expansion.update(grammar, changed, rng))
+            .sum()
+    }
+
+    /// The rewritten string, with its random walks resolved using `rng` and then simplified.
+    pub fn to_music_string<R: Rng + ?Sized>(&self, rng: &mut R) -> MusicString {
+        let mut string = splice(&self.axiom, &mut self.expansions.iter().map(|e| e.to_music_string()));
+        let mut last = RANDOM_WALK_START;
+        string.resolve_random_walks(&mut last, rng);
+        string.simplify();
+        string
+    }
+}
+
+/// Every non-terminal in `string`, in the order `splice` replaces them.
+fn non_terminals(string: &MusicString) -> Vec<NonTerminal> {
+    let mut found = vec![];
+    for mp in &string.0 {
+        match mp {
+            MusicPrimitive::Simple(Symbol::NT(nt)) => found.push(nt.clone()),
+            MusicPrimitive::Simple(_) => {}
+            MusicPrimitive::Split { branches, .. } => branches.iter().for_each(|b| found.extend(non_terminals(b))),
+            MusicPrimitive::Repeat { content, .. } | MusicPrimitive::Transform { content, .. } => found.extend(non_terminals(content)),
+        }
+    }
+    found
+}
+
+/// `string` with each of its non-terminals replaced by the next of `replacements`, in place.
+fn splice(string: &MusicString, replacements: &mut impl Iterator<Item = MusicString>) -> MusicString {
+    let mut spliced = vec![];
+    for mp in &string.0 {
+        match mp {
+            MusicPrimitive::Simple(Symbol::NT(_nt)) => spliced.extend(replacements.next().map(|r| r.0).unwrap_or_default()),
+            MusicPrimitive::Simple(_) => spliced.push(mp.clone()),
+            MusicPrimitive::Split { branches, ragged } => spliced.push(MusicPrimitive::Split {
+                branches: branches.iter().map(|b| splice(b, replacements)).collect(),
+                ragged: *ragged,
+            }),
+            MusicPrimitive::Repeat { num, content } => spliced.push(MusicPrimitive::Repeat {
+                num: *num,
+                content: splice(content, replacements),
+            }),
+            MusicPrimitive::Transform { transform, content } => spliced.push(MusicPrimitive::Transform {
+                transform: transform.clone(),
+                content: splice(content, replacements),
+            }),
+        }
+    }
+    MusicString(spliced)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::cfg::expansion::ExpandedString;
+    use crate::cfg::{Grammar, MusicString, NonTerminal};
+
+    #[test]
+    fn test_update_rewrites_only_changed() {
+        let old = Grammar::from_str("start S\nS = A B {A | [x2][B]}\nA = :c B\nA = :d B\nB = :e").unwrap();
+        let new = Grammar::from_str("start S\nS = A B {A | [x2][B]}\nA = :c B\nA = :d B\nB = :f").unwrap();
+        let changed = old.changed_non_terminals(&new);
+        assert_eq!(changed, vec![NonTerminal::Custom("B".to_string())]);
+
+        let axiom = MusicString::from_str("S").unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut expanded = ExpandedString::new(&axiom, &old, 3, &mut rng);
+        let before = expanded.to_music_string(&mut rng).to_string();
+        assert!(!before.contains('f'));
+        assert_eq!(expanded.update(&old, &[], &mut rng), 0);
+        assert_eq!(expanded.to_music_string(&mut rng).to_string(), before);
+
+        // one B in S, one in the repeat and one in each A
+        assert_eq!(expanded.update(&new, &changed, &mut rng), 4);
+        let after = expanded.to_music_string(&mut rng).to_string();
+        // the A's kept whichever production they had
+        assert_eq!(after, before.replace(":E", ":F"));
+    }
+}
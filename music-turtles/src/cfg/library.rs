@@ -0,0 +1,157 @@
+// A directory of grammars that can be switched between while playing.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use crate::cfg::scan::ScanError;
+use crate::cfg::{GenerateError, Grammar, NonTerminal};
+use crate::composition::Composition;
+use crate::time::TimeSignature;
+
+/// File extensions that are loaded as grammars.
+pub const GRAMMAR_EXTENSIONS: [&str; 2] = ["mtx", "grm"];
+
+/// Grammars loaded from a directory, keyed by file name without the extension.
+#[derive(Debug, Clone, Default)]
+pub struct GrammarLibrary {
+    grammars: HashMap<String, Grammar>,
+}
+
+#[derive(Debug)]
+pub enum LibraryError {
+    Io(std::io::Error),
+    Scan { name: String, error: ScanError },
+    UnknownGrammar(String),
+    Generate(GenerateError),
+    /// An included file has productions for a non-terminal that the including file already has.
+    DuplicateNonTerminal { name: String, file: PathBuf },
+    /// A file includes itself, maybe through other files.
+    IncludeCycle(PathBuf),
+}
+
+impl From<std::io::Error> for LibraryError {
+    fn from(value: std::io::Error) -> Self {
+        LibraryError::Io(value)
+    }
+}
+
+impl From<GenerateError> for LibraryError {
+    fn from(value: GenerateError) -> Self {
+        LibraryError::Generate(value)
+    }
+}
+
+/// Load a grammar file, along with everything it `@include`s.
+/// Includes are resolved relative to the file that includes them.
+pub fn load_grammar(path: impl AsRef<Path>) -> Result<Grammar, LibraryError> {
+    load_grammar_including(path.as_ref(), &mut vec![])
+}
+
+/// `load_grammar`, where `including` is the files that are part way through loading.
+fn load_grammar_including(path: &Path, including: &mut Vec<PathBuf>) -> Result<Grammar, LibraryError> {
+    let canonical = path.canonicalize()?;
+    if including.contains(&canonical) {
+        return Err(LibraryError::IncludeCycle(path.to_path_buf()));
+    }
+    let contents = std::fs::read_to_string(path)?;
+    let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+    let mut grammar = Grammar::from_str(&contents)
+        .map_err(|error| LibraryError::Scan { name, error })?;
+    let includes = grammar.meta().map(|m| m.includes.clone()).unwrap_or_default();
+    including.push(canonical);
+    for include in includes {
+        let file = path.parent().unwrap_or(Path::new("")).join(include);
+        let included = load_grammar_including(&file, including)?;
+        grammar.include(included)
+            .map_err(|NonTerminal::Custom(name)| LibraryError::DuplicateNonTerminal { name, file })?;
+    }
+    including.pop();
+    Ok(grammar)
+}
+
+impl GrammarLibrary {
+    /// Load every grammar file in `dir`. Other files are ignored.
+    /// Fails if any grammar file can't be read or scanned.
+    pub fn load_dir(dir: impl AsRef<Path>) -> Result<Self, LibraryError> {
+        let mut grammars = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_grammar = path.extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| GRAMMAR_EXTENSIONS.contains(&e));
+            let name = path.file_stem().and_then(|s| s.to_str());
+            if let (true, Some(name)) = (is_grammar, name) {
+                grammars.insert(name.to_string(), load_grammar(&path)?);
+            }
+        }
+        Ok(GrammarLibrary { grammars })
+    }
+
+    /// Grammar names in alphabetical order.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.grammars.keys().map(|n| n.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Grammar> {
+        self.grammars.get(name)
+    }
+
+    /// Generate from the grammar called `name`, choosing productions randomly,
+    /// in the grammar's own time signature (or 4/4).
+    pub fn generate(&self, name: &str, axiom: &str, iterations: usize) -> Result<Composition, LibraryError> {
+        let grammar = self.get(name)
+            .ok_or_else(|| LibraryError::UnknownGrammar(name.to_string()))?;
+        let time_signature = grammar.time_signature_or(TimeSignature::common());
+        Ok(grammar.generate(axiom, iterations, true, time_signature)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::library::{load_grammar, GrammarLibrary, LibraryError};
+    use crate::time::{MusicTime, TimeSignature};
+
+    #[test]
+    fn test_load_dir_and_generate() {
+        let dir = std::env::temp_dir().join(format!("music-turtles-library-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("four.mtx"), "start S\nS = :c :d :e :f").unwrap();
+        std::fs::write(dir.join("waltz.grm"), "@time 3/4\nstart S\nS = [x2][:c :e :g]").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a grammar").unwrap();
+        let library = GrammarLibrary::load_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let library = library.unwrap();
+        assert_eq!(library.names(), vec!["four", "waltz"]);
+
+        let four = library.generate("four", "S", 1).unwrap();
+        assert_eq!(four.get_duration(), MusicTime::measures(1));
+        let waltz = library.generate("waltz", "S", 1).unwrap();
+        assert_eq!(waltz.time_signature, TimeSignature(3, 4));
+        assert_eq!(waltz.tracks[0].events.len(), 6);
+
+        assert!(matches!(library.generate("missing", "S", 1), Err(LibraryError::UnknownGrammar(_))));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("music-turtles-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(dir.join("song.mtx"), "@include shared/rhythm.grm\nstart S\nS = Beat Beat").unwrap();
+        std::fs::write(dir.join("shared/rhythm.grm"), "start Beat\nBeat = :c :_ :c :c").unwrap();
+        std::fs::write(dir.join("clash.mtx"), "@include shared/rhythm.grm\nstart S\nS = Beat\nBeat = :d").unwrap();
+        std::fs::write(dir.join("loop.mtx"), "@include loop.mtx\nstart S\nS = :c").unwrap();
+        let song = load_grammar(dir.join("song.mtx"));
+        let clash = load_grammar(dir.join("clash.mtx"));
+        let cycle = load_grammar(dir.join("loop.mtx"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let song = song.unwrap();
+        let composition = song.generate("S", 2, false, TimeSignature::common()).unwrap();
+        assert_eq!(composition.get_duration(), MusicTime::measures(2));
+        assert_eq!(composition.tracks[0].events.len(), 6);
+        assert!(matches!(clash, Err(LibraryError::DuplicateNonTerminal { name, .. }) if name == "Beat"));
+        assert!(matches!(cycle, Err(LibraryError::IncludeCycle(_))));
+    }
+}
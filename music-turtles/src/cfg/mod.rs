@@ -1,25 +1,124 @@
 pub mod scan;
 pub mod interactive;
+pub mod library;
+pub mod builder;
+pub mod expansion;
 
-use crate::cfg::scan::{consume, MusicStringScanner, ScanError};
+use crate::cfg::scan::{check_nesting, consume, MusicStringScanner, ScanError};
 use crate::cfg::scan::{GrammarScanner, Scanner};
-use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume};
-use crate::time::{Beat, MusicTime, TimeCompression, TimeSignature};
+use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume, DEFAULT_INSTRUMENT, MAX_VOLUME};
+use crate::time::{Beat, BeatUnit, MusicTime, TimeCompression, TimeSignature, BPM};
+use num::rational::Ratio;
 use num::Zero;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::fmt::Display;
 use std::str::FromStr;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Grammar {
     start: NonTerminal,
     productions: Vec<Production>,
+    #[serde(default)]
+    meta: Option<GrammarMeta>,
 }
 
+/// Playback defaults and other information from a grammar's `@` header.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GrammarMeta {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub bpm: Option<BPM>,
+    pub time_signature: Option<TimeSignature>,
+    /// The instrument to start on, instead of `DEFAULT_INSTRUMENT`.
+    pub instrument: Option<Instrument>,
+    /// `@include other.grm`: files whose productions this grammar uses, as written.
+    /// Only `library::load_grammar` resolves these, since scanning a string can't read files.
+    #[serde(default)]
+    pub includes: Vec<String>,
+}
+
+/// A rewrite rule, `NonTerminal = MusicString`, that can be limited to some of the rewrites
+/// with a `DepthCondition`, ex. `S @<3 = :c S`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Production(NonTerminal, MusicString);
+pub struct Production(NonTerminal, MusicString, #[serde(default)] Option<DepthCondition>);
+
+/// Which rewrites a production can be used in, counting the first rewrite as depth 0.
+/// Written after the non-terminal, ex. `@<3` for the first three rewrites or `@>=3` for the rest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DepthCondition {
+    Below(usize),
+    AtMost(usize),
+    Above(usize),
+    AtLeast(usize),
+}
+
+impl DepthCondition {
+    pub fn allows(&self, depth: usize) -> bool {
+        match *self {
+            DepthCondition::Below(d) => depth < d,
+            DepthCondition::AtMost(d) => depth <= d,
+            DepthCondition::Above(d) => depth > d,
+            DepthCondition::AtLeast(d) => depth >= d,
+        }
+    }
+}
+
+impl Display for DepthCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DepthCondition::Below(d) => write!(f, "@<{d}"),
+            DepthCondition::AtMost(d) => write!(f, "@<={d}"),
+            DepthCondition::Above(d) => write!(f, "@>{d}"),
+            DepthCondition::AtLeast(d) => write!(f, "@>={d}"),
+        }
+    }
+}
+
+impl Production {
+    /// Whether this production can be used in the rewrite at `depth`.
+    pub fn applies_at(&self, depth: usize) -> bool {
+        self.2.is_none_or(|condition| condition.allows(depth))
+    }
+}
+
+/// Every instrument and pitch a grammar mentions. See `Grammar::inventory`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GrammarInventory {
+    /// switched to with `::i=`, or started on with `@instrument`
+    pub instruments: BTreeSet<Instrument>,
+    /// of notes and chords, as written, so before any transposition
+    pub pitches: BTreeSet<Pitch>,
+}
+
+impl GrammarInventory {
+    pub fn lowest(&self) -> Option<Pitch> {
+        self.pitches.first().copied()
+    }
+
+    pub fn highest(&self) -> Option<Pitch> {
+        self.pitches.last().copied()
+    }
+
+    fn add_string(&mut self, string: &MusicString) {
+        for primitive in &string.0 {
+            match primitive {
+                MusicPrimitive::Simple(Symbol::T(Terminal::Meta(MetaControl::ChangeInstrument(instrument)))) => {
+                    self.instruments.insert(*instrument);
+                }
+                MusicPrimitive::Simple(Symbol::T(Terminal::Music { note, .. })) => {
+                    self.pitches.extend(note.pitches());
+                }
+                MusicPrimitive::Simple(_) => {}
+                MusicPrimitive::Split { branches, .. } => branches.iter().for_each(|b| self.add_string(b)),
+                MusicPrimitive::Repeat { content, .. } | MusicPrimitive::Transform { content, .. } => self.add_string(content),
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MusicString(pub Vec<MusicPrimitive>);
@@ -29,7 +128,11 @@ pub struct MusicString(pub Vec<MusicPrimitive>);
 pub enum MusicPrimitive {
     Simple(Symbol),
     Split {
-        branches: Vec<MusicString>
+        branches: Vec<MusicString>,
+        /// `{< ... }`: the branches may have different lengths.
+        /// Shorter ones just end early, and the split lasts as long as the longest.
+        #[serde(default)]
+        ragged: bool,
     },
     #[deprecated]
     Repeat {
@@ -42,6 +145,15 @@ pub enum MusicPrimitive {
     }
 }
 
+/// Where a random walk starts if there's no note before it: middle C.
+const RANDOM_WALK_START: Pitch = Pitch(4, 3);
+
+/// The most rewriting steps `Grammar::generate_to_duration` takes to reach its target.
+const MAX_GENERATE_STEPS: usize = 1000;
+
+/// The most that `[3!]` scales the volume of a repeat by, up or down.
+pub const REPEAT_VOLUME_VARIATION: f32 = 0.15;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum MusicTransform {
@@ -50,6 +162,15 @@ pub enum MusicTransform {
     },
     Repeat {
         num: usize,
+        /// `[3+2]`: semitones to transpose by on each successive repeat
+        #[serde(default)]
+        step: i8,
+        /// `[3~]`: every other repeat, starting with the second, is played backwards
+        #[serde(default)]
+        alternate: bool,
+        /// `[3!]`: each repeat is made a little louder or softer, see `ComposeOptions::seed`
+        #[serde(default)]
+        vary_volume: bool,
     },
     Compression {
         factor: TimeCompression,
@@ -74,8 +195,16 @@ pub enum Terminal {
     Music {
         duration: MusicTime,
         note: TerminalNote,
+        #[serde(default)]
+        articulation: Articulation,
+        /// Written without a `<duration>`, so it lasts as long as the last `::dur=` says,
+        /// or `duration` (1 beat) if there wasn't one.
+        #[serde(default)]
+        implicit_duration: bool,
     },
     Meta(MetaControl),
+    /// `|`: a bar check. Composing fails unless it falls on a barline.
+    BarCheck,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -85,6 +214,143 @@ pub enum TerminalNote {
         pitch: Pitch
     },
     Rest,
+    /// `:?N`: a note at most N semitones away from the note before it,
+    /// picked when the string is rewritten.
+    RandomWalk {
+        max_step: u8,
+    },
+    /// ex. `:cM` or `:a7`: a chord built on `root`, played all at once.
+    /// `/N` after the quality plays the Nth inversion and `^` spreads it out, ex. `:cM/1^`.
+    Chord {
+        root: Pitch,
+        quality: ChordQuality,
+        /// how many of the lowest notes are moved up an octave
+        #[serde(default)]
+        inversion: u8,
+        /// every other note, from the second lowest, is moved up an octave
+        #[serde(default)]
+        spread: bool,
+    },
+}
+
+impl TerminalNote {
+    /// The pitches that sound, ex. every note of a chord. Rests and random walks have none.
+    pub fn pitches(&self) -> Vec<Pitch> {
+        match self {
+            TerminalNote::Note { pitch } => vec![*pitch],
+            TerminalNote::Chord { root, quality, inversion, spread } => {
+                let mut pitches: Vec<_> = quality.intervals().iter()
+                    .map(|interval| {
+                        let mut pitch = *root;
+                        pitch.transpose(*interval);
+                        pitch
+                    })
+                    .collect();
+                let len = pitches.len();
+                let inversion = *inversion as usize % len;
+                pitches.rotate_left(inversion);
+                for pitch in &mut pitches[len - inversion..] {
+                    pitch.transpose(12);
+                }
+                if *spread {
+                    for pitch in pitches.iter_mut().skip(1).step_by(2) {
+                        pitch.transpose(12);
+                    }
+                }
+                pitches
+            }
+            TerminalNote::Rest | TerminalNote::RandomWalk { .. } => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChordQuality {
+    /// `M` or `maj`
+    Major,
+    /// `m` or `min`
+    Minor,
+    /// `dim`
+    Diminished,
+    /// `aug`
+    Augmented,
+    /// `7`
+    Dominant7,
+    /// `maj7`
+    Major7,
+    /// `m7` or `min7`
+    Minor7,
+}
+
+impl ChordQuality {
+    /// Every symbol that can follow a note name, longest first so that they can be matched in order.
+    pub const SYMBOLS: [(&'static str, ChordQuality); 10] = [
+        ("maj7", ChordQuality::Major7),
+        ("min7", ChordQuality::Minor7),
+        ("maj", ChordQuality::Major),
+        ("min", ChordQuality::Minor),
+        ("dim", ChordQuality::Diminished),
+        ("aug", ChordQuality::Augmented),
+        ("m7", ChordQuality::Minor7),
+        ("M", ChordQuality::Major),
+        ("m", ChordQuality::Minor),
+        ("7", ChordQuality::Dominant7),
+    ];
+
+    /// Semitones above the root of each note in the chord, including the root.
+    pub fn intervals(&self) -> &'static [i8] {
+        match self {
+            ChordQuality::Major => &[0, 4, 7],
+            ChordQuality::Minor => &[0, 3, 7],
+            ChordQuality::Diminished => &[0, 3, 6],
+            ChordQuality::Augmented => &[0, 4, 8],
+            ChordQuality::Dominant7 => &[0, 4, 7, 10],
+            ChordQuality::Major7 => &[0, 4, 7, 11],
+            ChordQuality::Minor7 => &[0, 3, 7, 10],
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            ChordQuality::Major => "M",
+            ChordQuality::Minor => "m",
+            ChordQuality::Diminished => "dim",
+            ChordQuality::Augmented => "aug",
+            ChordQuality::Dominant7 => "7",
+            ChordQuality::Major7 => "maj7",
+            ChordQuality::Minor7 => "m7",
+        }
+    }
+}
+
+/// How long a note sounds relative to its rhythmic slot.
+/// The slot (and so the timing of the following notes) is unchanged.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Articulation {
+    #[default]
+    Normal,
+    /// `.`: sounds for half of the slot
+    Staccato,
+    /// `_`: explicitly sounds for the full slot
+    Tenuto,
+}
+
+impl Articulation {
+    /// The fraction of the slot that the note sounds for.
+    pub fn sounding_fraction(&self) -> Ratio<BeatUnit> {
+        match self {
+            Articulation::Normal | Articulation::Tenuto => Ratio::new(1, 1),
+            Articulation::Staccato => Ratio::new(1, 2),
+        }
+    }
+
+    pub fn marker(&self) -> &'static str {
+        match self {
+            Articulation::Normal => "",
+            Articulation::Staccato => ".",
+            Articulation::Tenuto => "_",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,29 +358,238 @@ pub enum TerminalNote {
 pub enum MetaControl {
     ChangeInstrument(Instrument),
     ChangeVolume(Volume),
+    /// Ramp the volume from the current volume to this one over the next group.
+    Crescendo(Volume),
+    /// `::t=`: change the tempo from here on. Recorded in `Composition::tempo_map`.
+    ChangeTempo(BPM),
+    /// `::rit=`: slow down to this tempo over the next group.
+    Ritardando(BPM),
+    /// `::accel=`: speed up to this tempo over the next group.
+    Accelerando(BPM),
+    /// `::key-shift=+2`: transpose every note from here on by this many semitones,
+    /// including in groups. Replaces any earlier key shift, so `::key-shift=0` undoes it.
+    KeyShift(i8),
+    /// `::dur=1/2`: how long notes and rests written without a `<duration>` last from here on,
+    /// including in groups.
+    DefaultDuration(Beat),
+}
+
+impl MetaControl {
+    /// Whether `other` replaces this when it comes right after, ex. two volume changes.
+    fn same_kind(&self, other: &MetaControl) -> bool {
+        use MetaControl::*;
+        matches!(
+            (self, other),
+            (ChangeInstrument(_), ChangeInstrument(_))
+                | (ChangeVolume(_), ChangeVolume(_))
+                | (Crescendo(_), Crescendo(_))
+                | (ChangeTempo(_), ChangeTempo(_))
+                | (KeyShift(_), KeyShift(_))
+                | (DefaultDuration(_), DefaultDuration(_))
+                | (Ritardando(_) | Accelerando(_), Ritardando(_) | Accelerando(_))
+        )
+    }
 }
 
 impl Grammar {
     pub fn new(start: NonTerminal, productions: Vec<Production>) -> Self {
-        Grammar { start, productions }
+        Grammar { start, productions, meta: None }
+    }
+
+    pub fn meta(&self) -> Option<&GrammarMeta> {
+        self.meta.as_ref()
+    }
+
+    pub fn bpm_or(&self, default: BPM) -> BPM {
+        self.meta.as_ref().and_then(|m| m.bpm).unwrap_or(default)
+    }
+
+    pub fn time_signature_or(&self, default: TimeSignature) -> TimeSignature {
+        self.meta.as_ref().and_then(|m| m.time_signature).unwrap_or(default)
+    }
+
+    pub fn instrument_or(&self, default: Instrument) -> Instrument {
+        self.meta.as_ref().and_then(|m| m.instrument).unwrap_or(default)
+    }
+
+    /// Add the productions of `other` to this grammar, keeping this grammar's start and meta.
+    /// If `other` has productions for a non-terminal that this grammar already has,
+    /// nothing is added and that non-terminal is returned.
+    pub fn include(&mut self, other: Grammar) -> Result<(), NonTerminal> {
+        if let Some(Production(duplicate, ..)) = other.productions.iter()
+            .find(|p| self.productions.iter().any(|q| q.0 == p.0)) {
+            return Err(duplicate.clone());
+        }
+        self.productions.extend(other.productions);
+        Ok(())
     }
 
+    /// The non-terminals whose productions differ between this grammar and `other`,
+    /// including ones that only one of them has, in the order they first appear.
+    pub fn changed_non_terminals(&self, other: &Grammar) -> Vec<NonTerminal> {
+        let bodies = |grammar: &Grammar, nt: &NonTerminal| grammar.productions.iter()
+            .filter(|p| &p.0 == nt)
+            .map(|p| (p.2, p.1.to_string()))
+            .collect::<Vec<_>>();
+        let mut changed: Vec<NonTerminal> = vec![];
+        for Production(nt, ..) in self.productions.iter().chain(&other.productions) {
+            if !changed.contains(nt) && bodies(self, nt) != bodies(other, nt) {
+                changed.push(nt.clone());
+            }
+        }
+        changed
+    }
+
+    /// The first production for `nt` that can be used in the first rewrite.
     pub fn get_production(&self, nt: &NonTerminal) -> Option<&Production> {
-        self.productions.iter().find(|p| &p.0 == nt)
+        self.get_production_at(nt, 0)
+    }
+
+    /// The first production for `nt` that can be used in the rewrite at `depth`.
+    pub fn get_production_at(&self, nt: &NonTerminal, depth: usize) -> Option<&Production> {
+        self.productions.iter().find(|p| &p.0 == nt && p.applies_at(depth))
     }
 
     pub fn get_production_random(
         &self,
         nt: &NonTerminal,
     ) -> Option<&Production> {
-        let mut rng = rand::thread_rng();
-        let productions: Vec<_> = self.productions.iter().filter(|p| &p.0 == nt).collect();
+        self.get_production_random_with(nt, &mut rand::thread_rng())
+    }
+
+    /// `get_production_random`, but with the given random number generator, ex. a seeded one.
+    pub fn get_production_random_with<R: Rng + ?Sized>(
+        &self,
+        nt: &NonTerminal,
+        rng: &mut R,
+    ) -> Option<&Production> {
+        self.get_production_random_at(nt, 0, rng)
+    }
+
+    /// `get_production_random_with`, picking from the productions that can be used in the rewrite at `depth`.
+    pub fn get_production_random_at<R: Rng + ?Sized>(
+        &self,
+        nt: &NonTerminal,
+        depth: usize,
+        rng: &mut R,
+    ) -> Option<&Production> {
+        self.get_production_at_with(nt, depth, &mut |productions| rng.gen_range(0..productions.len()))
+    }
+
+    /// Pick one of the productions for `nt` with `selector`, which is given all of them
+    /// (never empty) and returns the index of the one to use, ex. to favor longer productions.
+    /// `get_production_random` picks uniformly. An index out of range gives `None`.
+    /// Only productions that can be used in the first rewrite are given.
+    pub fn get_production_with(
+        &self,
+        nt: &NonTerminal,
+        selector: &mut dyn FnMut(&[&Production]) -> usize,
+    ) -> Option<&Production> {
+        self.get_production_at_with(nt, 0, selector)
+    }
+
+    /// `get_production_with`, but given the productions that can be used in the rewrite at `depth`.
+    pub fn get_production_at_with(
+        &self,
+        nt: &NonTerminal,
+        depth: usize,
+        selector: &mut dyn FnMut(&[&Production]) -> usize,
+    ) -> Option<&Production> {
+        let productions: Vec<_> = self.productions.iter().filter(|p| &p.0 == nt && p.applies_at(depth)).collect();
         if productions.is_empty() {
             None
         } else {
-            Some(productions[rng.gen_range(0..productions.len())])
+            productions.get(selector(&productions)).copied()
         }
     }
+
+    /// Scan the axiom, rewrite it `iterations` times and compose the result,
+    /// starting on the grammar's `@instrument` if it has one.
+    /// Non-terminals without a production are dropped rather than panicking.
+    pub fn generate(
+        &self,
+        axiom: &str,
+        iterations: usize,
+        random: bool,
+        time_signature: TimeSignature,
+    ) -> Result<Composition, GenerateError> {
+        let string = MusicString::from_str(axiom)?;
+        let string = string.parallel_rewrite_n(self, random, false, iterations);
+        Ok(string.compose(time_signature, Some(self.instrument_or(DEFAULT_INSTRUMENT)))?)
+    }
+
+    /// Like `generate`, but instead of a fixed number of iterations, rewrite one step at a time
+    /// until the composed piece lasts at least `target`, then clip it to `target`.
+    /// Stops early if rewriting doesn't change anything, or after `MAX_GENERATE_STEPS`.
+    /// Steps that don't compose (ex. a split whose branches aren't rewritten evenly yet) are rewritten further.
+    pub fn generate_to_duration(
+        &self,
+        axiom: &str,
+        target: MusicTime,
+        random: bool,
+        time_signature: TimeSignature,
+    ) -> Result<Composition, GenerateError> {
+        let instrument = Some(self.instrument_or(DEFAULT_INSTRUMENT));
+        let mut string = MusicString::from_str(axiom)?;
+        let mut steps = 0;
+        loop {
+            let composed = string.compose(time_signature, instrument);
+            let reached = matches!(&composed, Ok(c) if c.get_duration() >= target);
+            let next = (!reached && steps < MAX_GENERATE_STEPS)
+                .then(|| string.parallel_rewrite_at(self, random, false, steps));
+            match next {
+                Some(next) if next.to_string() != string.to_string() => {
+                    string = next;
+                    steps += 1;
+                }
+                _ => {
+                    let mut composed = composed?;
+                    composed.clip(target);
+                    return Ok(composed);
+                }
+            }
+        }
+    }
+
+    /// Like `generate`, once for each seed in `base_seed..base_seed + count`, so that any one
+    /// variation can be had again from its seed. Uses the grammar's `@time`, or else common time.
+    pub fn generate_variations(
+        &self,
+        axiom: &str,
+        iterations: usize,
+        count: usize,
+        base_seed: u64,
+    ) -> Result<Vec<Composition>, GenerateError> {
+        let string = MusicString::from_str(axiom)?;
+        let time_signature = self.time_signature_or(TimeSignature::common());
+        (base_seed..base_seed + count as u64)
+            .map(|seed| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let string = string.parallel_rewrite_n_with_rng(self, true, false, iterations, &mut rng);
+                Ok(string.compose(time_signature, Some(self.instrument_or(DEFAULT_INSTRUMENT)))?)
+            })
+            .collect()
+    }
+}
+
+impl Grammar {
+    /// The instruments and pitches that this grammar's productions mention, found by reading them
+    /// rather than by generating, so even productions that are unlikely to be picked are included.
+    /// Random walks aren't known until rewriting, so they add no pitches.
+    pub fn inventory(&self) -> GrammarInventory {
+        let mut inventory = GrammarInventory::default();
+        inventory.instruments.extend(self.meta.as_ref().and_then(|m| m.instrument));
+        for Production(_nt, string, _condition) in &self.productions {
+            inventory.add_string(string);
+        }
+        inventory
+    }
+
+    /// Scan a grammar from untrusted text. The scanner is written to return a `ScanError` for
+    /// anything it can't read rather than panic, which the fuzz tests in `scan` hold it to.
+    pub fn try_from_str(s: &str) -> Result<Self, ScanError> {
+        Grammar::from_str(s)
+    }
 }
 
 impl FromStr for Grammar {
@@ -127,159 +602,592 @@ impl FromStr for Grammar {
     }
 }
 
+/// The state that carries from one primitive to the next while composing.
+/// Nested groups inherit it, except for what only applies to the string it's in
+/// (the pending crescendo and tempo ramp, and the tempo map).
+#[derive(Debug, Clone)]
+struct ComposeState {
+    instrument: Instrument,
+    volume: Volume,
+    /// the last `::t=`, or the end of the last ramp, for ramps to start from
+    bpm: Option<BPM>,
+    /// semitones to transpose every note by, from `::key-shift=`
+    key_shift: i8,
+    /// the tag for events, which is the index of the top-level primitive they're in
+    tag: Option<u32>,
+    /// how long notes written without a duration last, from `::dur=`
+    duration: Beat,
+    /// where the string being composed starts in the whole piece, in beats, for bar checks
+    offset: Beat,
+    /// how much the string's time is stretched in the whole piece, by the compressions it's in
+    scale: Ratio<BeatUnit>,
+    /// target volume for the next group
+    crescendo: Option<Volume>,
+    /// target tempo for the next group
+    tempo_ramp: Option<BPM>,
+    /// tempo changes so far in this string, not necessarily in order
+    tempo_map: Vec<(MusicTime, BPM)>,
+}
+
+impl ComposeState {
+    fn new(instrument: Instrument) -> Self {
+        ComposeState {
+            instrument,
+            volume: Volume(50),
+            bpm: None,
+            key_shift: 0,
+            tag: None,
+            duration: Beat::whole(1),
+            offset: Beat::zero(),
+            scale: Ratio::from_integer(1),
+            crescendo: None,
+            tempo_ramp: None,
+            tempo_map: vec![],
+        }
+    }
+
+    /// The state for a string nested at `start` in the one being composed, with its time
+    /// stretched by `scale` on top of this one's.
+    fn nested_at(&self, start: MusicTime, scale: Ratio<BeatUnit>, time_signature: TimeSignature) -> Self {
+        ComposeState {
+            offset: self.absolute(start, time_signature),
+            scale: self.scale * scale,
+            crescendo: None,
+            tempo_ramp: None,
+            tempo_map: vec![],
+            ..*self
+        }
+    }
+
+    /// `time` in the string being composed, in beats from the start of the whole piece.
+    fn absolute(&self, time: MusicTime, time_signature: TimeSignature) -> Beat {
+        Beat::from_ratio(self.offset.as_ratio() + time.with(time_signature).total_beats().as_ratio() * self.scale)
+    }
+}
+
+/// What the meta controls so far are known to have set, for `MusicString::simplify`.
+#[derive(Debug, Copy, Clone, Default)]
+struct KnownMeta {
+    instrument: Option<Instrument>,
+    volume: Option<Volume>,
+    bpm: Option<BPM>,
+    key_shift: Option<i8>,
+    duration: Option<Beat>,
+}
+
+impl KnownMeta {
+    /// Whether `control` changes anything, remembering what it sets if so.
+    /// Crescendos and ramps always do, since they apply to the next group.
+    fn changes(&mut self, control: &MetaControl) -> bool {
+        fn set<T: PartialEq>(known: &mut Option<T>, value: T) -> bool {
+            let changed = known.as_ref() != Some(&value);
+            *known = Some(value);
+            changed
+        }
+        match control {
+            MetaControl::ChangeInstrument(instrument) => set(&mut self.instrument, *instrument),
+            MetaControl::ChangeVolume(volume) => set(&mut self.volume, *volume),
+            MetaControl::ChangeTempo(bpm) => set(&mut self.bpm, *bpm),
+            MetaControl::KeyShift(semitones) => set(&mut self.key_shift, *semitones),
+            MetaControl::DefaultDuration(duration) => set(&mut self.duration, *duration),
+            MetaControl::Crescendo(_) | MetaControl::Ritardando(_) | MetaControl::Accelerando(_) => true,
+        }
+    }
+}
+
+/// Options that apply to a whole call to `compose`.
+#[derive(Debug, Clone, Default)]
+pub struct ComposeOptions {
+    /// Largest repeat count allowed, to guard against absurd counts blowing up memory.
+    pub max_repeats: Option<usize>,
+    pub rests: RestMode,
+    /// Seeds the volume changes of `[3!]` repeats, along with where each repeat is,
+    /// so the same piece composed with the same seed always sounds the same.
+    pub seed: u64,
+}
+
+/// What `compose` does with rests. They are only used for visualization and for timing
+/// (a trailing rest still lengthens a group), so big pieces don't need every one of them.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum RestMode {
+    /// Keep every rest as it was written.
+    #[default]
+    Keep,
+    /// Merge rests that touch or overlap as they are composed.
+    Merge,
+    /// Merge rests while composing, then leave them out of the result.
+    /// `get_duration` of the result only covers the notes.
+    Skip,
+    /// Merge rests while composing, then move them into `events` as gap markers,
+    /// so that they are dispatched like notes and a player can cut off whatever is still ringing.
+    /// See `Event::is_gap`.
+    Gaps,
+}
+
+impl RestMode {
+    /// What to do with the merged rests of a finished composition.
+    fn finish(&self, composition: &mut Composition) {
+        match self {
+            RestMode::Keep | RestMode::Merge => {}
+            RestMode::Skip => composition.tracks.iter_mut().for_each(|t| t.rests.clear()),
+            RestMode::Gaps => for track in composition.tracks.iter_mut() {
+                track.rests.iter_mut().for_each(|rest| rest.gap = true);
+                track.events.append(&mut track.rests);
+                track.events.sort();
+            },
+        }
+    }
+}
+
+impl ComposeOptions {
+    /// The random numbers for the repeat at `start`, in the top-level primitive with `tag`.
+    fn repeat_rng(&self, tag: Option<u32>, start: MusicTime, time_signature: TimeSignature) -> StdRng {
+        let mut hasher = DefaultHasher::new();
+        (self.seed, tag, start.with(time_signature).total_beats()).hash(&mut hasher);
+        StdRng::seed_from_u64(hasher.finish())
+    }
+
+    fn check_repeats(&self, num: usize) -> Result<(), ComposeError> {
+        match self.max_repeats {
+            Some(max) if num > max => Err(ComposeError::TooManyRepeats { num, max }),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ComposeError {
     MismatchedLengths(String),
+    TooManyRepeats {
+        num: usize,
+        max: usize,
+    },
+    /// A random walk `:?N` that was never rewritten, so it has no pitch yet.
+    UnresolvedRandomWalk,
+    /// A bar check `|` that isn't on a barline. Has where it actually is.
+    BarlineMismatch(MusicTime),
+    /// A `::rit=` or `::accel=` with no `::t=` before it, so there's no tempo to ramp from.
+    TempoRampWithoutTempo,
+}
+
+impl Display for ComposeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ComposeError::MismatchedLengths(message) => write!(f, "{message}"),
+            ComposeError::TooManyRepeats { num, max } => write!(f, "Can't repeat {num} times, the most is {max}"),
+            ComposeError::UnresolvedRandomWalk => write!(f, "A random walk was composed before it was given a pitch"),
+            ComposeError::BarlineMismatch(time) => write!(f, "Bar check at {} isn't on a barline", time.to_string()),
+            ComposeError::TempoRampWithoutTempo => write!(f, "::rit= and ::accel= need a ::t= before them to ramp from"),
+        }
+    }
+}
+
+impl std::error::Error for ComposeError {}
 
+/// Anything that can go wrong between an axiom and a `Composition`.
+#[derive(Debug)]
+pub enum GenerateError {
+    Scan(ScanError),
+    Compose(ComposeError),
+}
+
+impl From<ScanError> for GenerateError {
+    fn from(value: ScanError) -> Self {
+        GenerateError::Scan(value)
+    }
+}
+
+impl From<ComposeError> for GenerateError {
+    fn from(value: ComposeError) -> Self {
+        GenerateError::Compose(value)
+    }
 }
 
 impl Display for MusicTransform {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
             MusicTransform::Transpose { semitones } => format!("T{}", semitones),
-            MusicTransform::Repeat { num } => format!("x{}", num),
+            MusicTransform::Repeat { num, step, alternate, vary_volume } => {
+                let alternate = if *alternate { "~" } else { "" };
+                let vary_volume = if *vary_volume { "!" } else { "" };
+                match step {
+                    0 => format!("x{num}{alternate}{vary_volume}"),
+                    step => format!("x{num}{alternate}{vary_volume}{step:+}"),
+                }
+            }
             MusicTransform::Compression { factor } => format!(">>{}", factor.to_string()),
         };
         write!(f, "{}", str)
     }
 }
 
-impl MusicString {
-    pub fn compose(&self, time_signature: TimeSignature, starting_instrument: Option<Instrument>) -> Result<Composition, ComposeError> {
-        let mut tracks = HashMap::new();
-        fn add_event(tracks: &mut HashMap<Instrument, Track>, e: Event, instrument: Instrument) {
-            if let Some(mut track) = tracks.get_mut(&instrument) {
-                track.events.push(e);
-            } else {
-                tracks.insert(
-                    instrument,
-                    Track {
-                        identifier: TrackId::Instrument(instrument),
-                        instrument,
-                        events: vec![e],
-                        rests: vec![],
-                    },
-                );
+type TrackMap = HashMap<Instrument, Track>;
+
+/// Composes a `MusicString` one top-level primitive at a time.
+/// See `MusicString::compose_streaming`.
+pub struct ComposeStream<'a> {
+    primitives: std::iter::Enumerate<std::slice::Iter<'a, MusicPrimitive>>,
+    time_signature: TimeSignature,
+    options: &'a ComposeOptions,
+    state: ComposeState,
+    /// where composing has gotten to
+    cursor: MusicTime,
+}
+
+impl Iterator for ComposeStream<'_> {
+    type Item = Result<Composition, ComposeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (i, mp) = self.primitives.next()?;
+            self.state.tag = Some(i as u32);
+            let mut tracks = HashMap::new();
+            if let Err(e) = compose_step(mp, &mut tracks, &mut self.cursor, &mut self.state, self.time_signature, self.options) {
+                return Some(Err(e));
+            }
+            if mp.is_group() {
+                let tempo_map = std::mem::take(&mut self.state.tempo_map);
+                let mut composition = finish_composition(tracks, tempo_map, self.time_signature, self.options.rests);
+                self.options.rests.finish(&mut composition);
+                return Some(Ok(composition));
             }
         }
+    }
+}
 
-        fn add_rest_event(tracks: &mut HashMap<Instrument, Track>, e: Event, instrument: Instrument) {
-            if let Some(mut track) = tracks.get_mut(&instrument) {
-                track.rests.push(e);
-            } else {
-                tracks.insert(
-                    instrument,
-                    Track {
-                        identifier: TrackId::Instrument(instrument),
-                        instrument,
-                        events: vec![],
-                        rests: vec![e],
-                    },
-                );
+fn add_event(tracks: &mut TrackMap, e: Event, instrument: Instrument) {
+    if let Some(track) = tracks.get_mut(&instrument) {
+        track.events.push(e);
+    } else {
+        tracks.insert(
+            instrument,
+            Track {
+                identifier: TrackId::Instrument(instrument),
+                instrument,
+                events: vec![e],
+                rests: vec![],
+            },
+        );
+    }
+}
+
+fn add_rest_event(tracks: &mut TrackMap, e: Event, instrument: Instrument, rests: RestMode, time_signature: TimeSignature) {
+    if let Some(track) = tracks.get_mut(&instrument) {
+        match track.rests.last_mut() {
+            // extend the previous rest when it ends right where this one starts
+            Some(last) if rests != RestMode::Keep && last.get_end(time_signature) == e.start => {
+                last.duration = last.duration + e.duration;
             }
+            _ => track.rests.push(e),
         }
-        fn add_track(tracks: &mut HashMap<Instrument, Track>, track: Track) {
-            if let Some(mtrack) = tracks.remove(&track.instrument) {
-                tracks.insert(mtrack.instrument, mtrack + track);
-            } else {
-                tracks.insert(track.instrument, track);
-            }
+    } else {
+        tracks.insert(
+            instrument,
+            Track {
+                identifier: TrackId::Instrument(instrument),
+                instrument,
+                events: vec![],
+                rests: vec![e],
+            },
+        );
+    }
+}
+
+/// Merge the tracks without sorting; `finish_composition` sorts everything once at the end.
+fn add_composition(tracks: &mut TrackMap, tempo_map: &mut Vec<(MusicTime, BPM)>, composition: Composition) {
+    tempo_map.extend(composition.tempo_map);
+    for track in composition.tracks {
+        if let Some(mtrack) = tracks.get_mut(&track.instrument) {
+            mtrack.events.extend(track.events);
+            mtrack.rests.extend(track.rests);
+        } else {
+            tracks.insert(track.instrument, track);
         }
-        fn add_composition(tracks: &mut HashMap<Instrument, Track>, composition: Composition) {
-            for track in composition.tracks {
-                add_track(tracks, track);
-            }
+    }
+}
+
+fn finish_composition(tracks: TrackMap, mut tempo_map: Vec<(MusicTime, BPM)>, time_signature: TimeSignature, rests: RestMode) -> Composition {
+    // stable, so that of two changes at the same time the one written later wins
+    tempo_map.sort_by_key(|(time, _bpm)| *time);
+    // sort so that the track order doesn't depend on the hash map
+    let mut tracks: Vec<_> = tracks.into_values().collect();
+    tracks.sort_by_key(|t| t.instrument);
+    for track in tracks.iter_mut() {
+        track.events.sort();
+        if rests == RestMode::Keep {
+            track.rests.sort();
+        } else {
+            track.coalesce_rests(time_signature);
         }
-        let mut current_mt = MusicTime::zero();
-        let mut current_instrument = starting_instrument.unwrap_or(Instrument::SineWave);
-        let mut current_volume = Volume(50);
-        for mp in self.0.iter() {
-            let duration = match mp {
-                MusicPrimitive::Simple(sym) => match sym {
-                    Symbol::NT(_) => MusicTime::zero(),
-                    Symbol::T(Terminal::Music { note, duration }) => match note {
-                        TerminalNote::Note { pitch } => {
+    }
+    Composition {
+        tracks,
+        time_signature,
+        tempo_map,
+    }
+}
+
+/// Compose one primitive at the cursor and move the cursor past it.
+fn compose_step(
+    mp: &MusicPrimitive,
+    tracks: &mut TrackMap,
+    cursor: &mut MusicTime,
+    state: &mut ComposeState,
+    time_signature: TimeSignature,
+    options: &ComposeOptions,
+) -> Result<(), ComposeError> {
+    let duration = if let Some(target) = state.crescendo && mp.is_group() {
+        // compose the group by itself so the ramp only covers its events
+        state.crescendo = None;
+        let mut group = HashMap::new();
+        let duration = compose_primitive(mp, &mut group, *cursor, state, time_signature, options)?;
+        let mut composed = finish_composition(group, vec![], time_signature, options.rests);
+        composed.ramp_volume(state.volume, target);
+        add_composition(tracks, &mut state.tempo_map, composed);
+        state.volume = target;
+        duration
+    } else {
+        compose_primitive(mp, tracks, *cursor, state, time_signature, options)?
+    };
+    if let Some(target) = state.tempo_ramp && mp.is_group() {
+        state.tempo_ramp = None;
+        let from = state.bpm.ok_or(ComposeError::TempoRampWithoutTempo)?;
+        let length = duration.with(time_signature).total_beats();
+        state.tempo_map.extend(tempo_ramp(*cursor, length, from, target, time_signature));
+        state.bpm = Some(target);
+    }
+    *cursor = cursor.with(time_signature) + duration;
+    Ok(())
+}
+
+/// How many tempo changes a beat of a `::rit=` or `::accel=` ramp is made of.
+const TEMPO_RAMP_STEPS_PER_BEAT: BeatUnit = 4;
+
+/// Tempo changes going from `from` to `to` over `length` beats after `start`, then staying at `to`.
+/// The tempo is linear in beats over the ramp. Each step is held at the tempo that takes as long
+/// as the linear ramp would over that step, so the times at the steps are exact.
+fn tempo_ramp(start: MusicTime, length: Beat, from: BPM, to: BPM, time_signature: TimeSignature) -> Vec<(MusicTime, BPM)> {
+    let steps = (length.as_ratio() * TEMPO_RAMP_STEPS_PER_BEAT).ceil().to_integer().max(1);
+    let at = |step: BeatUnit| start.with(time_signature) + Beat::from_ratio(length.as_ratio() * Ratio::new(step, steps)).as_music_time(time_signature);
+    let bpm = |step: BeatUnit| from + (to - from) * step as BPM / steps as BPM;
+    let mut changes: Vec<_> = (0..steps)
+        .map(|step| {
+            let (a, b) = (bpm(step), bpm(step + 1));
+            // the logarithmic mean, since time is the integral of 1 / tempo
+            let held = if (b - a).abs() < 1e-6 { a } else { (b - a) / (b / a).ln() };
+            (at(step), held)
+        })
+        .collect();
+    changes.push((at(steps), to));
+    changes
+}
+
+/// Compose one primitive into `tracks`, starting at `start`. Returns how long it lasts.
+fn compose_primitive(
+    mp: &MusicPrimitive,
+    tracks: &mut TrackMap,
+    start: MusicTime,
+    state: &mut ComposeState,
+    time_signature: TimeSignature,
+    options: &ComposeOptions,
+) -> Result<MusicTime, ComposeError> {
+    let duration = match mp {
+        MusicPrimitive::Simple(sym) => match sym {
+            Symbol::NT(_) => MusicTime::zero(),
+            Symbol::T(Terminal::Music { note, duration, articulation, implicit_duration }) => {
+                let duration = &if *implicit_duration { state.duration.as_music_time(time_signature) } else { *duration };
+                match note {
+                    TerminalNote::Note { .. } | TerminalNote::Chord { .. } => {
+                        let slot = duration.with(time_signature).total_beats();
+                        let sounding = Beat::from_ratio(slot.as_ratio() * articulation.sounding_fraction());
+                        for mut pitch in note.pitches() {
+                            pitch.transpose(state.key_shift);
                             add_event(
-                                &mut tracks,
+                                tracks,
                                 Event {
-                                    start: current_mt,
-                                    duration: duration.with(time_signature).total_beats(),
-                                    volume: current_volume,
-                                    pitch: *pitch,
+                                    start,
+                                    duration: sounding,
+                                    volume: state.volume,
+                                    pitch,
+                                    gate: None,
+                                    tag: state.tag,
+                                    gap: false,
                                 },
-                                current_instrument,
+                                state.instrument,
                             );
-                            *duration
                         }
-                        TerminalNote::Rest => {
+                        if sounding < slot {
+                            // fill the rest of the slot so the track keeps its length
                             add_rest_event(
-                                &mut tracks,
+                                tracks,
                                 Event {
-                                    start: current_mt,
-                                    duration: duration.with(time_signature).total_beats(),
+                                    start: start.with(time_signature) + sounding.as_music_time(time_signature),
+                                    duration: slot - sounding,
                                     volume: Volume(0),
                                     pitch: Pitch(0, 0),
+                                    gate: None,
+                                    tag: state.tag,
+                                    gap: false,
                                 },
-                                current_instrument,
+                                state.instrument,
+                                options.rests,
+                                time_signature,
                             );
-                            *duration
                         }
-                    },
-                    Symbol::T(Terminal::Meta(control)) => {
-                        match control {
-                            MetaControl::ChangeInstrument(i) => {
-                                current_instrument = *i;
-                            }
-                            MetaControl::ChangeVolume(v) => {
-                                current_volume = *v;
-                            }
-                        }
-                        MusicTime::zero()
+                        *duration
                     }
-                },
-                MusicPrimitive::Split { branches } => {
-                    let comps: Vec<_> = branches
-                        .into_iter()
-                        .map(|ms| ms.compose(time_signature, Some(current_instrument)))
-                        .err_first()?
-                        .map(|mut c| {
-                            c.shift_by(current_mt);
-                            c
-                        })
-                        .map(|c| (c.get_duration(), c))
-                        .collect();
-                    let uniform_duration = match comps.first() {
-                        Some((duration, _c)) => {
-                            if comps.iter().all(|(d, _c)| d == duration) {
-                                Some(*duration)
-                            } else {
-                                None
-                            }
-                        }
-                        // there are none, so yes they are
-                        None => Some(MusicTime::zero()),
-                    };
-                    if let Some(dur) = uniform_duration {
-                        for (_d, comp) in comps {
-                            add_composition(&mut tracks, comp);
-                        }
-                        dur
+                    TerminalNote::Rest => {
+                        add_rest_event(
+                            tracks,
+                            Event {
+                                start,
+                                duration: duration.with(time_signature).total_beats(),
+                                volume: Volume(0),
+                                pitch: Pitch(0, 0),
+                                gate: None,
+                                tag: state.tag,
+                                gap: false,
+                            },
+                            state.instrument,
+                            options.rests,
+                            time_signature,
+                        );
+                        *duration
+                    }
+                    TerminalNote::RandomWalk { .. } => return Err(ComposeError::UnresolvedRandomWalk),
+                }
+            }
+            Symbol::T(Terminal::Meta(control)) => {
+                match control {
+                    MetaControl::ChangeInstrument(i) => {
+                        state.instrument = *i;
+                    }
+                    MetaControl::ChangeVolume(v) => {
+                        state.volume = *v;
+                    }
+                    MetaControl::Crescendo(v) => {
+                        state.crescendo = Some(*v);
+                    }
+                    MetaControl::ChangeTempo(bpm) => {
+                        state.tempo_map.push((start, *bpm));
+                        state.bpm = Some(*bpm);
+                    }
+                    MetaControl::Ritardando(bpm) | MetaControl::Accelerando(bpm) => {
+                        state.tempo_ramp = Some(*bpm);
+                    }
+                    MetaControl::KeyShift(semitones) => {
+                        state.key_shift = *semitones;
+                    }
+                    MetaControl::DefaultDuration(duration) => {
+                        state.duration = *duration;
+                    }
+                }
+                MusicTime::zero()
+            }
+            Symbol::T(Terminal::BarCheck) => {
+                let at = state.absolute(start, time_signature).as_music_time(time_signature);
+                if at.1 != Beat::zero() {
+                    return Err(ComposeError::BarlineMismatch(at));
+                }
+                MusicTime::zero()
+            }
+        },
+        MusicPrimitive::Split { branches, ragged } => {
+            let comps: Vec<_> = branches
+                .iter()
+                .map(|ms| ms.compose_from(time_signature, state.nested_at(start, Ratio::from_integer(1), time_signature), options))
+                .err_first()?
+                .map(|mut c| {
+                    c.shift_by(start);
+                    c
+                })
+                .map(|c| (c.get_duration(), c))
+                .collect();
+            // compare total beats, so that the same length written differently
+            // (ex. by a tuplet) still counts as equal
+            let total_beats = |d: &MusicTime| d.with(time_signature).total_beats();
+            let uniform_duration = match comps.first() {
+                Some(_) if *ragged => comps.iter().map(|(d, _c)| *d).max_by_key(total_beats),
+                Some((duration, _c)) => {
+                    if comps.iter().all(|(d, _c)| total_beats(d) == total_beats(duration)) {
+                        Some(*duration)
                     } else {
-                        return Err(ComposeError::MismatchedLengths(
-                            format!("Not all split tracks have the same duration: {:?}",
-                                    comps.iter().map(|(d, c)| d).collect::<Vec<_>>()
-                            )));
+                        None
                     }
                 }
-                MusicPrimitive::Repeat { content, num } => {
-                    let composed = content.compose(time_signature, Some(current_instrument))?;
+                // there are none, so yes they are
+                None => Some(MusicTime::zero()),
+            };
+            if let Some(dur) = uniform_duration {
+                for (_d, comp) in comps {
+                    add_composition(tracks, &mut state.tempo_map, comp);
+                }
+                dur
+            } else {
+                return Err(ComposeError::MismatchedLengths(
+                    format!("Not all split tracks have the same duration: {:?}",
+                            comps.iter().map(|(d, _c)| d).collect::<Vec<_>>()
+                    )));
+            }
+        }
+        MusicPrimitive::Repeat { content, num } => {
+            options.check_repeats(*num)?;
+            let composed = content.compose_from(time_signature, state.nested_at(start, Ratio::from_integer(1), time_signature), options)?;
+            let duration = composed.get_duration();
+            check_repeated_bars(content, start, duration, *num, state, time_signature, options)?;
+            let mut offset = start;
+            for _i in 0..*num {
+                let mut comp_i = composed.clone();
+                comp_i.shift_by(offset);
+                add_composition(tracks, &mut state.tempo_map, comp_i);
+                offset = offset.with(time_signature) + duration;
+            }
+            let mut total_duration = MusicTime::zero();
+            for _i in 0..*num {
+                total_duration = total_duration.with(time_signature) + duration;
+            }
+            total_duration
+        },
+        MusicPrimitive::Transform { transform, content } => {
+            match transform {
+                MusicTransform::Transpose { semitones} => {
+                    let mut composed = content.compose_from(time_signature, state.nested_at(start, Ratio::from_integer(1), time_signature), options)?;
+                    composed.transpose(*semitones);
+                    composed.shift_by(start);
                     let duration = composed.get_duration();
-                    let mut offset = current_mt;
-                    for _i in 0..*num {
+                    add_composition(tracks, &mut state.tempo_map, composed);
+                    duration
+                }
+                MusicTransform::Repeat { num, step, alternate, vary_volume } => {
+                    options.check_repeats(*num)?;
+                    let mut composed = content.compose_from(time_signature, state.nested_at(start, Ratio::from_integer(1), time_signature), options)?;
+                    let duration = composed.get_duration();
+                    check_repeated_bars(content, start, duration, *num, state, time_signature, options)?;
+                    let mut rng = options.repeat_rng(state.tag, start, time_signature);
+                    let mut offset = start;
+                    for i in 0..*num {
                         let mut comp_i = composed.clone();
+                        if *alternate && i % 2 == 1 {
+                            // within the whole pass, so a trailing rest becomes a leading one
+                            for track in &mut comp_i.tracks {
+                                track.reverse_within(time_signature, MusicTime::zero(), duration);
+                                track.events.sort();
+                                track.rests.sort();
+                            }
+                        }
+                        if *vary_volume {
+                            let factor = 1. + rng.gen_range(-REPEAT_VOLUME_VARIATION..=REPEAT_VOLUME_VARIATION);
+                            for event in comp_i.tracks.iter_mut().flat_map(|t| t.events.iter_mut()) {
+                                let volume = (event.volume.0 as f32 * factor).round() as u32;
+                                event.volume = Volume(volume.min(MAX_VOLUME));
+                            }
+                        }
                         comp_i.shift_by(offset);
-                        add_composition(&mut tracks, comp_i);
+                        add_composition(tracks, &mut state.tempo_map, comp_i);
                         offset = offset.with(time_signature) + duration;
+                        if *step != 0 {
+                            composed.transpose(*step);
+                        }
                     }
                     let mut total_duration = MusicTime::zero();
                     for _i in 0..*num {
@@ -288,64 +1196,181 @@ impl MusicString {
                     // println!("total duration for {num} repeats is {total_duration:?}, or {:?} * {num}",
                     //          composed.get_duration());
                     total_duration
-                },
-                MusicPrimitive::Transform { transform, content } => {
-                    match transform {
-                        MusicTransform::Transpose { semitones} => {
-                            let mut composed = content.compose(time_signature, Some(current_instrument))?;
-                            composed.transpose(*semitones);
-                            composed.shift_by(current_mt);
-                            let duration = composed.get_duration();
-                            add_composition(&mut tracks, composed);
-                            duration
-                        }
-                        MusicTransform::Repeat { num } => {
-                            let composed = content.compose(time_signature, Some(current_instrument))?;
-                            let duration = composed.get_duration();
-                            let mut offset = current_mt;
-                            for _i in 0..*num {
-                                let mut comp_i = composed.clone();
-                                comp_i.shift_by(offset);
-                                add_composition(&mut tracks, comp_i);
-                                offset = offset.with(time_signature) + duration;
-                            }
-                            let mut total_duration = MusicTime::zero();
-                            for _i in 0..*num {
-                                total_duration = total_duration.with(time_signature) + duration;
-                            }
-                            // println!("total duration for {num} repeats is {total_duration:?}, or {:?} * {num}",
-                            //          composed.get_duration());
-                            total_duration
-                        }
-                        MusicTransform::Compression { factor } => {
-                            let mut composed = content.compose(time_signature, Some(current_instrument))?;
-                            composed.compress(*factor);
-                            composed.shift_by(current_mt);
-                            let duration = composed.get_duration();
-                            add_composition(&mut tracks, composed);
-                            duration
-                        }
-                    }
                 }
-            };
-            current_mt = current_mt.with(time_signature) + duration;
+                MusicTransform::Compression { factor } => {
+                    let scale = Ratio::new(factor.0.numer().unsigned_abs() as BeatUnit, factor.0.denom().unsigned_abs() as BeatUnit);
+                    let mut composed = content.compose_from(time_signature, state.nested_at(start, scale, time_signature), options)?;
+                    composed.compress(*factor);
+                    composed.shift_by(start);
+                    let duration = composed.get_duration();
+                    add_composition(tracks, &mut state.tempo_map, composed);
+                    duration
+                }
+            }
         }
-        Ok(Composition {
-            tracks: tracks.into_values().collect(),
+    };
+    Ok(duration)
+}
+
+/// Every pass of a repeat is composed from the first, so its bar checks are only checked there.
+/// If they're all on barlines then so are the rest, unless a pass isn't a whole number of measures,
+/// which the second pass catches.
+fn check_repeated_bars(
+    content: &MusicString,
+    start: MusicTime,
+    duration: MusicTime,
+    num: usize,
+    state: &ComposeState,
+    time_signature: TimeSignature,
+    options: &ComposeOptions,
+) -> Result<(), ComposeError> {
+    if num > 1 && content.flat_terminals().any(|t| matches!(t, Terminal::BarCheck)) {
+        let second = start.with(time_signature) + duration;
+        content.compose_from(time_signature, state.nested_at(second, Ratio::from_integer(1), time_signature), options)?;
+    }
+    Ok(())
+}
+
+impl MusicPrimitive {
+    fn flat_terminals(&self) -> Box<dyn Iterator<Item = &Terminal> + '_> {
+        match self {
+            MusicPrimitive::Simple(Symbol::T(t)) => Box::new(std::iter::once(t)),
+            MusicPrimitive::Simple(Symbol::NT(_)) => Box::new(std::iter::empty()),
+            MusicPrimitive::Split { branches, .. } => {
+                Box::new(branches.iter().flat_map(|b| b.flat_terminals()))
+            }
+            MusicPrimitive::Repeat { num, content }
+            | MusicPrimitive::Transform { transform: MusicTransform::Repeat { num, .. }, content } => {
+                Box::new((0..*num).flat_map(move |_| content.flat_terminals()))
+            }
+            MusicPrimitive::Transform { content, .. } => Box::new(content.flat_terminals()),
+        }
+    }
+
+    /// Whether this primitive produces sound or silence, as opposed to
+    /// non-terminals, meta controls and bar checks which take up no time.
+    pub fn is_group(&self) -> bool {
+        !matches!(self, MusicPrimitive::Simple(Symbol::NT(_))
+            | MusicPrimitive::Simple(Symbol::T(Terminal::Meta(_)))
+            | MusicPrimitive::Simple(Symbol::T(Terminal::BarCheck)))
+    }
+}
+
+impl MusicString {
+    pub fn compose(&self, time_signature: TimeSignature, starting_instrument: Option<Instrument>) -> Result<Composition, ComposeError> {
+        self.compose_with(time_signature, starting_instrument, &ComposeOptions::default())
+    }
+
+    pub fn compose_with(
+        &self,
+        time_signature: TimeSignature,
+        starting_instrument: Option<Instrument>,
+        options: &ComposeOptions,
+    ) -> Result<Composition, ComposeError> {
+        let state = ComposeState::new(starting_instrument.unwrap_or(DEFAULT_INSTRUMENT));
+        let mut composition = self.compose_from(time_signature, state, options)?;
+        options.rests.finish(&mut composition);
+        Ok(composition)
+    }
+
+    /// Compose one top-level primitive at a time, so that very long strings can be
+    /// played before all of them are composed. Each chunk is already positioned in time,
+    /// and merging all the chunks gives the same result as `compose_with`.
+    /// Primitives that take up no time (meta controls and non-terminals) don't produce a chunk.
+    pub fn compose_streaming<'a>(
+        &'a self,
+        time_signature: TimeSignature,
+        starting_instrument: Option<Instrument>,
+        options: &'a ComposeOptions,
+    ) -> ComposeStream<'a> {
+        ComposeStream {
+            primitives: self.0.iter().enumerate(),
             time_signature,
-        })
+            options,
+            state: ComposeState::new(starting_instrument.unwrap_or(DEFAULT_INSTRUMENT)),
+            cursor: MusicTime::zero(),
+        }
+    }
+
+    fn compose_from(&self, time_signature: TimeSignature, mut state: ComposeState, options: &ComposeOptions) -> Result<Composition, ComposeError> {
+        let mut tracks = HashMap::new();
+        let mut cursor = MusicTime::zero();
+        // nested strings keep the tag of the top-level primitive they're in
+        let tag = state.tag;
+        for (i, mp) in self.0.iter().enumerate() {
+            if tag.is_none() {
+                state.tag = Some(i as u32);
+            }
+            compose_step(mp, &mut tracks, &mut cursor, &mut state, time_signature, options)?;
+        }
+        Ok(finish_composition(tracks, state.tempo_map, time_signature, options.rests))
     }
 
     /// Rewrites the music string according to the grammar, replacing non-terminals with their productions.
     /// If `random` is true, it will choose a random production for each non-terminal.
     /// If `panic_on_bad_production` is true, it will panic if a non-terminal has no production.
+    /// Random walks are then resolved into notes.
     pub fn parallel_rewrite(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool) -> Self {
+        self.parallel_rewrite_with_rng(grammar, random, panic_on_bad_production, &mut rand::thread_rng())
+    }
+
+    /// `parallel_rewrite`, but with the given random number generator, ex. a seeded one.
+    pub fn parallel_rewrite_with_rng<R: Rng + ?Sized>(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, rng: &mut R) -> Self {
+        self.parallel_rewrite_at_with_rng(grammar, random, panic_on_bad_production, 0, rng)
+    }
+
+    /// `parallel_rewrite` as the rewrite at `depth` (the first is 0), so only productions
+    /// whose `DepthCondition` allows that depth are used. `parallel_rewrite` is depth 0.
+    pub fn parallel_rewrite_at(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, depth: usize) -> Self {
+        self.parallel_rewrite_at_with_rng(grammar, random, panic_on_bad_production, depth, &mut rand::thread_rng())
+    }
+
+    /// `parallel_rewrite_at`, but with the given random number generator, ex. a seeded one.
+    pub fn parallel_rewrite_at_with_rng<R: Rng + ?Sized>(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, depth: usize, rng: &mut R) -> Self {
+        let mut new_string = self.rewrite_with(grammar, random, panic_on_bad_production, depth, rng);
+        let mut last = RANDOM_WALK_START;
+        new_string.resolve_random_walks(&mut last, rng);
+        new_string.simplify();
+        new_string
+    }
+
+    /// Replace each random walk with a note at most its step away from the note before it,
+    /// in the order of `flat_terminals`. `last` is the note before the string.
+    fn resolve_random_walks<R: Rng + ?Sized>(&mut self, last: &mut Pitch, rng: &mut R) {
+        for mp in self.0.iter_mut() {
+            match mp {
+                MusicPrimitive::Simple(Symbol::T(Terminal::Music { note, .. })) => match note {
+                    TerminalNote::Note { pitch } => *last = *pitch,
+                    TerminalNote::Chord { root, .. } => *last = *root,
+                    TerminalNote::RandomWalk { max_step } => {
+                        let max_step = *max_step as i8;
+                        let mut pitch = *last;
+                        pitch.transpose(rng.gen_range(-max_step..=max_step));
+                        *note = TerminalNote::Note { pitch };
+                        *last = pitch;
+                    }
+                    TerminalNote::Rest => {}
+                },
+                MusicPrimitive::Simple(_) => {}
+                MusicPrimitive::Split { branches, .. } => {
+                    for branch in branches {
+                        branch.resolve_random_walks(last, rng);
+                    }
+                }
+                MusicPrimitive::Repeat { content, .. } | MusicPrimitive::Transform { content, .. } => {
+                    content.resolve_random_walks(last, rng);
+                }
+            }
+        }
+    }
+
+    fn rewrite_with<R: Rng + ?Sized>(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, depth: usize, rng: &mut R) -> Self {
         let mut new_string = vec![];
         for (i, mp) in self.0.iter().enumerate() {
             match mp {
                 MusicPrimitive::Simple(x) => match x {
                     Symbol::NT(nt) => {
-                        if let Some(Production(nt, ms)) = if random { grammar.get_production_random(nt) } else { grammar.get_production(nt) } {
+                        if let Some(Production(nt, ms, _condition)) = if random { grammar.get_production_random_at(nt, depth, rng) } else { grammar.get_production_at(nt, depth) } {
                             new_string.extend(ms.clone().0);
                         } else {
                             if panic_on_bad_production {
@@ -357,22 +1382,22 @@ impl MusicString {
                         new_string.push(MusicPrimitive::Simple(x.clone()));
                     }
                 }
-                MusicPrimitive::Split { branches } => {
+                MusicPrimitive::Split { branches, ragged } => {
                     let new_branches = branches
                         .iter()
-                        .map(|ms| ms.parallel_rewrite(grammar, random, panic_on_bad_production))
+                        .map(|ms| ms.rewrite_with(grammar, random, panic_on_bad_production, depth, rng))
                         .collect::<Vec<_>>();
-                    new_string.push(MusicPrimitive::Split { branches: new_branches });
+                    new_string.push(MusicPrimitive::Split { branches: new_branches, ragged: *ragged });
                 }
                 MusicPrimitive::Repeat { num, content } => {
-                    let new_content = content.parallel_rewrite(grammar, random, panic_on_bad_production);
+                    let new_content = content.rewrite_with(grammar, random, panic_on_bad_production, depth, rng);
                     new_string.push(MusicPrimitive::Repeat {
                         num: *num,
                         content: new_content,
                     });
                 }
                 MusicPrimitive::Transform { transform, content } => {
-                    let new_content = content.parallel_rewrite(grammar, random, panic_on_bad_production);
+                    let new_content = content.rewrite_with(grammar, random, panic_on_bad_production, depth, rng);
                     new_string.push(MusicPrimitive::Transform {
                         transform: transform.clone(),
                         content: new_content,
@@ -383,10 +1408,67 @@ impl MusicString {
         MusicString(new_string)
     }
 
+    /// Drop meta controls that don't do anything: ones replaced by another of the same kind
+    /// before any note, and ones that change to what's already in effect.
+    /// Rewriting tends to pile these up, so `parallel_rewrite` does this after every step.
+    /// Ex. `::v=50 ::v=60 :c` is just `::v=60 :c`.
+    pub fn simplify(&mut self) {
+        self.simplify_with(KnownMeta::default());
+    }
+
+    fn simplify_with(&mut self, mut known: KnownMeta) {
+        // indices of the meta controls since the last note
+        let mut run: Vec<usize> = vec![];
+        let mut dropped = vec![false; self.0.len()];
+        for (i, mp) in self.0.iter().enumerate() {
+            match mp {
+                MusicPrimitive::Simple(Symbol::T(Terminal::Meta(control))) => {
+                    let replaced = run.iter().position(|j| matches!(
+                        &self.0[*j],
+                        MusicPrimitive::Simple(Symbol::T(Terminal::Meta(earlier))) if earlier.same_kind(control)
+                    ));
+                    if let Some(position) = replaced {
+                        dropped[run.remove(position)] = true;
+                    }
+                    run.push(i);
+                }
+                MusicPrimitive::Simple(Symbol::T(Terminal::BarCheck)) => {}
+                _ => run.clear(),
+            }
+        }
+        for (i, mp) in self.0.iter_mut().enumerate() {
+            if dropped[i] {
+                continue;
+            }
+            match mp {
+                MusicPrimitive::Simple(Symbol::T(Terminal::Meta(control))) => dropped[i] = !known.changes(control),
+                // it could be rewritten into anything
+                MusicPrimitive::Simple(Symbol::NT(_)) => known = KnownMeta::default(),
+                MusicPrimitive::Simple(_) => {}
+                // groups start from what's in effect, and don't change it for what comes after
+                MusicPrimitive::Split { branches, .. } => branches.iter_mut().for_each(|b| b.simplify_with(known)),
+                MusicPrimitive::Repeat { content, .. } | MusicPrimitive::Transform { content, .. } => content.simplify_with(known),
+            }
+        }
+        let mut dropped = dropped.into_iter();
+        self.0.retain(|_mp| !dropped.next().unwrap_or(false));
+    }
+
+    /// Every terminal this string would play, in order, ignoring timing:
+    /// split branches are visited one after another and repeats are expanded.
+    /// Transpositions aren't applied, since the terminals are borrowed.
+    pub fn flat_terminals(&self) -> impl Iterator<Item = &Terminal> {
+        self.0.iter().flat_map(|mp| mp.flat_terminals())
+    }
+
     pub fn parallel_rewrite_n(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, n: usize) -> Self {
+        self.parallel_rewrite_n_with_rng(grammar, random, panic_on_bad_production, n, &mut rand::thread_rng())
+    }
+
+    pub fn parallel_rewrite_n_with_rng<R: Rng + ?Sized>(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, n: usize, rng: &mut R) -> Self {
         let mut new_string = self.clone();
-        for _i in 0..n {
-            new_string = new_string.parallel_rewrite(grammar, random, panic_on_bad_production);
+        for i in 0..n {
+            new_string = new_string.parallel_rewrite_at_with_rng(grammar, random, panic_on_bad_production, i, rng);
         }
         new_string
     }
@@ -401,8 +1483,8 @@ impl ToString for MusicString {
                     let sym_to_string = sym.to_string();
                     s.push_str(&sym_to_string);
                 }
-                MusicPrimitive::Split { branches } => {
-                    s.push_str("{");
+                MusicPrimitive::Split { branches, ragged } => {
+                    s.push_str(if *ragged { "{< " } else { "{" });
                     let str = branches.into_iter()
                         .map(|b| b.to_string())
                         .reduce(|b1, b2| b1 + " | " + &b2)
@@ -443,18 +1525,29 @@ impl ToString for NonTerminal {
 impl ToString for Terminal {
     fn to_string(&self) -> String {
         match self {
-            Terminal::Music { duration, note } => {
+            Terminal::Music { duration, note, articulation, implicit_duration } => {
+                let duration = if *implicit_duration { String::new() } else { format!("<{}>", duration.to_string()) };
                 match note {
                     TerminalNote::Note { pitch } => {
                         let letter = pitch.letter_name();
-                        format!(":{letter}<{}>", duration.to_string())
+                        format!(":{letter}{duration}{}", articulation.marker())
                     }
                     TerminalNote::Rest => {
-                        format!(":_<{}>", duration.to_string())
+                        format!(":_{duration}")
+                    }
+                    TerminalNote::RandomWalk { max_step } => {
+                        format!(":?{max_step}{duration}{}", articulation.marker())
+                    }
+                    TerminalNote::Chord { root, quality, inversion, spread } => {
+                        let letter = root.letter_name();
+                        let inversion = if *inversion > 0 { format!("/{inversion}") } else { String::new() };
+                        let spread = if *spread { "^" } else { "" };
+                        format!(":{letter}{}{inversion}{spread}{duration}{}", quality.symbol(), articulation.marker())
                     }
                 }
             }
             Terminal::Meta(control) => control.to_string(),
+            Terminal::BarCheck => "|".to_string(),
         }
     }
 }
@@ -484,6 +1577,12 @@ impl ToString for MetaControl {
         match self {
             MetaControl::ChangeInstrument(i) => format!("::i={:?}", i),
             MetaControl::ChangeVolume(v) => format!("::v={:?}", v),
+            MetaControl::Crescendo(v) => format!("::cresc={}", v.0),
+            MetaControl::ChangeTempo(bpm) => format!("::t={bpm}"),
+            MetaControl::Ritardando(bpm) => format!("::rit={bpm}"),
+            MetaControl::Accelerando(bpm) => format!("::accel={bpm}"),
+            MetaControl::KeyShift(semitones) => format!("::key-shift={semitones:+}"),
+            MetaControl::DefaultDuration(duration) => format!("::dur={}", MusicTime(0, *duration).to_string()),
         }
     }
 }
@@ -491,6 +1590,7 @@ impl FromStr for MusicString {
     type Err = ScanError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        check_nesting(s)?;
         let scanner = consume(MusicStringScanner);
         scanner.scan(s).map(|(r, _s)| r)
     }
@@ -519,5 +1619,625 @@ where
 
 #[cfg(test)]
 mod test {
-    
+    use std::str::FromStr;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::cfg::expansion::ExpandedString;
+    use crate::cfg::{ComposeError, ComposeOptions, DepthCondition, GenerateError, Grammar, MetaControl, MusicPrimitive, MusicString, NonTerminal, Production, RestMode, Terminal, TerminalNote, REPEAT_VOLUME_VARIATION};
+    use std::collections::BTreeSet;
+    use crate::composition::{Composition, Instrument, Pitch, Track, Volume};
+    use crate::midi_file::MidiFileFormat;
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_compose_track_order_is_stable() {
+        let string = MusicString::from_str(
+            "::i=Snare :c ::i=Piano :d ::i=BassDrum :e ::i=Bass :f ::i=HiHatOpen :g"
+        ).unwrap();
+        let first = string.compose(TimeSignature::common(), None).unwrap();
+        for _i in 0..10 {
+            let again = string.compose(TimeSignature::common(), None).unwrap();
+            assert_eq!(first, again);
+        }
+        let instruments = first.tracks.iter().map(|t| t.instrument).collect::<Vec<_>>();
+        assert_eq!(instruments, vec![
+            Instrument::Piano,
+            Instrument::Bass,
+            Instrument::BassDrum,
+            Instrument::HiHatOpen,
+            Instrument::Snare,
+        ]);
+    }
+
+    #[test]
+    fn test_crescendo() {
+        let string = MusicString::from_str("::v=20 ::cresc=100 {:c :d :e :f} :g").unwrap();
+        let music = string.compose(TimeSignature::common(), None).unwrap();
+        let volumes = music.tracks[0].events.iter().map(|e| e.volume).collect::<Vec<_>>();
+        assert_eq!(volumes.len(), 5);
+        assert_eq!(volumes[0], Volume(20));
+        assert!(volumes[..4].windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(volumes[3], Volume(100));
+        // the target volume persists after the group
+        assert_eq!(volumes[4], Volume(100));
+    }
+
+    #[test]
+    fn test_staccato_keeps_slot() {
+        let string = MusicString::from_str(":c. :d").unwrap();
+        let music = string.compose(TimeSignature::common(), None).unwrap();
+        let events = &music.tracks[0].events;
+        assert_eq!(events[0].duration, Beat::new(1, 2));
+        assert_eq!(events[1].start, MusicTime::beats(1));
+        assert_eq!(music.get_duration(), MusicTime::beats(2));
+    }
+
+    #[test]
+    fn test_tenuto_full_slot() {
+        let string = MusicString::from_str(":c<2>_ :d").unwrap();
+        let music = string.compose(TimeSignature::common(), None).unwrap();
+        let events = &music.tracks[0].events;
+        assert_eq!(events[0].duration, Beat::whole(2));
+        assert_eq!(events[1].start, MusicTime::beats(2));
+    }
+
+    #[test]
+    fn test_zero_repeats() {
+        let string = MusicString::from_str("[0][:c :d]").unwrap();
+        let music = string.compose(TimeSignature::common(), None).unwrap();
+        assert!(music.tracks.is_empty());
+        assert_eq!(music.get_duration(), MusicTime::zero());
+    }
+
+    #[test]
+    fn test_max_repeats() {
+        let string = MusicString::from_str("[x1000000000][:c]").unwrap();
+        let options = ComposeOptions {
+            max_repeats: Some(1000),
+            ..ComposeOptions::default()
+        };
+        let result = string.compose_with(TimeSignature::common(), None, &options);
+        assert!(matches!(result, Err(ComposeError::TooManyRepeats { num: 1000000000, max: 1000 })));
+    }
+
+    #[test]
+    fn test_merged_rests() {
+        let string = MusicString::from_str(":c [x100][:_] :d").unwrap();
+        let options = ComposeOptions {
+            rests: RestMode::Merge,
+            ..ComposeOptions::default()
+        };
+        let kept = string.compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(kept.tracks[0].rests.len(), 100);
+        let merged = string.compose_with(TimeSignature::common(), None, &options).unwrap();
+        assert_eq!(merged.tracks[0].rests.len(), 1);
+        let rest = merged.tracks[0].rests[0];
+        assert_eq!(rest.start, MusicTime(0, Beat::whole(1)));
+        assert_eq!(rest.duration, Beat::whole(100));
+        // the notes and the overall length are unchanged
+        assert_eq!(merged.tracks[0].events, kept.tracks[0].events);
+        assert_eq!(merged.get_duration(), kept.get_duration());
+
+        let options = ComposeOptions {
+            rests: RestMode::Skip,
+            ..ComposeOptions::default()
+        };
+        let skipped = string.compose_with(TimeSignature::common(), None, &options).unwrap();
+        assert!(skipped.tracks[0].rests.is_empty());
+        assert_eq!(skipped.tracks[0].events, kept.tracks[0].events);
+    }
+
+    #[test]
+    fn test_rest_gap_markers() {
+        let string = MusicString::from_str(":c :_<2> :d :_ :_ :e").unwrap();
+        let options = ComposeOptions {
+            rests: RestMode::Gaps,
+            ..ComposeOptions::default()
+        };
+        let composition = string.compose_with(TimeSignature::common(), None, &options).unwrap();
+        let track = &composition.tracks[0];
+        assert!(track.rests.is_empty());
+        let gaps: Vec<_> = track.events.iter()
+            .filter(|e| e.is_gap())
+            .map(|e| (e.start, e.duration))
+            .collect();
+        assert_eq!(gaps, vec![
+            (MusicTime::beats(1), Beat::whole(2)),
+            (MusicTime::measures(1), Beat::whole(2)),
+        ]);
+        assert_eq!(track.events.iter().filter(|e| !e.is_gap()).count(), 3);
+        let kept = string.compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(composition.get_duration(), kept.get_duration());
+        // gaps aren't notes, so they don't change what's played or what analysis sees
+        let skipped = string.compose_with(TimeSignature::common(), None, &ComposeOptions { rests: RestMode::Skip, ..options.clone() }).unwrap();
+        assert_eq!(composition.fingerprint(), skipped.fingerprint());
+        assert_eq!(composition.pitch_class_histogram(), skipped.pitch_class_histogram());
+        assert_eq!(track.density(MusicTime::beats(2), TimeSignature::common())[0], (MusicTime::zero(), 1));
+        assert!(composition.lint(60.).is_empty());
+        assert_eq!(
+            composition.to_midi_file(120., MidiFileFormat::Parallel).unwrap(),
+            skipped.to_midi_file(120., MidiFileFormat::Parallel).unwrap(),
+        );
+        // a note that happens to be silent isn't a gap
+        let silent = MusicString::from_str("::v=0 :c").unwrap().compose_with(TimeSignature::common(), None, &options).unwrap();
+        assert!(!silent.tracks[0].events[0].is_gap());
+    }
+
+    #[test]
+    fn test_transpose_on_repeat() {
+        let string = MusicString::from_str("[3+2][:c]").unwrap();
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        let events: Vec<_> = composition.tracks[0].events.iter()
+            .map(|e| (e.start, e.pitch.name_with_octave()))
+            .collect();
+        assert_eq!(events, vec![
+            (MusicTime(0, Beat::whole(0)), "C4".to_string()),
+            (MusicTime(0, Beat::whole(1)), "D4".to_string()),
+            (MusicTime(0, Beat::whole(2)), "E4".to_string()),
+        ]);
+        let string = MusicString::from_str("[x2-12][:c :e]").unwrap();
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(composition.tracks[0].events[3].pitch.name_with_octave(), "E3");
+    }
+
+    #[test]
+    fn test_flat_terminals() {
+        let string = MusicString::from_str("[2][:c]").unwrap();
+        let notes: Vec<_> = string.flat_terminals()
+            .map(|t| match t {
+                Terminal::Music { note: TerminalNote::Note { pitch }, .. } => *pitch,
+                other => panic!("expected a note, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(notes, vec![Pitch(4, 3), Pitch(4, 3)]);
+
+        let string = MusicString::from_str("::v=20 { :d | [T2][:e] X } [x2][:_]").unwrap();
+        let terminals: Vec<_> = string.flat_terminals().collect();
+        assert_eq!(terminals.len(), 5);
+        assert!(matches!(terminals[0], Terminal::Meta(MetaControl::ChangeVolume(Volume(20)))));
+        assert!(matches!(terminals[4], Terminal::Music { note: TerminalNote::Rest, .. }));
+    }
+
+    #[test]
+    fn test_random_walk() {
+        let grammar = Grammar::from_str("start S\nS = :e :?2 :?2 { :?2 :?2 | :?2 } [x2][:?2.]").unwrap();
+        let string = MusicString::from_str("S").unwrap();
+        let walk = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            string.parallel_rewrite_with_rng(&grammar, true, true, &mut rng)
+        };
+        let rewritten = walk(1151);
+        assert_eq!(rewritten.to_string(), walk(1151).to_string());
+        let notes: Vec<_> = rewritten.flat_terminals()
+            .map(|t| match t {
+                Terminal::Music { note: TerminalNote::Note { pitch }, .. } => pitch.to_midi_note() as i32,
+                other => panic!("expected a note, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(notes.len(), 8);
+        assert_eq!(notes[0], Pitch(4, 7).to_midi_note() as i32);
+        for pair in notes.windows(2) {
+            assert!((pair[1] - pair[0]).abs() <= 2, "{notes:?} steps too far");
+        }
+        // the repeat is resolved once, so both copies are the same note
+        assert_eq!(notes[6], notes[7]);
+
+        let unresolved = MusicString::from_str(":?3").unwrap();
+        assert!(matches!(unresolved.compose(TimeSignature::common(), None), Err(ComposeError::UnresolvedRandomWalk)));
+        assert!(MusicString::from_str(":?200").is_err());
+    }
+
+    #[test]
+    fn test_get_production_with() {
+        let grammar = Grammar::from_str("start S\nS = :c\nS = :c :d :e\nS = :c :d\nT = :f").unwrap();
+        let nt = NonTerminal::Custom("S".to_string());
+        let mut longest = |productions: &[&Production]| {
+            (0..productions.len()).max_by_key(|&i| productions[i].1.0.len()).unwrap()
+        };
+        for _ in 0..10 {
+            let production = grammar.get_production_with(&nt, &mut longest).unwrap();
+            assert_eq!(production.1.0.len(), 3);
+        }
+        assert!(grammar.get_production_with(&NonTerminal::Custom("X".to_string()), &mut longest).is_none());
+        assert!(grammar.get_production_with(&nt, &mut |productions| productions.len()).is_none());
+    }
+
+    #[test]
+    fn test_depth_conditions() {
+        let grammar = Grammar::from_str("start S\nS @<3 = :c S\nS @>=3 = :d").unwrap();
+        let axiom = MusicString::from_str("S").unwrap();
+        let rewritten = |n| axiom.parallel_rewrite_n(&grammar, true, true, n).to_string();
+        assert_eq!(rewritten(3), ":C :C :C S ");
+        assert_eq!(rewritten(4), ":C :C :C :D ");
+        // nothing is left to rewrite after the fourth
+        assert_eq!(rewritten(10), rewritten(4));
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(ExpandedString::new(&axiom, &grammar, 10, &mut rng).to_music_string(&mut rng).to_string(), rewritten(4));
+
+        let nt = NonTerminal::Custom("S".to_string());
+        assert_eq!(grammar.get_production_at(&nt, 2).unwrap().2, Some(DepthCondition::Below(3)));
+        assert_eq!(grammar.get_production_at(&nt, 3).unwrap().2, Some(DepthCondition::AtLeast(3)));
+        assert!(Grammar::from_str("start S\nS @<=1 = :c\nS @>1 = :d").is_ok());
+        assert!(Grammar::from_str("start S\nS @3 = :c").is_err());
+        assert!(Grammar::from_str("start S\nS @<x = :c").is_err());
+    }
+
+    #[test]
+    fn test_timeline_table() {
+        let string = MusicString::from_str("::i=piano :c :d<2> {:e<1/2> :f<1/2> | :g} ::i=bass :_<2> :4a<2>").unwrap();
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        let table = composition.to_string();
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines, vec![
+            "             | 1.1   | 1.2   | 1.3   | 1.4   | 2.1   | 2.2   | 2.3   | 2.4",
+            "       Piano | C     | D     | ~     | E/F/G",
+            "        Bass |       |       |       |       |       |       | A     | ~",
+        ]);
+    }
+
+    #[test]
+    fn test_bar_check() {
+        let string = MusicString::from_str(":c<3> |").unwrap();
+        let result = string.compose(TimeSignature::common(), None);
+        assert!(matches!(result, Err(ComposeError::BarlineMismatch(MusicTime(0, b))) if b == Beat::whole(3)));
+        let string = MusicString::from_str(":c<4> | [x2][:d :e :f :g |] :c<2> :d<2>|").unwrap();
+        assert_eq!(string.to_string().trim(), ":C<4> | [x2][:D :E :F :G | ] :C<2> :D<2> |");
+        assert!(string.compose(TimeSignature::common(), None).is_ok());
+        // still a separator in a split
+        let string = MusicString::from_str("{ :c<4> | :d<4> } |").unwrap();
+        assert!(string.compose(TimeSignature::common(), None).is_ok());
+        // but not when it's nested in a branch
+        let string = MusicString::from_str(":c<4> { [x1][:d<4> |] | :e<4> }").unwrap();
+        assert!(matches!(&string.0[1], MusicPrimitive::Split { branches, .. } if branches.len() == 2));
+        assert!(string.compose(TimeSignature::common(), None).is_ok());
+        // counted from the start of the piece, not of the group the check is in
+        let string = MusicString::from_str(":c [x1][:d<4> |]").unwrap();
+        let result = string.compose(TimeSignature::common(), None);
+        assert!(matches!(result, Err(ComposeError::BarlineMismatch(MusicTime(1, b))) if b == Beat::whole(1)));
+        let string = MusicString::from_str(":c<2> [x1][:d<2> |]").unwrap();
+        assert!(string.compose(TimeSignature::common(), None).is_ok());
+        // a check that's on a barline the first time around but not the second
+        let string = MusicString::from_str(":c<2> [x2][:d<2> |]").unwrap();
+        let result = string.compose(TimeSignature::common(), None);
+        assert!(matches!(result, Err(ComposeError::BarlineMismatch(MusicTime(1, b))) if b == Beat::whole(2)));
+        // inside a triplet, three notes make one beat
+        let string = MusicString::from_str(":c<3> [>>3][:d :e :f |]").unwrap();
+        assert!(string.compose(TimeSignature::common(), None).is_ok());
+        let string = MusicString::from_str(":c<3> [>>3][:d :e |]").unwrap();
+        assert!(string.compose(TimeSignature::common(), None).is_err());
+    }
+
+    #[test]
+    fn test_compose_error_display() {
+        let error = MusicString::from_str(":c<3> |").unwrap()
+            .compose(TimeSignature::common(), None).unwrap_err();
+        assert_eq!(format!("{error}"), "Bar check at 3 isn't on a barline");
+        let error = ComposeError::TooManyRepeats { num: 1000, max: 64 };
+        assert_eq!(error.to_string(), "Can't repeat 1000 times, the most is 64");
+    }
+
+    #[test]
+    fn test_tempo_map() {
+        let string = MusicString::from_str("::t=100 :c :d { :e ::t=80 :f | :g<2> } ::t=140.5 :a").unwrap();
+        assert_eq!(string.to_string().trim(), "::t=100 :C :D {:E ::t=80 :F  | :G<2> } ::t=140.5 :A");
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(composition.tempo_map, vec![
+            (MusicTime::zero(), 100.),
+            (MusicTime::beats(3), 80.),
+            (MusicTime::measures(1), 140.5),
+        ]);
+        let repeated = MusicString::from_str("[x2][::t=90 :c :d]").unwrap()
+            .compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(repeated.tempo_map, vec![(MusicTime::zero(), 90.), (MusicTime::beats(2), 90.)]);
+        assert!(MusicString::from_str("::t=0 :c").is_err());
+    }
+
+    #[test]
+    fn test_default_duration() {
+        let string = MusicString::from_str("::dur=1/2 :c :d :e<2> :_ {:f | :g} ::dur=3 :a").unwrap();
+        assert_eq!(string.to_string().trim(), "::dur=1/2 :C :D :E<2> :_ {:F  | :G } ::dur=3 :A");
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        let notes: Vec<_> = composition.tracks[0].events.iter().map(|e| (e.start, e.duration)).collect();
+        assert_eq!(notes, vec![
+            (MusicTime::zero(), Beat::new(1, 2)),
+            (MusicTime(0, Beat::new(1, 2)), Beat::new(1, 2)),
+            (MusicTime::beats(1), Beat::whole(2)),
+            // the rest is half a beat too, and the split inherits it
+            (MusicTime(0, Beat::new(7, 2)), Beat::new(1, 2)),
+            (MusicTime(0, Beat::new(7, 2)), Beat::new(1, 2)),
+            (MusicTime::measures(1), Beat::whole(3)),
+        ]);
+        // without it notes are a beat long, as always
+        let composition = MusicString::from_str(":c :d").unwrap().compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(composition.get_duration(), MusicTime::beats(2));
+        assert!(MusicString::from_str("::dur=0 :c").is_err());
+        assert!(MusicString::from_str("::dur=1/0 :c").is_err());
+    }
+
+    #[test]
+    fn test_key_shift() {
+        let pitches = |s: &str| MusicString::from_str(s).unwrap()
+            .compose(TimeSignature::common(), None).unwrap()
+            .tracks[0].events.iter().map(|e| e.pitch).collect::<Vec<_>>();
+        assert_eq!(pitches("::key-shift=+2 :c :d"), vec![Pitch(4, 5), Pitch(4, 7)]);
+        // it carries into groups, and can be undone
+        assert_eq!(pitches("::key-shift=-1 [x2][:c] ::key-shift=0 :c"), vec![Pitch(4, 2), Pitch(4, 2), Pitch(4, 3)]);
+        let string = MusicString::from_str("::key-shift=+2 :c").unwrap();
+        assert_eq!(string.to_string().trim(), "::key-shift=+2 :C");
+        assert!(MusicString::from_str("::key-shift=up :c").is_err());
+    }
+
+    #[test]
+    fn test_event_tags() {
+        let string = MusicString::from_str("::v=80 :c [x2][:d :_] {:e | :g} [T2][:c]").unwrap();
+        let mut composition = string.compose(TimeSignature::common(), None).unwrap();
+        let tags = |c: &Composition| c.tracks[0].events.iter().map(|e| (e.start, e.tag)).collect::<Vec<_>>();
+        assert_eq!(tags(&composition), vec![
+            (MusicTime::beats(0), Some(1)),
+            (MusicTime::beats(1), Some(2)),
+            (MusicTime::beats(3), Some(2)),
+            (MusicTime(1, Beat::whole(1)), Some(3)),
+            (MusicTime(1, Beat::whole(1)), Some(3)),
+            (MusicTime(1, Beat::whole(2)), Some(4)),
+        ]);
+        assert!(composition.tracks[0].rests.iter().all(|r| r.tag == Some(2)));
+        // transforms keep them
+        composition.shift_by(MusicTime::beats(1));
+        composition.transpose(3);
+        assert_eq!(tags(&composition)[1..3], [(MusicTime::beats(2), Some(2)), (MusicTime::measures(1), Some(2))]);
+        // streaming tags the same way
+        let options = ComposeOptions::default();
+        let streamed: Vec<_> = string.compose_streaming(TimeSignature::common(), None, &options)
+            .map(|c| c.unwrap().tracks[0].events[0].tag)
+            .collect();
+        assert_eq!(streamed, vec![Some(1), Some(2), Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn test_alternating_repeat() {
+        let string = MusicString::from_str("[2~][:c :d]").unwrap();
+        assert_eq!(string.to_string().trim(), "[x2~][:C :D ]");
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        let notes: Vec<_> = composition.tracks[0].events.iter().map(|e| (e.start, e.pitch)).collect();
+        assert_eq!(notes, vec![
+            (MusicTime::beats(0), Pitch(4, 3)),
+            (MusicTime::beats(1), Pitch(4, 5)),
+            (MusicTime::beats(2), Pitch(4, 5)),
+            (MusicTime::beats(3), Pitch(4, 3)),
+        ]);
+        // the rest at the end of a pass is at the start of a backwards one
+        let composition = MusicString::from_str("[3~][:c :_]").unwrap()
+            .compose(TimeSignature::common(), None).unwrap();
+        let starts: Vec<_> = composition.tracks[0].events.iter().map(|e| e.start).collect();
+        assert_eq!(starts, vec![MusicTime::beats(0), MusicTime::beats(3), MusicTime::measures(1)]);
+    }
+
+    #[test]
+    fn test_varied_repeat_volumes() {
+        let string = MusicString::from_str("::v=80 [4!][:c :d]").unwrap();
+        assert!(string.to_string().contains("[x4!][:C :D ]"));
+        let options = ComposeOptions { seed: 7, ..ComposeOptions::default() };
+        let composition = string.compose_with(TimeSignature::common(), None, &options).unwrap();
+        let volumes: Vec<_> = composition.tracks[0].events.iter().map(|e| e.volume.0).collect();
+        // both notes of a pass get the same change
+        let passes: Vec<_> = volumes.chunks(2).map(|pass| {
+            assert_eq!(pass[0], pass[1]);
+            pass[0]
+        }).collect();
+        let lowest = (80. * (1. - REPEAT_VOLUME_VARIATION)).round() as u32;
+        let highest = (80. * (1. + REPEAT_VOLUME_VARIATION)).round() as u32;
+        assert!(passes.iter().all(|v| (lowest..=highest).contains(v)), "{passes:?}");
+        assert_eq!(passes.iter().collect::<BTreeSet<_>>().len(), 4, "{passes:?}");
+        // the same seed gives the same volumes, and without `!` nothing changes
+        assert_eq!(string.compose_with(TimeSignature::common(), None, &options).unwrap(), composition);
+        let plain = MusicString::from_str("::v=80 [4][:c :d]").unwrap().compose_with(TimeSignature::common(), None, &options).unwrap();
+        assert!(plain.tracks[0].events.iter().all(|e| e.volume == Volume(80)));
+    }
+
+    #[test]
+    fn test_simplify() {
+        let simplified = |s: &str| {
+            let mut string = MusicString::from_str(s).unwrap();
+            string.simplify();
+            string.to_string()
+        };
+        let same = |s: &str| MusicString::from_str(s).unwrap().to_string();
+        assert_eq!(simplified("::v=50 ::v=60 :c"), same("::v=60 :c"));
+        assert_eq!(simplified("::i=piano :c ::i=piano ::v=20 :d ::t=90 ::i=piano ::t=90 :e"), same("::i=piano :c ::v=20 :d ::t=90 :e"));
+        // groups inherit what's in effect but don't change it afterward
+        assert_eq!(simplified("::v=20 {::v=20 :c | ::v=30 :d} ::v=20 :e"), same("::v=20 {:c | ::v=30 :d} :e"));
+        // a non-terminal might change anything
+        assert_eq!(simplified("::v=20 A ::v=20 :c"), same("::v=20 A ::v=20 :c"));
+        assert_eq!(simplified("::cresc=80 ::cresc=90 [x2][:c]"), same("::cresc=90 [x2][:c]"));
+    }
+
+    #[test]
+    fn test_tempo_ramp() {
+        let string = MusicString::from_str("::t=60 ::rit=30 [x8][:c] :d :e").unwrap();
+        assert_eq!(string.to_string().trim(), "::t=60 ::rit=30 [x8][:C ] :D :E");
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        assert!(composition.tempo_map.len() > 8);
+        let starts: Vec<_> = composition.tracks[0].events.iter()
+            .map(|e| composition.seconds_at(e.start, 60.))
+            .collect();
+        let gaps: Vec<_> = starts.windows(2).map(|w| w[1] - w[0]).collect();
+        // slowing down over the repeat, so each note is further from the last
+        assert!(gaps[..7].windows(2).all(|w| w[1] > w[0]));
+        assert!(gaps[0] > 1. && gaps[6] < 2.);
+        // the integral of 60 / (60 - 30t/8) over the 8 beats
+        assert!((starts[8] - 16. * 2f32.ln()).abs() < 1e-3);
+        // then it stays at the new tempo
+        assert!((gaps[8] - 2.).abs() < 1e-4);
+
+        let accelerated = MusicString::from_str("::t=60 ::accel=120 [x4][:c]").unwrap()
+            .compose(TimeSignature::common(), None).unwrap();
+        assert!(accelerated.duration_seconds(60.) < 4.);
+        let error = MusicString::from_str("::rit=30 [x4][:c]").unwrap()
+            .compose(TimeSignature::common(), None).unwrap_err();
+        assert!(matches!(error, ComposeError::TempoRampWithoutTempo));
+    }
+
+    #[test]
+    fn test_chord() {
+        let string = MusicString::from_str(":cM<2> :am").unwrap();
+        assert_eq!(string.to_string().trim(), ":CM<2> :Am");
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        let mut c_major: Vec<_> = composition.tracks[0].events.iter()
+            .filter(|e| e.start == MusicTime::zero())
+            .map(|e| e.pitch)
+            .collect();
+        c_major.sort();
+        assert_eq!(c_major, vec![Pitch(4, 3), Pitch(4, 7), Pitch(4, 10)]);
+        assert_eq!(composition.tracks[0].events.len(), 6);
+        assert_eq!(composition.get_duration().with(TimeSignature::common()).total_beats(), Beat::whole(3));
+    }
+
+    #[test]
+    fn test_chord_voicing() {
+        let chord_pitches = |s: &str| {
+            let string = MusicString::from_str(s).unwrap();
+            let composition = string.compose(TimeSignature::common(), None).unwrap();
+            composition.tracks[0].events.iter().map(|e| e.pitch).collect::<Vec<_>>()
+        };
+        assert_eq!(chord_pitches(":cM"), vec![Pitch(4, 3), Pitch(4, 7), Pitch(4, 10)]);
+        // E-G-C, with the C raised an octave
+        assert_eq!(chord_pitches(":cM/1"), vec![Pitch(4, 7), Pitch(4, 10), Pitch(5, 3)]);
+        assert_eq!(chord_pitches(":cM/2"), vec![Pitch(4, 10), Pitch(5, 3), Pitch(5, 7)]);
+        // C-G-E, with the E raised an octave
+        assert_eq!(chord_pitches(":cM^"), vec![Pitch(4, 3), Pitch(4, 10), Pitch(5, 7)]);
+        assert_eq!(chord_pitches(":c7/1^"), vec![Pitch(4, 7), Pitch(5, 1), Pitch(5, 10), Pitch(6, 3)]);
+        assert!(MusicString::from_str(":cM/3").is_err());
+        let string = MusicString::from_str(":cM/1^<2> :am/2").unwrap();
+        assert_eq!(string.to_string().trim(), ":CM/1^<2> :Am/2");
+    }
+
+    #[test]
+    fn test_triplet_split() {
+        // a triplet then a quarter note against two straight quarter notes
+        let string = MusicString::from_str("{ [>>3][:c :d :e] :g | :f :a }").unwrap();
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(composition.tracks[0].events.len(), 6);
+        assert_eq!(composition.get_duration().with(TimeSignature::common()).total_beats(), Beat::whole(2));
+        // a duration that spills into the next measure against the same length in beats
+        let string = MusicString::from_str("{ :c<3> :d<2> | [>>1/5][:e] }").unwrap();
+        assert!(string.compose(TimeSignature::common(), None).is_ok());
+    }
+
+    #[test]
+    fn test_ragged_split() {
+        let string = MusicString::from_str("{< :c<2> | :e :f :g } :a").unwrap();
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        let events = &composition.tracks[0].events;
+        assert_eq!(events.len(), 5);
+        // both branches are there
+        assert!(events.iter().any(|e| e.duration == Beat::whole(2)));
+        // the cursor moved past the longer branch
+        let last = events.last().unwrap();
+        assert_eq!(last.start, MusicTime(0, Beat::whole(3)));
+        assert_eq!(composition.get_duration(), MusicTime(1, Beat::whole(0)));
+        // ordinary splits still need equal lengths
+        let string = MusicString::from_str("{ :c<2> | :e :f :g } :a").unwrap();
+        assert!(string.compose(TimeSignature::common(), None).is_err());
+    }
+
+    #[test]
+    fn test_streaming_matches_eager() {
+        let string = MusicString::from_str(
+            ":c ::v=20 ::cresc=80 {:d :e | ::i=Bass :3c<2>} [x3][:f :_] ::i=Piano [T2][:g. :a]"
+        ).unwrap();
+        let options = ComposeOptions::default();
+        let eager = string.compose(TimeSignature::common(), None).unwrap();
+        let mut merged: Vec<Track> = vec![];
+        let mut chunks = 0;
+        for chunk in string.compose_streaming(TimeSignature::common(), None, &options) {
+            chunks += 1;
+            for track in chunk.unwrap().tracks {
+                match merged.iter_mut().find(|t| t.instrument == track.instrument) {
+                    Some(t) => {
+                        t.events.extend(track.events);
+                        t.rests.extend(track.rests);
+                    }
+                    None => merged.push(track),
+                }
+            }
+        }
+        assert_eq!(chunks, 4);
+        merged.sort_by_key(|t| t.instrument);
+        for track in merged.iter_mut() {
+            track.events.sort();
+            track.rests.sort();
+        }
+        assert_eq!(merged, eager.tracks);
+    }
+
+    #[test]
+    fn test_generate_from_file() {
+        let contents = std::fs::read_to_string("../data/beat-1.mtx").unwrap();
+        let grammar = Grammar::from_str(&contents).unwrap();
+        let music = grammar.generate("S", 4, true, TimeSignature::common()).unwrap();
+        assert!(!music.tracks.is_empty());
+        assert!(music.get_duration() > MusicTime::zero());
+    }
+
+    #[test]
+    fn test_default_instrument() {
+        let string = MusicString::from_str(":c").unwrap();
+        let music = string.compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(music.tracks[0].instrument, Instrument::SineWave);
+
+        let grammar = Grammar::from_str("@instrument piano\nstart S\nS = :c { :d | :e }").unwrap();
+        let music = grammar.generate("S", 1, false, TimeSignature::common()).unwrap();
+        assert_eq!(music.tracks.len(), 1);
+        assert_eq!(music.tracks[0].instrument, Instrument::Piano);
+        assert_eq!(music.tracks[0].events.len(), 3);
+    }
+
+    #[test]
+    fn test_generate_bad_axiom() {
+        let grammar = Grammar::from_str("start S\nS = :c").unwrap();
+        let result = grammar.generate("(", 1, false, TimeSignature::common());
+        assert!(matches!(result, Err(GenerateError::Scan(_))));
+    }
+
+    #[test]
+    fn test_generate_variations() {
+        let grammar = Grammar::from_str("start S\nS = S S\nS = :c\nS = :d\nS = :e\nS = :f").unwrap();
+        let variations = grammar.generate_variations("S", 6, 2, 1180).unwrap();
+        assert_eq!(variations.len(), 2);
+        assert_ne!(variations[0], variations[1]);
+        let again = grammar.generate_variations("S", 6, 1, 1181).unwrap();
+        assert_eq!(again, vec![variations[1].clone()]);
+    }
+
+    #[test]
+    fn test_generate_to_duration() {
+        let grammar = Grammar::from_str("start S\nS = :c<3> S").unwrap();
+        let music = grammar.generate_to_duration("S", MusicTime::measures(4), false, TimeSignature::common()).unwrap();
+        assert_eq!(music.get_duration(), MusicTime::measures(4));
+        // six notes make 18 beats, so the last one is cut down to fit
+        let events = &music.tracks[0].events;
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[5].duration, Beat::whole(1));
+        // `T` doesn't grow, so it stops short
+        let grammar = Grammar::from_str("start T\nT = :c :d").unwrap();
+        let music = grammar.generate_to_duration("T", MusicTime::measures(4), true, TimeSignature::common()).unwrap();
+        assert_eq!(music.get_duration(), MusicTime::beats(2));
+    }
+
+    #[test]
+    fn test_inventory() {
+        let grammar = Grammar::from_str("start S\nS = ::i=piano :4c A\nS = {:5e | [x2][::i=bongohigh :2g]}\nA = [T12][:cM]").unwrap();
+        let inventory = grammar.inventory();
+        assert_eq!(inventory.instruments.iter().copied().collect::<Vec<_>>(), vec![Instrument::Piano, Instrument::BongoHigh]);
+        assert_eq!(inventory.lowest(), Some(Pitch(2, 10)));
+        assert_eq!(inventory.highest(), Some(Pitch(5, 7)));
+        assert_eq!(inventory.pitches.len(), 5);
+    }
+
+    #[test]
+    fn test_nested_group_inherits_volume() {
+        let string = MusicString::from_str("::v=20 [x2][:c]").unwrap();
+        let music = string.compose(TimeSignature::common(), None).unwrap();
+        assert!(music.tracks[0].events.iter().all(|e| e.volume == Volume(20)));
+    }
 }
\ No newline at end of file
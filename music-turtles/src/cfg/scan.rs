@@ -1,20 +1,26 @@
 /*
 
-Informally, line comments starting with `//` are allowed.
+Informally, comments starting with `//` are allowed, either on their own line
+or after whitespace at the end of a line, including inside multi-line `{ }` and `[ ][ ]` blocks.
+Lines starting with `@` are header directives: `@bpm 120`, `@time 6/8`, `@instrument piano`, `@title ...`, `@author ...`.
+A production can continue onto the next line if the line ends with `\`,
+or if it has an unclosed `{` or `[`.
 
 Grammar := `start ` NonTerminal `\n` Production*
 
-Production := NonTerminal `=` MusicString
+Production := NonTerminal (`@` (`<` | `<=` | `>` | `>=`) usize)? `=` MusicString
+   (the `@` condition limits the production to some rewrites, the first rewrite being depth 0)
 
 MusicString := MusicPrimitive*
 
 MusicPrimitive :=
   | Symbol
   | `{` (MusicString `|`)* MusicString? `}`
+  | `{<` (MusicString `|`)* MusicString? `}`   (branches may have different lengths)
   | `[` MusicTransform `][` MusicString `]`
 
 MusicTransform :=
-    | `x` usize
+    | `x`? usize `~`? `!`? ((`+`|`-`) usize)?   (`~` plays every other repeat backwards; `!` varies the volume of each repeat; `+`/`-` transposes by that many semitones each repeat)
     | `T` Int
     | `>>` Fraction
 
@@ -25,16 +31,24 @@ Symbol :=
 NonTerminal := [-a-zA-Z1-9/#\?]
 
 Terminal :=
-  | Note (`<` Duration `>`)?
+  | Note (`<` Duration `>`)? Articulation?   (without a duration, a note lasts as long as the last `::dur=` says, or 1 beat)
   | `:` MetaControl
 
+Articulation :=
+  | `.`   (staccato)
+  | `_`   (tenuto)
+
 Note :=
   | `_`
-  | Int?[a-gA-G](b|#)?
+  | Int?[a-gA-G](b|#)?   (or other names, see `NoteNaming`)
 
 MetaControl :=
   | `i=` Instrument
   | `v=` Volume
+  | `cresc=` Volume
+  | (`t=` | `rit=` | `accel=`) Float
+  | `key-shift=` (`+`|`-`)? Int
+  | `dur=` Fraction   (the duration of notes written without one from here on)
 
 Instrument := Sine | piano | ...
 
@@ -50,10 +64,12 @@ B = :0c
 
 */
 use std::collections::HashSet;
+use std::str::FromStr;
 use num::rational::Ratio;
-use crate::cfg::{Grammar, MetaControl, MusicPrimitive, MusicString, MusicTransform, NonTerminal, Production, Symbol, Terminal, TerminalNote};
-use crate::composition::{Instrument, Octave, Pitch, Volume};
-use crate::time::{Beat, MusicTime, TimeCompression};
+use num::Zero;
+use crate::cfg::{Articulation, ChordQuality, DepthCondition, Grammar, GrammarMeta, MetaControl, MusicPrimitive, MusicString, MusicTransform, NonTerminal, Production, Symbol, Terminal, TerminalNote};
+use crate::composition::{Instrument, NoteNaming, Octave, Pitch, Volume};
+use crate::time::{Beat, BeatUnit, MusicTime, TimeCompression, TimeSignature, BPM};
 
 
 #[derive(Debug)]
@@ -62,6 +78,17 @@ pub enum ScanError {
     ExpectedEither(String, String),
 }
 
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanError::Generic(message) => write!(f, "{message}"),
+            ScanError::ExpectedEither(a, b) => write!(f, "Expected '{a}' or '{b}'"),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
 pub type Result<T> = std::result::Result<T, ScanError>;
 
 pub trait Scanner {
@@ -75,6 +102,8 @@ pub struct GrammarScanner;
 
 pub struct ProductionScanner;
 
+pub struct DepthConditionScanner;
+
 pub struct MusicStringScanner;
 
 pub struct MusicPrimitiveScanner;
@@ -88,9 +117,22 @@ pub struct NonTerminalScanner;
 
 pub struct TerminalScanner;
 
-pub struct NoteScanner;
+/// Notes, named with `naming`. `NoteScanner::default()` uses English letters.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoteScanner {
+    naming: NoteNaming,
+}
+
+impl NoteScanner {
+    pub fn new(naming: NoteNaming) -> Self {
+        NoteScanner { naming }
+    }
+}
 
 pub struct DurationScanner;
+/// `DurationScanner`, but `None` if no duration is written.
+pub struct WrittenDurationScanner;
+pub struct ArticulationScanner;
 pub struct FractionScanner;
 
 pub struct MetaControlScanner;
@@ -103,15 +145,24 @@ impl Scanner for GrammarScanner {
     type Output = Grammar;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        let lines = input.lines()
-            .map(|line| line.trim())
-            .filter(|line| !line.is_empty())
-            .filter(|line| !line.trim().starts_with("//"))
-            .collect::<Vec<_>>();
+        check_nesting(input)?;
+        let (directives, lines): (Vec<_>, Vec<_>) = logical_lines(input)
+            .into_iter()
+            .map(|(_i, line)| line)
+            .partition(|line| line.starts_with('@'));
+        let meta = if directives.is_empty() {
+            None
+        } else {
+            let mut meta = GrammarMeta::default();
+            for directive in directives {
+                scan_directive(&directive, &mut meta)?;
+            }
+            Some(meta)
+        };
         if lines.is_empty() {
             return Err(ScanError::Generic("Expected at least one line".to_string()));
         }
-        let start_line = lines[0];
+        let start_line = &lines[0];
         let start = start_line
             .strip_prefix("start ")
             .ok_or_else(|| ScanError::Generic("Expected 'start' at the beginning of the first line".to_string()))?;
@@ -131,21 +182,110 @@ impl Scanner for GrammarScanner {
             .into_iter()
             .filter_map(|x| x)
             .collect();
-        Ok((Grammar { start, productions }, ""))
+        Ok((Grammar { start, productions, meta }, ""))
+    }
+}
+
+impl GrammarScanner {
+    /// Scan what can be scanned of a grammar that may be incomplete or wrong, ex. while it's being typed.
+    /// Each line is scanned on its own, and errors are given with the index of the line they're on.
+    /// Directives and the `start` line are checked but only productions are returned.
+    /// A production continued over several lines is reported on its first line.
+    pub fn scan_partial(&self, input: &str) -> (Vec<Production>, Vec<(usize, ScanError)>) {
+        let mut productions = vec![];
+        let mut errors = vec![];
+        let mut meta = GrammarMeta::default();
+        for (i, line) in logical_lines(input) {
+            let scanned = if line.starts_with('@') {
+                scan_directive(&line, &mut meta)
+            } else if let Some(start) = line.strip_prefix("start ") {
+                NonTerminalScanner.scan(start).map(|_| ())
+            } else {
+                ProductionScanner.scan(&line).map(|(production, _s)| productions.push(production))
+            };
+            if let Err(e) = scanned {
+                errors.push((i, e));
+            }
+        }
+        (productions, errors)
+    }
+}
+
+/// Read a header directive such as `@bpm 120` into the grammar's metadata.
+/// Unknown directives are warned about and ignored.
+fn scan_directive(line: &str, meta: &mut GrammarMeta) -> Result<()> {
+    let line = line.trim_start_matches('@');
+    let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let value = value.trim();
+    match name {
+        "title" => meta.title = Some(value.to_string()),
+        "author" => meta.author = Some(value.to_string()),
+        "bpm" => {
+            let bpm = value.parse()
+                .map_err(|_| ScanError::Generic(format!("Expected a number after @bpm but found {value}")))?;
+            meta.bpm = Some(bpm);
+        }
+        "instrument" => {
+            let instrument = Instrument::from_str(value)
+                .map_err(|e| ScanError::Generic(format!("Expected an instrument after @instrument: {e}")))?;
+            meta.instrument = Some(instrument);
+        }
+        "time" => {
+            let time_signature = value.split_once('/')
+                .and_then(|(beats, unit)| Some(TimeSignature(beats.trim().parse().ok()?, unit.trim().parse().ok()?)))
+                .filter(|TimeSignature(beats, unit)| *beats > 0 && *unit > 0)
+                .ok_or_else(|| ScanError::Generic(format!("Expected a time signature like 6/8 after @time but found {value}")))?;
+            meta.time_signature = Some(time_signature);
+        }
+        "include" => {
+            if value.is_empty() {
+                return Err(ScanError::Generic("Expected a file name after @include".to_string()));
+            }
+            meta.includes.push(value.to_string());
+        }
+        _ => warn!("Ignoring unknown grammar directive @{name}"),
     }
+    Ok(())
 }
 
 impl Scanner for ProductionScanner {
     type Output = Production;
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        scan_map(concat(
-            scan_map(
-                concat(NonTerminalScanner, trim(StringScanner("=".to_string()))),
-                |(nt, _s)| NonTerminal::Custom(nt),
-            ),
-            MusicStringScanner,
-        ), |(nt, str)| Production(nt, str))
-            .scan(input)
+        let (nt, rest) = NonTerminalScanner.scan(input)?;
+        let rest = rest.trim_start();
+        let (condition, rest) = match rest.strip_prefix('@') {
+            Some(rest) => {
+                let (condition, rest) = DepthConditionScanner.scan(rest)?;
+                (Some(condition), rest)
+            }
+            None => (None, rest),
+        };
+        scan_map(
+            concat(trim(StringScanner("=".to_string())), MusicStringScanner),
+            |(_s, str)| Production(NonTerminal::Custom(nt.clone()), str, condition),
+        )
+            .scan(rest)
+    }
+}
+
+impl Scanner for DepthConditionScanner {
+    type Output = DepthCondition;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        // the longer comparisons first, so `<=` isn't taken as `<`
+        let (comparison, rest) = ["<=", ">=", "<", ">"].into_iter()
+            .find_map(|op| input.strip_prefix(op).map(|rest| (op, rest)))
+            .ok_or_else(|| ScanError::Generic(format!("Expected <, <=, > or >= after @ but found {input}")))?;
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let depth = rest[..digits].parse()
+            .map_err(|_| ScanError::Generic(format!("Expected a depth after @ but found {rest}")))?;
+        let condition = match comparison {
+            "<=" => DepthCondition::AtMost(depth),
+            ">=" => DepthCondition::AtLeast(depth),
+            "<" => DepthCondition::Below(depth),
+            _ => DepthCondition::Above(depth),
+        };
+        Ok((condition, &rest[digits..]))
     }
 }
 
@@ -162,6 +302,12 @@ impl Scanner for MusicStringScanner {
             if remaining_input.is_empty() {
                 break;
             }
+            // a bar check; inside `{ }` the split scanner has already taken these as separators
+            if let Some(rest) = remaining_input.strip_prefix('|') {
+                music_string.push(MusicPrimitive::Simple(Symbol::T(Terminal::BarCheck)));
+                remaining_input = rest;
+                continue;
+            }
             match MusicPrimitiveScanner.scan(remaining_input) {
                 Ok((primitive, new_input)) => {
                     music_string.push(primitive);
@@ -201,12 +347,16 @@ impl Scanner for MusicPrimitiveSplitScanner {
     type Output = MusicPrimitive;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        // if it starts with '{', then find the matching '}' and split on each '|'
+        // if it starts with '{', then find the matching '}' and split on each '|' that isn't nested
         if let Some('{') = input.chars().next() {
             let rest = &input[1..];
+            let (ragged, rest) = match rest.strip_prefix('<') {
+                Some(rest) => (true, rest),
+                None => (false, rest),
+            };
             if let Some(end) = find_matching(rest, '{', '}') {
                 let inner = &rest[..end];
-                let mut parts = inner.split('|');
+                let mut parts = split_top_level(inner, '|').into_iter();
                 let first_part = parts.next().unwrap_or("");
                 let rest_parts: Vec<_> = parts.collect();
                 let scanner = consume(MusicStringScanner);
@@ -220,7 +370,7 @@ impl Scanner for MusicPrimitiveSplitScanner {
                         Ok(vec)
                     })?;
                 let rest = &rest[end + 1..];
-                Ok((MusicPrimitive::Split { branches: rest_music_strings }, rest))
+                Ok((MusicPrimitive::Split { branches: rest_music_strings, ragged }, rest))
             } else {
                 Err(ScanError::Generic("Expected '}'".to_string()))
             }
@@ -237,9 +387,8 @@ impl Scanner for MusicPrimitiveRepeatScanner {
         // first scan '[' a positive integer, '][', then a MusicString, and finally ']'
         if let Some('[') = input.chars().next() {
             if let Some(repeat_num_end) = input.find(']') {
-                let repeat_num = &input[1..repeat_num_end];
-                if let Some('[') = &input[repeat_num_end + 1..].chars().next() {
-                    let rest = &input[repeat_num_end + 2..];
+                let repeat_num = input[1..repeat_num_end].trim();
+                if let Some(rest) = input[repeat_num_end + 1..].trim_start().strip_prefix('[') {
                     if let Some(end_bracket) = find_matching(rest, '[', ']')
                     {
                         let music_string = &rest[..end_bracket];
@@ -274,17 +423,13 @@ impl Scanner for MusicTransformScanner {
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
         // if it starts with 'x', then scan a positive integer
+        // if it starts with a digit, it's a repeat without the 'x'
         // if it starts with 'T', then scan an integer
         // if it starts with '>>', then scan a Duration
         // otherwise, return an error
         if let Some(first) = input.chars().next() {
             match first {
-                'x' => {
-                    let num: usize = (&input[1..]).parse().map_err(|_| ScanError::Generic("Expected positive integer after 'x'".to_string()))?;
-                    Ok((MusicTransform::Repeat {
-                        num,
-                    }, ""))
-                }
+                'x' | '0'..='9' => scan_repeat(input.strip_prefix('x').unwrap_or(input)),
                 'T' => {
                     let num = &input[1..];
                     let num = num.parse().map_err(|_| ScanError::Generic("Expected integer after 'T'".to_string()))?;
@@ -295,6 +440,9 @@ impl Scanner for MusicTransformScanner {
                 '>' if input.starts_with(">>") => {
                     let (fraction, rest) = consume(FractionScanner).scan(&input[2..])
                         .map_err(|_| ScanError::Generic("Expected fraction after '>>'".to_string()))?;
+                    if fraction.is_zero() {
+                        return Err(ScanError::Generic("Can't compress by zero".to_string()));
+                    }
                     Ok((MusicTransform::Compression {
                         // use reciprocal because the user expects the inverse.
                         // ex. If they do `>>2` they expect the music to go twice as fast,
@@ -310,6 +458,31 @@ impl Scanner for MusicTransformScanner {
     }
 }
 
+/// The repeat count, optionally followed by a signed transposition per repeat, ex. `3+2`.
+fn scan_repeat(input: &str) -> Result<(MusicTransform, &str)> {
+    let count_end = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (count, rest) = input.split_at(count_end);
+    let num: usize = count.parse()
+        .map_err(|_| ScanError::Generic(format!("Expected positive integer repeat count but found {input}")))?;
+    let (alternate, rest) = match rest.strip_prefix('~') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let (vary_volume, rest) = match rest.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let step = if rest.is_empty() {
+        0
+    } else if rest.starts_with(['+', '-']) {
+        rest.parse()
+            .map_err(|_| ScanError::Generic(format!("Expected semitones to transpose each repeat by but found {rest}")))?
+    } else {
+        return Err(ScanError::Generic(format!("Unexpected {rest} after repeat count {count}")));
+    };
+    Ok((MusicTransform::Repeat { num, step, alternate, vary_volume }, ""))
+}
+
 impl Scanner for SymbolScanner {
     type Output = Symbol;
 
@@ -338,10 +511,12 @@ impl Scanner for TerminalScanner {
             ScanPrefix::from(":".to_string()),
             scan_map_input(scan_map(MetaControlScanner, |s| Terminal::Meta(s)), |s| &s[1..]),
             None,
-            scan_map(concat(NoteScanner, DurationScanner), |(note, duration)| {
+            scan_map(concat(concat(NoteScanner::default(), WrittenDurationScanner), ArticulationScanner), |((note, duration), articulation)| {
                 Terminal::Music {
                     note,
-                    duration,
+                    duration: duration.unwrap_or(MusicTime::beats(1)),
+                    articulation,
+                    implicit_duration: duration.is_none(),
                 }
             }),
         )
@@ -356,61 +531,71 @@ impl Scanner for NoteScanner {
         /*
         Note :=
           | `_`
-          | Int?[a-gA-G](b|#)?
+          | `?`Int?
+          | Int?Name(b|#)?Quality?
+        Name := any of `NoteNaming::names`, ignoring case, ex. [a-gA-G]
+        Quality := `maj7` | `min7` | `maj` | `min` | `dim` | `aug` | `m7` | `M` | `m` | `7`
         */
-        let mut chars = input.chars();
-        let mut rest = input;
+        if let Some(rest) = input.strip_prefix('?') {
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let max_step = match &rest[..digits] {
+                "" => 1,
+                digits => digits.parse().ok()
+                    .filter(|step| *step <= MAX_WALK_STEP)
+                    .ok_or_else(|| ScanError::Generic(format!("Random walk step {digits} is more than {MAX_WALK_STEP}")))?,
+            };
+            return Ok((TerminalNote::RandomWalk { max_step }, &rest[digits..]));
+        }
         let mut octave = 4;
-        let mut note = 0;
-        let mut consumed = 0;
-        if let Some(first) = chars.next() {
-            consumed += 1;
-            let next = if first == '_' {
-                return Ok((TerminalNote::Rest, chars.as_str()));
-            } else if let Some(dig) = first.to_digit(10) {
+        let mut rest = input;
+        match input.chars().next() {
+            Some('_') => return Ok((TerminalNote::Rest, &input[1..])),
+            Some(first) => if let Some(dig) = first.to_digit(10) {
                 octave = dig as Octave;
-                consumed += 1;
-                chars.next()
-            } else {
-                Some(first)
-            };
-            if let Some(next) = next {
-                if 'a' <= next.to_ascii_lowercase() && next.to_ascii_lowercase() <= 'g' {
-                    match next.to_ascii_lowercase() {
-                        'a' => note = 0,
-                        'b' => note = 2,
-                        'c' => note = 3,
-                        'd' => note = 5,
-                        'e' => note = 7,
-                        'f' => note = 8,
-                        'g' => note = 10,
-                        _ => unreachable!(),
-                    }
-                    if let Some(next) = chars.next() {
-                        if next == '#' {
-                            note += 1;
-                            consumed += 1;
-                        } else if next == 'b' {
-                            note = (note + 11) % 12; // 'b' is a flat, so we subtract 1 from the note
-                            consumed += 1;
-                        }
-                    }
-                    Ok((TerminalNote::Note { pitch: Pitch(octave, note) }, &input[consumed..]))
-                } else {
-                    Err(ScanError::Generic(
-                        format!("Expected Note: note name {next} is not a valid note."),
-                    ))
-                }
-            } else {
-                Err(ScanError::Generic(
-                    format!("Expected letter [a-g] after octave number after {first}"),
-                ))
-            }
-        } else {
-            Err(ScanError::Generic(
+                rest = &input[1..];
+            },
+            None => return Err(ScanError::Generic(
                 "Expected Note: octave number or note letter".to_string(),
-            ))
+            )),
+        }
+        let Some((name, mut note)) = self.naming.names().iter()
+            .find(|(name, _note)| rest.get(..name.len()).is_some_and(|r| r.eq_ignore_ascii_case(name)))
+            .copied() else {
+            return Err(ScanError::Generic(match rest.chars().next() {
+                Some(next) => format!("Expected Note: note name {next} is not a valid note."),
+                None => format!("Expected a note name after octave number {octave}"),
+            }));
+        };
+        rest = &rest[name.len()..];
+        if let Some(after) = rest.strip_prefix('#') {
+            note = (note + 1) % 12;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('b') {
+            note = (note + 11) % 12; // 'b' is a flat, so we subtract 1 from the note
+            rest = after;
         }
+        let pitch = Pitch(octave, note);
+        let Some((symbol, quality)) = ChordQuality::SYMBOLS.iter().find(|(symbol, _)| rest.starts_with(symbol)) else {
+            return Ok((TerminalNote::Note { pitch }, rest));
+        };
+        rest = &rest[symbol.len()..];
+        let mut inversion = 0;
+        if let Some(after) = rest.strip_prefix('/') {
+            let digits = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+            let notes = quality.intervals().len();
+            inversion = after[..digits].parse().ok()
+                .filter(|inversion| (*inversion as usize) < notes)
+                .ok_or_else(|| ScanError::Generic(format!("Expected an inversion from 0 to {} after '/'", notes - 1)))?;
+            rest = &after[digits..];
+        }
+        let spread = match rest.strip_prefix('^') {
+            Some(after) => {
+                rest = after;
+                true
+            }
+            None => false,
+        };
+        Ok((TerminalNote::Chord { root: pitch, quality: *quality, inversion, spread }, rest))
     }
 }
 
@@ -427,6 +612,9 @@ impl Scanner for DurationScanner {
                     // it's a ratio
                     let mut parts = duration.split('/');
                     match (parts.next().and_then(|s| s.parse().ok()), parts.next().and_then(|s| s.parse().ok())) {
+                        (Some(_num), Some(0)) => {
+                            Err(ScanError::Generic(format!("Duration {duration} has a zero denominator")))
+                        }
                         (Some(num), Some(denom)) => {
                             Ok((MusicTime(0, Beat::new(num, denom)), rest))
                         }
@@ -448,6 +636,33 @@ impl Scanner for DurationScanner {
     }
 }
 
+impl Scanner for WrittenDurationScanner {
+    type Output = Option<MusicTime>;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        if input.starts_with('<') {
+            DurationScanner.scan(input).map(|(duration, rest)| (Some(duration), rest))
+        } else {
+            Ok((None, input))
+        }
+    }
+}
+
+impl Scanner for ArticulationScanner {
+    type Output = Articulation;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        // optional, so this never fails
+        if let Some(rest) = input.strip_prefix('.') {
+            Ok((Articulation::Staccato, rest))
+        } else if let Some(rest) = input.strip_prefix('_') {
+            Ok((Articulation::Tenuto, rest))
+        } else {
+            Ok((Articulation::Normal, input))
+        }
+    }
+}
+
 impl Scanner for FractionScanner {
     type Output = Ratio<isize>;
 
@@ -475,33 +690,67 @@ impl Scanner for MetaControlScanner {
     type Output = MetaControl;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        let mut chars = input.chars();
-        if let Some(first) = chars.next() {
-            if let Some('=') = chars.next() {
-                let mut rest = &input[2..];
-                match first {
-                    'i' => {
-                        let (instrument, new_input) = InstrumentScanner.scan(rest)?;
-                        rest = new_input;
-                        Ok((MetaControl::ChangeInstrument(instrument), rest))
-                    }
-                    'v' => {
-                        let (volume, new_input) = VolumeScanner.scan(rest)?;
-                        rest = new_input;
-                        Ok((MetaControl::ChangeVolume(volume), rest))
-                    }
-                    _ => {
-                        Err(ScanError::Generic(format!(
-                            "Expected MetaControl: i= or v=, found {}=",
-                            first
-                        )))
+        // the key is everything up to the '=', ex. `i` or `cresc`
+        let key_len = input.find(|c: char| !(c.is_ascii_alphabetic() || c == '-'))
+            .unwrap_or(input.len());
+        if key_len == 0 {
+            return Err(ScanError::Generic("Expected MetaControl".to_string()));
+        }
+        let key = &input[..key_len];
+        if let Some(rest) = input[key_len..].strip_prefix('=') {
+            match key {
+                "i" => {
+                    let (instrument, rest) = InstrumentScanner.scan(rest)?;
+                    Ok((MetaControl::ChangeInstrument(instrument), rest))
+                }
+                "v" => {
+                    let (volume, rest) = VolumeScanner.scan(rest)?;
+                    Ok((MetaControl::ChangeVolume(volume), rest))
+                }
+                "cresc" => {
+                    let (volume, rest) = VolumeScanner.scan(rest)?;
+                    Ok((MetaControl::Crescendo(volume), rest))
+                }
+                "t" | "rit" | "accel" => {
+                    let len = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+                    let bpm = rest[..len].parse::<BPM>().ok()
+                        .filter(|bpm| *bpm > 0. && bpm.is_finite())
+                        .ok_or_else(|| ScanError::Generic(format!("Expected a tempo after {key}= but found {}", &rest[..len])))?;
+                    let control = match key {
+                        "rit" => MetaControl::Ritardando(bpm),
+                        "accel" => MetaControl::Accelerando(bpm),
+                        _ => MetaControl::ChangeTempo(bpm),
+                    };
+                    Ok((control, &rest[len..]))
+                }
+                "dur" => {
+                    let len = rest.find(|c: char| !(c.is_ascii_digit() || c == '/')).unwrap_or(rest.len());
+                    let (fraction, _s) = FractionScanner.scan(&rest[..len])?;
+                    if *fraction.numer() <= 0 || *fraction.denom() <= 0 {
+                        return Err(ScanError::Generic(format!("Expected a positive duration after dur= but found {}", &rest[..len])));
                     }
+                    let (Ok(numer), Ok(denom)) = (BeatUnit::try_from(*fraction.numer()), BeatUnit::try_from(*fraction.denom())) else {
+                        return Err(ScanError::Generic(format!("Duration {} after dur= is too big to use", &rest[..len])));
+                    };
+                    Ok((MetaControl::DefaultDuration(Beat::new(numer, denom)), &rest[len..]))
+                }
+                "key-shift" => {
+                    let len = rest.char_indices()
+                        .find(|(i, c)| !(c.is_ascii_digit() || (*i == 0 && (*c == '+' || *c == '-'))))
+                        .map_or(rest.len(), |(i, _c)| i);
+                    let semitones = rest[..len].parse::<i8>()
+                        .map_err(|_| ScanError::Generic(format!("Expected semitones after key-shift= but found {}", &rest[..len])))?;
+                    Ok((MetaControl::KeyShift(semitones), &rest[len..]))
+                }
+                _ => {
+                    Err(ScanError::Generic(format!(
+                        "Expected MetaControl: i=, v=, cresc=, t=, rit=, accel=, key-shift= or dur=, found {}=",
+                        key
+                    )))
                 }
-            } else {
-                Err(ScanError::Generic(format!("Expected '=' to follow meta control character {first}")))
             }
         } else {
-            Err(ScanError::Generic("Expected MetaControl".to_string()))
+            Err(ScanError::Generic(format!("Expected '=' to follow meta control {key}")))
         }
     }
 }
@@ -550,10 +799,10 @@ impl Scanner for InstrumentScanner {
                     if c.is_alphanumeric() || c == '_' {
                         instrument.push(c);
                     } else {
-                        return Ok((instrument.parse().unwrap(), chars.as_str()));
+                        return Ok((instrument.parse().map_err(ScanError::Generic)?, chars.as_str()));
                     }
                 }
-                Ok((instrument.parse().unwrap(), chars.as_str()))
+                Ok((instrument.parse().map_err(ScanError::Generic)?, chars.as_str()))
             } else {
                 Err(ScanError::Generic("Expected Instrument".to_string()))
             }
@@ -576,10 +825,10 @@ impl Scanner for VolumeScanner {
                     if c.is_digit(10) {
                         volume.push(c);
                     } else {
-                        return Ok((Volume(volume.parse().unwrap()), chars.as_str()));
+                        return Ok((parse_volume(&volume)?, chars.as_str()));
                     }
                 }
-                Ok((Volume(volume.parse().unwrap()), chars.as_str()))
+                Ok((parse_volume(&volume)?, chars.as_str()))
             } else {
                 Err(ScanError::Generic("Expected Volume".to_string()))
             }
@@ -589,10 +838,95 @@ impl Scanner for VolumeScanner {
     }
 }
 
+fn parse_volume(digits: &str) -> Result<Volume> {
+    digits.parse()
+        .map(Volume)
+        .map_err(|_| ScanError::Generic(format!("Volume {digits} is too large")))
+}
+
+/// Deepest nesting of `{` and `[` allowed. Scanning is recursive,
+/// so this keeps hostile input from overflowing the stack.
+pub const MAX_NESTING: usize = 64;
+
+/// Largest step allowed in a random walk `:?N`, so that it can't step off the end of `Pitch`.
+pub const MAX_WALK_STEP: u8 = 96;
+
+pub fn check_nesting(input: &str) -> Result<()> {
+    let mut depth: usize = 0;
+    for c in input.chars() {
+        match c {
+            '{' | '[' => {
+                depth += 1;
+                if depth > MAX_NESTING {
+                    return Err(ScanError::Generic(format!("Nested deeper than {MAX_NESTING} levels")));
+                }
+            }
+            '}' | ']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Join the lines of a grammar file into logical lines, skipping blank lines and comments.
+/// A line is continued onto the next if it ends with `\` or if it leaves a `{` or `[` unclosed.
+/// Each logical line comes with the index of the line it starts on.
+fn logical_lines(input: &str) -> Vec<(usize, String)> {
+    let mut lines = vec![];
+    let mut current = String::new();
+    let mut current_start = 0;
+    let mut depth = 0;
+    for (i, line) in input.lines().map(|line| strip_comment(line).trim()).enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let (line, continued) = match line.strip_suffix('\\') {
+            Some(line) => (line.trim_end(), true),
+            None => (line, false),
+        };
+        if current.is_empty() {
+            current_start = i;
+        } else {
+            current.push(' ');
+        }
+        current.push_str(line);
+        for c in line.chars() {
+            match c {
+                '{' | '[' => depth += 1,
+                '}' | ']' => depth -= 1,
+                _ => {}
+            }
+        }
+        if !continued && depth <= 0 {
+            lines.push((current_start, std::mem::take(&mut current)));
+            depth = 0;
+        }
+    }
+    if !current.is_empty() {
+        lines.push((current_start, current));
+    }
+    lines
+}
+
+/// Cut off a `//` comment, which can start a line or follow whitespace.
+/// A `//` right after other characters is part of a non-terminal, since those can contain `/`.
+pub fn strip_comment(line: &str) -> &str {
+    let mut from = 0;
+    while let Some(i) = line[from..].find("//") {
+        let i = from + i;
+        if line[..i].chars().next_back().is_none_or(char::is_whitespace) {
+            return &line[..i];
+        }
+        from = i + 2;
+    }
+    line
+}
+
 /// Assume that exactly 1 opening char has already been found. Find the next closing char.
+/// Returns its byte index, so it's safe to slice with.
 fn find_matching(input: &str, open: char, close: char) -> Option<usize> {
     let mut stack = 1;
-    for (i, c) in input.chars().enumerate() {
+    for (i, c) in input.char_indices() {
         if c == open {
             stack += 1;
         } else if c == close {
@@ -605,6 +939,26 @@ fn find_matching(input: &str, open: char, close: char) -> Option<usize> {
     None
 }
 
+/// `input` split on each `separator` that isn't inside brackets, ex. a bar check in a group.
+fn split_top_level(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut part_start = 0;
+    for (i, c) in input.char_indices() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                parts.push(&input[part_start..i]);
+                part_start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[part_start..]);
+    parts
+}
+
 pub struct StringScanner(String);
 
 impl Scanner for StringScanner {
@@ -831,8 +1185,14 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::str::FromStr;
     use num::rational::Ratio;
-    use crate::cfg::scan::{consume, ConsumeScanner, DurationScanner, FractionScanner, GrammarScanner, InstrumentScanner, MetaControlScanner, MusicPrimitiveRepeatScanner, MusicPrimitiveScanner, MusicStringScanner, MusicTransformScanner, NonTerminalScanner, NoteScanner, ProductionScanner, Scanner, SymbolScanner, TerminalScanner, VolumeScanner};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::cfg::{Articulation, ChordQuality, Grammar, MetaControl, MusicPrimitive, MusicString, MusicTransform, Terminal, TerminalNote};
+    use crate::composition::{NoteNaming, Pitch, Volume};
+    use crate::time::TimeSignature;
+    use crate::cfg::scan::{consume, ConsumeScanner, ScanError, DurationScanner, FractionScanner, GrammarScanner, InstrumentScanner, MetaControlScanner, MusicPrimitiveRepeatScanner, MusicPrimitiveScanner, MusicStringScanner, MusicTransformScanner, NonTerminalScanner, NoteScanner, ProductionScanner, Scanner, SymbolScanner, TerminalScanner, VolumeScanner};
 
     #[test]
     fn test_1() {
@@ -843,6 +1203,113 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_header_directives() {
+        let input = "@title My Piece\n@bpm 90\n@time 6/8\n@mystery 1\nstart S\nS = :c";
+        let (grammar, _s) = consume(GrammarScanner).scan(input).unwrap();
+        let meta = grammar.meta().unwrap();
+        assert_eq!(meta.bpm, Some(90.0));
+        assert_eq!(meta.time_signature, Some(TimeSignature(6, 8)));
+        assert_eq!(meta.title.as_deref(), Some("My Piece"));
+        assert_eq!(meta.author, None);
+
+        let input = "@include rhythms.grm\n@include ../shared/bass.mtx\nstart S\nS = Beat";
+        let (grammar, _s) = consume(GrammarScanner).scan(input).unwrap();
+        assert_eq!(grammar.meta().unwrap().includes, vec!["rhythms.grm", "../shared/bass.mtx"]);
+        assert!(consume(GrammarScanner).scan("@include\nstart S\nS = :c").is_err());
+    }
+
+    /// A grammar that uses most of the syntax, to cut up in the tests below.
+    const FUZZ_SEED_GRAMMAR: &str = "@bpm 90\n@time 3/4\nstart S\nS = ::i=piano ::v=40 [x2+3][:4c<1/2>. :d#_ A] {< :e<3> | [>>3/2][:f :g :_] }\nA = [T-2][::cresc=90 :5bb<2>]\n";
+
+    #[test]
+    fn test_try_from_str_truncated() {
+        for (i, _c) in FUZZ_SEED_GRAMMAR.char_indices() {
+            let _ = Grammar::from_str(&FUZZ_SEED_GRAMMAR[..i]);
+            let _ = Grammar::from_str(&FUZZ_SEED_GRAMMAR[i..]);
+        }
+        assert!(Grammar::try_from_str(FUZZ_SEED_GRAMMAR).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_str_garbage() {
+        let alphabet: Vec<char> = "start S=\n :_<>/[]{}|x+-T>>.#b0123456789abcdefgiv@éß🎵\\".chars().collect();
+        let mut rng = StdRng::seed_from_u64(1146);
+        for _i in 0..2000 {
+            let len = rng.gen_range(0..40);
+            let garbage: String = (0..len).map(|_| alphabet[rng.gen_range(0..alphabet.len())]).collect();
+            let _ = Grammar::from_str(&format!("start S\nS = {garbage}"));
+            let _ = Grammar::from_str(&garbage);
+        }
+    }
+
+    #[test]
+    fn test_scan_errors_instead_of_panics() {
+        let cases = [
+            "start S\nS = :c<1/0>",
+            "start S\nS = [>>0][:c]",
+            "start S\nS = ::v=99999999999999999999",
+            "start S\nS = ::i=kazoo",
+            "@time 4/0\nstart S\nS = :c",
+            "start S\nS = [é][:c]",
+            "start S\nS = ::dur=1/299999999999",
+        ];
+        for case in cases {
+            assert!(Grammar::try_from_str(case).is_err(), "expected an error for {case:?}");
+        }
+        // multibyte characters around brackets
+        assert!(Grammar::from_str("start S\nS = {é|:c<é>} [x2][ü]").is_ok());
+        let deep = format!("start S\nS = {}:c{}", "[x1][".repeat(10_000), "]".repeat(10_000));
+        assert!(Grammar::try_from_str(&deep).is_err());
+        assert!(MusicString::from_str(&"{".repeat(10_000)).is_err());
+    }
+
+    #[test]
+    fn test_header_bad_bpm() {
+        let input = "@bpm fast\nstart S\nS = :c";
+        assert!(consume(GrammarScanner).scan(input).is_err());
+    }
+
+    #[test]
+    fn test_no_header() {
+        let input = "start S\nS = :c";
+        let (grammar, _s) = consume(GrammarScanner).scan(input).unwrap();
+        assert!(grammar.meta().is_none());
+    }
+
+    #[test]
+    fn test_multiline_production() {
+        let input = "start S\nS = :c :d \\\n    :e :f\nB = :g";
+        let (grammar, _s) = consume(GrammarScanner).scan(input).unwrap();
+        assert_eq!(grammar.productions.len(), 2);
+        assert_eq!(grammar.productions[0].1.0.len(), 4);
+    }
+
+    #[test]
+    fn test_multiline_split() {
+        let input = "start S\nS = {\n    :c :d\n    // the bass\n    | :3c<2>\n}\nB = [x2][\n:g\n]";
+        let (grammar, _s) = consume(GrammarScanner).scan(input).unwrap();
+        assert_eq!(grammar.productions.len(), 2);
+        match &grammar.productions[0].1.0[..] {
+            [MusicPrimitive::Split { branches, ragged: false }] => assert_eq!(branches.len(), 2),
+            other => panic!("expected a single split, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_comments_inside_blocks() {
+        let input = "start S\nS = {\n    :c :d // the melody {\n    | :3c<2> // the bass [\n} [ 2 ] [\n    // twice\n    :g A//B\n]";
+        let (grammar, _s) = consume(GrammarScanner).scan(input).unwrap();
+        assert_eq!(grammar.productions.len(), 1);
+        match &grammar.productions[0].1.0[..] {
+            [MusicPrimitive::Split { branches, ragged: false }, MusicPrimitive::Transform { transform: MusicTransform::Repeat { num: 2, step: 0, .. }, content }] => {
+                assert_eq!(branches.iter().map(|b| b.0.len()).collect::<Vec<_>>(), vec![2, 1]);
+                assert_eq!(content.0.len(), 2);
+            }
+            other => panic!("expected a split and a repeat, got {other:?}"),
+        }
+    }
+
     #[test]
     fn test_instrument() {
         let input = "piano";
@@ -897,21 +1364,79 @@ mod test {
     #[test]
     fn test_note() {
         let input = "4c#";
-        let scanner = ConsumeScanner(NoteScanner);
+        let scanner = ConsumeScanner(NoteScanner::default());
         let result = scanner.scan(input);
         println!("result: {result:#?}");
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_scan_partial() {
+        let input = "S = :c :d\nT = :e :h\nU = :f A";
+        let (productions, errors) = GrammarScanner.scan_partial(input);
+        assert_eq!(productions.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 1);
+
+        let input = "@bpm fast\nstart S\n\n// a comment\nS = :c \\\n  :d\nT = :e<";
+        let (productions, errors) = GrammarScanner.scan_partial(input);
+        assert_eq!(productions.len(), 1);
+        let lines: Vec<_> = errors.iter().map(|(i, _e)| *i).collect();
+        assert_eq!(lines, vec![0, 6]);
+    }
+
+    #[test]
+    fn test_chord() {
+        let scanner = ConsumeScanner(NoteScanner::default());
+        assert!(matches!(scanner.scan("am"), Ok((TerminalNote::Chord { quality: ChordQuality::Minor, .. }, _))));
+        assert!(matches!(scanner.scan("g7"), Ok((TerminalNote::Chord { quality: ChordQuality::Dominant7, .. }, _))));
+        assert!(matches!(scanner.scan("bbm7"), Ok((TerminalNote::Chord { quality: ChordQuality::Minor7, .. }, _))));
+        assert!(matches!(scanner.scan("cmaj7"), Ok((TerminalNote::Chord { quality: ChordQuality::Major7, .. }, _))));
+        assert!(matches!(scanner.scan("f#dim"), Ok((TerminalNote::Chord { quality: ChordQuality::Diminished, .. }, _))));
+        assert!(matches!(scanner.scan("3e"), Ok((TerminalNote::Note { .. }, _))));
+        assert!(matches!(scanner.scan("cM/1^"), Ok((TerminalNote::Chord { inversion: 1, spread: true, .. }, _))));
+        assert!(matches!(scanner.scan("g7/3"), Ok((TerminalNote::Chord { inversion: 3, spread: false, .. }, _))));
+        assert!(scanner.scan("g7/4").is_err());
+        assert!(scanner.scan("cM/").is_err());
+    }
+
     #[test]
     fn test_rest() {
         let input = "_";
-        let scanner = ConsumeScanner(NoteScanner);
+        let scanner = ConsumeScanner(NoteScanner::default());
         let result = scanner.scan(input);
         println!("result: {result:#?}");
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_note_naming() {
+        let note = |naming: NoteNaming, input: &str| match ConsumeScanner(NoteScanner::new(naming)).scan(input) {
+            Ok((TerminalNote::Note { pitch }, _rest)) => pitch,
+            result => panic!("{input} didn't scan as a note: {result:?}"),
+        };
+        assert_eq!(note(NoteNaming::German, "h"), Pitch(4, 2));
+        assert_eq!(note(NoteNaming::German, "3b"), Pitch(3, 1));
+        assert_eq!(note(NoteNaming::German, "fis"), Pitch(4, 9));
+        assert_eq!(note(NoteNaming::German, "es"), Pitch(4, 6));
+        assert_eq!(note(NoteNaming::Solfege, "do"), Pitch(4, 3));
+        assert_eq!(note(NoteNaming::Solfege, "5Sol#"), Pitch(5, 11));
+        assert_eq!(note(NoteNaming::English, "c"), note(NoteNaming::Solfege, "do"));
+        assert!(ConsumeScanner(NoteScanner::default()).scan("h").is_err());
+        assert!(ConsumeScanner(NoteScanner::new(NoteNaming::Solfege)).scan("c").is_err());
+        assert_eq!(Pitch(4, 2).letter_name_in(NoteNaming::German), "H");
+        assert_eq!(Pitch(4, 3).letter_name_in(NoteNaming::Solfege), "Do");
+    }
+
+    #[test]
+    fn test_scan_error_display() {
+        let error = Grammar::from_str("@include\nstart S\nS = :c").unwrap_err();
+        assert!(!format!("{error}").is_empty());
+        assert_eq!(format!("{}", ScanError::ExpectedEither("{".to_string(), "[".to_string())), "Expected '{' or '['");
+        let boxed: Box<dyn std::error::Error> = Box::new(MusicString::from_str("{ :c").unwrap_err());
+        assert_eq!(boxed.to_string(), "Expected '}'");
+    }
+
     #[test]
     fn test_meta_control() {
         let input = "i=piano";
@@ -921,6 +1446,15 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_meta_control_crescendo() {
+        let input = "cresc=100";
+        let scanner = ConsumeScanner(MetaControlScanner);
+        let result = scanner.scan(input);
+        println!("result: {result:#?}");
+        assert!(matches!(result, Ok((MetaControl::Crescendo(Volume(100)), _))));
+    }
+
     #[test]
     fn test_meta_control_terminal() {
         let input = ":i=piano";
@@ -930,6 +1464,17 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_terminal_articulation() {
+        let scanner = ConsumeScanner(TerminalScanner);
+        let result = scanner.scan("c<2>.");
+        assert!(matches!(result, Ok((Terminal::Music { articulation: Articulation::Staccato, .. }, _))));
+        let result = scanner.scan("4c_");
+        assert!(matches!(result, Ok((Terminal::Music { articulation: Articulation::Tenuto, .. }, _))));
+        let result = scanner.scan("_");
+        assert!(matches!(result, Ok((Terminal::Music { articulation: Articulation::Normal, .. }, _))));
+    }
+
     #[test]
     fn test_terminal() {
         let input = "4c<1>";
@@ -1058,6 +1603,38 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn music_primitive_repeat_bare_count() {
+        let input = "[0][:c :d]";
+        let scanner = ConsumeScanner(MusicPrimitiveRepeatScanner);
+        let result = scanner.scan(input);
+        println!("result: {result:#?}");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn music_primitive_repeat_step() {
+        let scanner = ConsumeScanner(MusicTransformScanner);
+        assert!(matches!(scanner.scan("3+2"), Ok((MusicTransform::Repeat { num: 3, step: 2, .. }, _))));
+        assert!(matches!(scanner.scan("x4-1"), Ok((MusicTransform::Repeat { num: 4, step: -1, .. }, _))));
+        assert!(matches!(scanner.scan("x4"), Ok((MusicTransform::Repeat { num: 4, step: 0, .. }, _))));
+        assert!(scanner.scan("3+").is_err());
+        assert!(scanner.scan("3*2").is_err());
+        assert!(matches!(scanner.scan("3~"), Ok((MusicTransform::Repeat { num: 3, step: 0, alternate: true, vary_volume: false }, _))));
+        assert!(matches!(scanner.scan("x2~+5"), Ok((MusicTransform::Repeat { num: 2, step: 5, alternate: true, .. }, _))));
+        assert!(matches!(scanner.scan("4!"), Ok((MusicTransform::Repeat { num: 4, alternate: false, vary_volume: true, .. }, _))));
+        assert!(matches!(scanner.scan("x2~!-3"), Ok((MusicTransform::Repeat { step: -3, alternate: true, vary_volume: true, .. }, _))));
+        assert!(scanner.scan("2!~").is_err());
+    }
+
+    #[test]
+    fn music_primitive_repeat_bad_count() {
+        let scanner = ConsumeScanner(MusicPrimitiveRepeatScanner);
+        assert!(scanner.scan("[abc][:c :d]").is_err());
+        assert!(scanner.scan("[3abc][:c :d]").is_err());
+        assert!(scanner.scan("[x][:c :d]").is_err());
+    }
+
     #[test]
     fn music_primitive_repeat_bad_string_test_1() {
         let input = "[x3][nont( nont2]";
@@ -6,9 +6,9 @@ use serde::{Deserialize, Serialize};
 use enumkit::EnumValues;
 use num::Integer;
 use num::rational::Ratio;
-use crate::time::{Beat, BeatUnit, MusicTime, TimeCompression, TimeSignature};
+use crate::time::{Beat, BeatUnit, MusicTime, Seconds, TimeCompression, TimeSignature, BPM};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Serialize, Deserialize, EnumValues)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord, Serialize, Deserialize, EnumValues)]
 pub enum Instrument {
     SineWave,
     Piano,
@@ -27,8 +27,18 @@ pub enum Instrument {
 
 impl Instrument {
     pub fn is_percussion(&self) -> bool {
-        // matches!(self, Instrument::Drum | Instrument::Snare | Instrument::Cymbal)
-        false
+        use Instrument::*;
+        matches!(self, BassDrum | HiHatOpen | HiHatClosed | Snare | Snare2 | BongoHigh | BongoLow | Shaker1 | Shaker2)
+    }
+    /// Instruments in the same choke group cut each other off, like a closed hi-hat
+    /// silencing an open one.
+    pub fn choke_group(&self) -> Option<u8> {
+        use Instrument::*;
+        match self {
+            Shaker1 | Shaker2 => Some(1),
+            HiHatOpen | HiHatClosed => Some(2),
+            _ => None,
+        }
     }
     pub fn str_values() -> impl Iterator<Item=(Instrument, String)> {
         Instrument::values()
@@ -36,23 +46,67 @@ impl Instrument {
     }
 }
 
+/// Note names from C, used for scientific pitch notation.
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B"];
+
 /// [0, 12)
 pub type NoteNum = u8;
 pub type Octave = i8;
 
 pub type Frequency = f32;
 
+/// How notes are named, for reading and writing them. Note numbers count from A, like `Pitch`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum NoteNaming {
+    /// `a` to `g`
+    #[default]
+    English,
+    /// `h` is B and `b` is B flat, with `-is` for sharps and `-es` for flats, ex. `fis` or `es`
+    German,
+    /// `do re mi fa sol la si`, also `so` and `ti`
+    Solfege,
+}
+
+impl NoteNaming {
+    /// Every name a note can be written with and its note number, longest first so that they
+    /// can be matched in order. `#` and `b` can follow any of them.
+    pub fn names(&self) -> &'static [(&'static str, NoteNum)] {
+        match self {
+            NoteNaming::English => &[("a", 0), ("b", 2), ("c", 3), ("d", 5), ("e", 7), ("f", 8), ("g", 10)],
+            NoteNaming::German => &[
+                ("ais", 1), ("his", 3), ("ces", 2), ("cis", 4), ("des", 4), ("dis", 6), ("eis", 8),
+                ("fes", 7), ("fis", 9), ("ges", 9), ("gis", 11),
+                ("as", 11), ("es", 6),
+                ("a", 0), ("b", 1), ("h", 2), ("c", 3), ("d", 5), ("e", 7), ("f", 8), ("g", 10),
+            ],
+            NoteNaming::Solfege => &[
+                ("sol", 10),
+                ("la", 0), ("si", 2), ("ti", 2), ("do", 3), ("re", 5), ("mi", 7), ("fa", 8), ("so", 10),
+            ],
+        }
+    }
+
+    /// Names from A, like `Pitch::letter_name`.
+    fn letter_names(&self) -> [&'static str; 12] {
+        match self {
+            NoteNaming::English => ["A", "Bb", "B", "C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab"],
+            NoteNaming::German => ["A", "B", "H", "C", "Cis", "D", "Es", "E", "F", "Fis", "G", "As"],
+            NoteNaming::Solfege => ["La", "Sib", "Si", "Do", "Do#", "Re", "Mib", "Mi", "Fa", "Fa#", "Sol", "Lab"],
+        }
+    }
+}
+
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Pitch(pub Octave, pub NoteNum);
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum TrackId {
     Instrument(Instrument),
     Custom(usize),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Track {
     pub identifier: TrackId,
     pub instrument: Instrument,
@@ -60,16 +114,37 @@ pub struct Track {
     pub rests: Vec<Event>,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Event {
     pub start: MusicTime,
     pub duration: Beat,
     pub volume: Volume,
     pub pitch: Pitch,
+    /// How long the note actually sounds, if not for its whole `duration`.
+    /// `duration` still decides where the next note goes.
+    #[serde(default)]
+    pub gate: Option<Beat>,
+    /// Where the event came from, for tools that want to tell events apart by their source.
+    /// Composing sets it to the index of the top-level primitive the event was written in.
+    #[serde(default)]
+    pub tag: Option<u32>,
+    /// Whether this is a marker for a rest rather than a note, from composing with `RestMode::Gaps`.
+    /// Gaps are never played, exported, or counted as notes.
+    #[serde(default)]
+    pub gap: bool,
 }
 
 pub const MAX_VOLUME: u32 = 100;
 
+/// Echoes quieter than this are left out.
+pub const ECHO_VOLUME_FLOOR: u32 = 5;
+
+/// A groove's strength is rounded to this many steps between none and full.
+const GROOVE_PRECISION: BeatUnit = 1000;
+
+/// The instrument music starts on when nothing says otherwise.
+pub const DEFAULT_INSTRUMENT: Instrument = Instrument::SineWave;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Volume(pub u32);
 
@@ -80,8 +155,41 @@ impl Volume {
 }
 
 impl Event {
+    /// When this event is over: the end of its slot, or of its gate if that rings past it.
     pub fn get_end(&self, time_signature: TimeSignature) -> MusicTime {
-        self.start.with(time_signature) + self.duration.as_music_time(time_signature)
+        let slot_end = self.start.with(time_signature) + self.duration.as_music_time(time_signature);
+        slot_end.max(self.sounding_end(time_signature))
+    }
+
+    /// Whether this is a marker for a rest, from composing with `RestMode::Gaps`.
+    /// A note with no volume is still a note.
+    pub fn is_gap(&self) -> bool {
+        self.gap
+    }
+
+    /// How long the note sounds for, which is its gate if it has one.
+    pub fn sounding_duration(&self) -> Beat {
+        self.gate.unwrap_or(self.duration)
+    }
+
+    /// When the note is released.
+    pub fn sounding_end(&self, time_signature: TimeSignature) -> MusicTime {
+        self.start.with(time_signature) + self.sounding_duration().as_music_time(time_signature)
+    }
+
+    /// This event cut at every barline it crosses, in order.
+    pub fn split_at_barlines(&self, time_signature: TimeSignature) -> Vec<Event> {
+        let end = self.get_end(time_signature);
+        let length = |from: MusicTime, to: MusicTime| (to.with(time_signature) - from).with(time_signature).total_beats();
+        let mut pieces = vec![];
+        let mut start = self.start;
+        while MusicTime::measures(start.0 + 1) < end {
+            let barline = MusicTime::measures(start.0 + 1);
+            pieces.push(Event { start, duration: length(start, barline), ..*self });
+            start = barline;
+        }
+        pieces.push(Event { start, duration: length(start, end), ..*self });
+        pieces
     }
 }
 
@@ -132,15 +240,17 @@ impl Track {
         s.push(']');
         s
     }
-    fn get_events_at(&self, time: MusicTime, time_signature: TimeSignature) -> Vec<Event> {
+    /// Events sounding at `time`, i.e. `time` is in `[start, end)`.
+    pub fn get_events_at(&self, time: MusicTime, time_signature: TimeSignature) -> Vec<Event> {
         self.events.iter()
-            .filter(|e| time >= e.start && time <= e.get_end(time_signature))
+            .filter(|e| time >= e.start && time < e.get_end(time_signature))
             .map(|e| *e)
             .collect()
     }
+    /// Rests at `time`, half-open like `get_events_at`.
     fn get_rests_at(&self, time: MusicTime, time_signature: TimeSignature) -> Vec<Event> {
         self.rests.iter()
-            .filter(|e| time >= e.start && time <= e.get_end(time_signature))
+            .filter(|e| time >= e.start && time < e.get_end(time_signature))
             .map(|e| *e)
             .collect()
     }
@@ -169,6 +279,10 @@ impl Track {
             .unwrap_or(MusicTime::zero())
     }
 
+    pub fn duration_seconds(&self, time_signature: TimeSignature, bpm: BPM) -> Seconds {
+        self.get_duration(time_signature).to_seconds(time_signature, bpm)
+    }
+
     /// End is always inclusive
     /// Doesn't include rests
     pub fn get_events_starting_between(&self, start: MusicTime, end: MusicTime, start_exclusive: bool) -> Vec<Event> {
@@ -187,6 +301,45 @@ impl Track {
         es
     }
 
+    /// The notes and the rests that sound at any point in `[start, end)`, ex. for drawing a viewport.
+    /// That includes ones that start before `start` and are still going, and ones that go past `end`,
+    /// but not ones that only touch the window: one ending exactly at `start` or starting at `end`.
+    /// Both are in order.
+    pub fn events_in_window(&self, start: MusicTime, end: MusicTime, time_signature: TimeSignature) -> (Vec<Event>, Vec<Event>) {
+        let overlapping = |events: &[Event]| {
+            let mut overlapping: Vec<Event> = events.iter()
+                .filter(|e| e.start < end && (e.get_end(time_signature) > start || e.start >= start))
+                .copied()
+                .collect();
+            overlapping.sort();
+            overlapping
+        };
+        (overlapping(&self.events), overlapping(&self.rests))
+    }
+
+    /// How busy the track is: the number of notes starting in each `window`,
+    /// for back-to-back windows from the start of the piece to the end of the track.
+    /// Each window includes its start but not its end.
+    pub fn density(&self, window: MusicTime, time_signature: TimeSignature) -> Vec<(MusicTime, usize)> {
+        let mut density = vec![];
+        if window.with(time_signature).total_beats() == Beat::zero() {
+            return density;
+        }
+        let Some(track_end) = self.get_end(time_signature) else {
+            return density;
+        };
+        let mut start = MusicTime::zero();
+        while start < track_end {
+            let end = start.with(time_signature) + window;
+            let onsets = self.get_events_starting_between(start, end, false).iter()
+                .filter(|e| e.start < end && !e.is_gap())
+                .count();
+            density.push((start, onsets));
+            start = end;
+        }
+        density
+    }
+
     pub fn shift_by(&mut self, offset: MusicTime, time_signature: TimeSignature) {
         self.events.iter_mut()
             .chain(self.rests.iter_mut())
@@ -195,53 +348,173 @@ impl Track {
             );
     }
 
+    /// Cut every event and rest that crosses a barline into pieces at the barlines, ex. for notation.
+    /// The pieces of a note are tied: they have the same pitch and volume, and each starts where the last ends.
+    pub fn split_at_barlines(&mut self, time_signature: TimeSignature) {
+        let split = |events: &[Event]| {
+            let mut split: Vec<Event> = events.iter()
+                .flat_map(|e| e.split_at_barlines(time_signature))
+                .collect();
+            split.sort();
+            split
+        };
+        self.events = split(&self.events);
+        self.rests = split(&self.rests);
+    }
+
+    /// Put every start in terms of whole measures plus leftover beats, sort the events and rests,
+    /// and drop anything with no duration. Starts can't be negative, since times are unsigned.
+    pub fn tidy(&mut self, time_signature: TimeSignature) {
+        let tidy = |events: &mut Vec<Event>| {
+            events.retain(|e| e.duration > Beat::zero());
+            for event in events.iter_mut() {
+                event.start = event.start.with(time_signature).total_beats().as_music_time(time_signature);
+            }
+            events.sort();
+        };
+        tidy(&mut self.events);
+        tidy(&mut self.rests);
+    }
+
+    /// Merge rests that touch or overlap into single rests, leaving them sorted.
+    pub fn coalesce_rests(&mut self, time_signature: TimeSignature) {
+        self.rests.sort();
+        let mut merged: Vec<Event> = Vec::with_capacity(self.rests.len());
+        for rest in self.rests.drain(..) {
+            match merged.last_mut() {
+                Some(last) if rest.start <= last.get_end(time_signature) => {
+                    let end = last.get_end(time_signature).max(rest.get_end(time_signature));
+                    last.duration = (end.with(time_signature) - last.start).with(time_signature).total_beats();
+                }
+                _ => merged.push(rest),
+            }
+        }
+        self.rests = merged;
+    }
+
+    /// Strum chords like a guitar: of the notes that start together, each one after the lowest
+    /// (or highest, if not `up`) starts `spread` after the one before it, and is shortened so that
+    /// it still ends when it did. Notes too short to start that late are left where they are.
+    pub fn strum(&mut self, spread: Beat, up: bool, time_signature: TimeSignature) {
+        let mut chords: HashMap<MusicTime, Vec<&mut Event>> = HashMap::new();
+        for event in self.events.iter_mut() {
+            chords.entry(event.start).or_default().push(event);
+        }
+        for mut chord in chords.into_values() {
+            chord.sort_by_key(|e| e.pitch);
+            if !up {
+                chord.reverse();
+            }
+            for (k, event) in chord.into_iter().enumerate() {
+                let delay = Beat::from_ratio(spread.as_ratio() * Ratio::from_integer(k as BeatUnit));
+                if delay < event.duration {
+                    event.start = event.start.with(time_signature) + delay.as_music_time(time_signature);
+                    event.duration = event.duration - delay;
+                }
+            }
+        }
+        self.events.sort();
+    }
+
     pub fn transpose(&mut self, semitones: i8) {
         for event in &mut self.events {
             event.pitch.transpose(semitones);
         }
     }
 
+    /// Move every note by octaves until it is within `low..=high`, keeping its pitch class.
+    /// If the range is narrower than an octave, a note whose pitch class doesn't fit ends up just above `low`.
+    pub fn fold_into_range(&mut self, low: Pitch, high: Pitch) {
+        for event in &mut self.events {
+            while event.pitch > high {
+                event.pitch.transpose(-12);
+            }
+            while event.pitch < low {
+                event.pitch.transpose(12);
+            }
+        }
+    }
+
     /// Flip entire track, keeping it within its start/end bounds.
     pub fn reverse(&mut self, time_signature: TimeSignature) {
         if let (Some(start), Some(end)) = (self.get_start(), self.get_end(time_signature)) {
-            self.events.iter_mut()
-                .chain(self.rests.iter_mut())
-                .for_each(|e| {
-                    let offset = e.start.with(time_signature) - start;
-                    let new_start = (end.with(time_signature) - offset).with(time_signature) - e.duration.as_music_time(time_signature);
-                    e.start = new_start;
-                });
-            self.events.reverse();
-            self.rests.reverse();
+            self.reverse_within(time_signature, start, end);
         }
     }
 
+    /// Flip the track around the middle of `start..end`, which can be wider than the track itself.
+    pub fn reverse_within(&mut self, time_signature: TimeSignature, start: MusicTime, end: MusicTime) {
+        self.events.iter_mut()
+            .chain(self.rests.iter_mut())
+            .for_each(|e| {
+                let offset = e.start.with(time_signature) - start;
+                let new_start = (end.with(time_signature) - offset).with(time_signature) - e.duration.as_music_time(time_signature);
+                e.start = new_start;
+            });
+        self.events.reverse();
+        self.rests.reverse();
+    }
+
     /// Compress all timings by the compression factor.
     /// Example: if the factor is 0.5, it will compress the track to half its length.
+    /// A negative factor reverses the track first and then scales by its absolute value,
+    /// so -1/2 plays the track backwards in half the time. Durations always stay positive.
     pub fn compress(&mut self, time_signature: TimeSignature, compression: TimeCompression) {
+        if let (Some(start), Some(end)) = (self.get_start(), self.get_end(time_signature)) {
+            self.compress_within(time_signature, compression, start, end);
+        }
+    }
+
+    /// `compress`, but toward `start` (and reversing within `start..end`) instead of the track's own bounds.
+    pub fn compress_within(&mut self, time_signature: TimeSignature, compression: TimeCompression, start: MusicTime, end: MusicTime) {
         let factor = compression.0;
         if factor < Ratio::new(0, 1) {
-            self.reverse(time_signature);
+            self.reverse_within(time_signature, start, end);
         }
         let factor = Ratio::new(factor.numer().abs() as BeatUnit, factor.denom().abs() as BeatUnit);
-        if let (Some(start), Some(end)) = (self.get_start(), self.get_end(time_signature)) {
-            self.events.iter_mut()
-                .chain(self.rests.iter_mut())
-                .for_each(|e| {
-                    let offset = (e.start.with(time_signature) - start).with(time_signature) * factor;
-                    e.start = start.with(time_signature) + offset.time;
-                    e.duration = (e.duration.as_music_time(time_signature).with(time_signature) * factor).total_beats();
-                });
-        }
+        self.events.iter_mut()
+            .chain(self.rests.iter_mut())
+            .for_each(|e| {
+                let offset = (e.start.with(time_signature) - start).with(time_signature) * factor;
+                e.start = start.with(time_signature) + offset.time;
+                e.duration = (e.duration.as_music_time(time_signature).with(time_signature) * factor).total_beats();
+            });
     }
 }
 
-impl Add<Self> for Track {
-    type Output = Track;
+/// Why two tracks or compositions couldn't be merged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    DifferentInstruments(Instrument, Instrument),
+    DifferentTimeSignatures(TimeSignature, TimeSignature),
+}
 
-    fn add(self, rhs: Self) -> Self::Output {
+/// Something in a composition that will probably play wrong. See `Composition::lint`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompositionWarning {
+    pub track: TrackId,
+    /// when the problem starts, from the start of the composition
+    pub at: Seconds,
+    pub kind: CompositionWarningKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompositionWarningKind {
+    /// Two notes of the same pitch overlap. Over MIDI, the first note-off cuts both short.
+    Overlap(Pitch),
+    /// The note can't be sent over MIDI. See `Composition::fit_midi_range`.
+    OutOfRange(Pitch),
+    /// The note has no duration, so it's never heard.
+    ZeroDuration(Pitch),
+    /// A rest in the same track overlaps a note of this pitch.
+    RestOverlapsNote(Pitch),
+}
+
+impl Track {
+    /// `Add`, but with an error instead of a panic if the instruments differ.
+    pub fn try_add(self, rhs: Self) -> Result<Self, MergeError> {
         if self.instrument != rhs.instrument {
-            panic!("not the same instruments!");
+            return Err(MergeError::DifferentInstruments(self.instrument, rhs.instrument));
         }
         let mut events = self.events;
         for event in rhs.events {
@@ -253,12 +526,20 @@ impl Add<Self> for Track {
             rests.push(rest);
         }
         rests.sort();
-        Track {
+        Ok(Track {
             identifier: self.identifier,
             instrument: self.instrument,
             events,
             rests,
-        }
+        })
+    }
+}
+
+impl Add<Self> for Track {
+    type Output = Track;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.try_add(rhs).unwrap_or_else(|e| panic!("can't add tracks: {e:?}"))
     }
 }
 
@@ -278,23 +559,65 @@ impl Pitch {
     }
 
     pub fn letter_name(&self) -> String {
+        self.letter_name_in(NoteNaming::English)
+    }
+
+    /// `letter_name`, in another way of naming notes.
+    pub fn letter_name_in(&self, naming: NoteNaming) -> String {
         let Pitch(_, note_num) = *self;
-        let note_num = note_num as u8;
-        match note_num {
-            0 => "A",
-            1 => "Bb",
-            2 => "B",
-            3 => "C",
-            4 => "C#",
-            5 => "D",
-            6 => "Eb",
-            7 => "E",
-            8 => "F",
-            9 => "F#",
-            10 => "G",
-            11 => "Ab",
-            _ => panic!("Invalid note number")
-        }.to_string()
+        match naming.letter_names().get(note_num as usize) {
+            Some(name) => name.to_string(),
+            None => panic!("Invalid note number"),
+        }
+    }
+
+    /// Scientific pitch notation, ex. "C4" for MIDI note 60, consistent with `to_midi_note`.
+    pub fn name_with_octave(&self) -> String {
+        let midi = self.midi_number();
+        format!("{}{}", NOTE_NAMES[midi.rem_euclid(12) as usize], midi.div_euclid(12) - 1)
+    }
+
+    /// Parse scientific pitch notation, ex. "C4", "F#3" or "Bb-1".
+    pub fn from_name(name: &str) -> Result<Pitch, String> {
+        let mut chars = name.chars();
+        let letter = chars.next().ok_or("Empty note name".to_string())?;
+        let base = match letter.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return Err(format!("Invalid note letter {letter} in {name}")),
+        };
+        let rest = chars.as_str();
+        let (accidental, octave) = if let Some(octave) = rest.strip_prefix('#') {
+            (1, octave)
+        } else if let Some(octave) = rest.strip_prefix('b') {
+            (-1, octave)
+        } else {
+            (0, rest)
+        };
+        let octave: i32 = octave.parse()
+            .map_err(|_| format!("Invalid octave {octave} in {name}"))?;
+        let midi = (octave + 1) * 12 + base + accidental;
+        Ok(Pitch::from_midi_number(midi))
+    }
+
+    pub fn from_midi_note(note: u8) -> Pitch {
+        Pitch::from_midi_number(note as i32)
+    }
+
+    fn from_midi_number(midi: i32) -> Pitch {
+        let from_a = midi - 9;
+        Pitch(from_a.div_euclid(12) as Octave, from_a.rem_euclid(12) as NoteNum)
+    }
+
+    /// Same as `to_midi_note`, but without overflowing for pitches outside of MIDI.
+    pub(crate) fn midi_number(&self) -> i32 {
+        let Pitch(octave, note_num) = *self;
+        octave as i32 * 12 + note_num as i32 + 9
     }
 
     pub fn transpose(&mut self, semitones: i8) {
@@ -305,10 +628,14 @@ impl Pitch {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Composition {
     pub tracks: Vec<Track>,
     pub time_signature: TimeSignature,
+    /// Tempo changes (from `::t=`) in order. Before the first one, it's up to the player.
+    /// Compression and reversal leave these where they are.
+    #[serde(default)]
+    pub tempo_map: Vec<(MusicTime, BPM)>,
 }
 
 impl Composition {
@@ -330,6 +657,51 @@ impl Composition {
         }
         s
     }
+    /// A table of what each track plays on each beat, with a column per beat from the start.
+    /// Notes are shown by letter name, in a chord lowest first, ex. `C/E/G`.
+    /// A beat where a note from an earlier beat is still sounding is shown as `~`.
+    pub fn timeline_table(&self) -> String {
+        let ts = self.time_signature;
+        let beats = self.get_end()
+            .map_or(0, |end| end.with(ts).total_beats().as_ratio().ceil().to_integer());
+        let header: Vec<String> = (0..beats)
+            .map(|beat| format!("{}.{}", beat / ts.0 + 1, beat % ts.0 + 1))
+            .collect();
+        let rows: Vec<Vec<String>> = self.tracks.iter()
+            .map(|track| (0..beats)
+                .map(|beat| {
+                    let start = MusicTime::from_whole_beats(ts, beat);
+                    let end = MusicTime::from_whole_beats(ts, beat + 1);
+                    let mut starting: Vec<_> = track.events.iter()
+                        .filter(|e| e.start >= start && e.start < end)
+                        .map(|e| e.pitch)
+                        .collect();
+                    starting.sort();
+                    starting.dedup();
+                    if !starting.is_empty() {
+                        starting.iter().map(|p| p.letter_name()).collect::<Vec<_>>().join("/")
+                    } else if !track.get_events_at(start, ts).is_empty() {
+                        "~".to_string()
+                    } else {
+                        String::new()
+                    }
+                })
+                .collect())
+            .collect();
+        let width = header.iter().chain(rows.iter().flatten()).map(|cell| cell.len()).max().unwrap_or(0);
+        let line = |label: String, cells: &[String]| {
+            let cells: Vec<_> = cells.iter().map(|cell| format!("{cell:<width$}")).collect();
+            // leave off empty cells at the end
+            format!("{label:>12} | {}", cells.join(" | ")).trim_end_matches([' ', '|']).to_string()
+        };
+        let mut table = line(String::new(), &header);
+        for (track, row) in self.tracks.iter().zip(&rows) {
+            table.push('\n');
+            table.push_str(&line(track.identifier.to_string(), row));
+        }
+        table
+    }
+
     pub fn get_duration(&self) -> MusicTime {
         let start = self.tracks.iter().filter_map(|t| t.get_start())
             .min();
@@ -341,6 +713,61 @@ impl Composition {
         }
     }
 
+    /// How long the piece lasts from time zero, rather than from its first note or rest like
+    /// `get_duration`, so a piece that starts late (ex. after a rest that was left out) counts
+    /// the time before it. This is how long the piece takes when it loops.
+    pub fn span_from_zero(&self) -> MusicTime {
+        match self.get_end() {
+            Some(end) => end.with(self.time_signature) - MusicTime::zero(),
+            None => MusicTime::zero(),
+        }
+    }
+
+    /// A hash of what's played, for caching and spotting duplicate variations. It only depends
+    /// on each note's instrument, start, duration, pitch, and volume, so the order of the tracks
+    /// (or of the notes in them) and how the start times are written don't change it.
+    /// It's FNV-1a over each note written out as bytes, so it's the same across builds and platforms.
+    pub fn fingerprint(&self) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+        let mut notes: Vec<Vec<u8>> = self.tracks.iter()
+            .flat_map(|track| track.events.iter().filter(|e| !e.is_gap()).map(|e| {
+                let start = e.start.with(self.time_signature).total_beats().as_ratio();
+                let duration = e.duration.as_ratio();
+                let mut bytes = format!("{:?}", track.instrument).into_bytes();
+                bytes.push(0);
+                for n in [start.numer(), start.denom(), duration.numer(), duration.denom()] {
+                    bytes.extend(n.to_le_bytes());
+                }
+                bytes.push(e.pitch.to_midi_note());
+                bytes.extend(e.volume.0.to_le_bytes());
+                bytes
+            }))
+            .collect();
+        notes.sort();
+        notes.iter().flatten().fold(FNV_OFFSET, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+    }
+
+    /// `get_duration` in seconds, in this composition's time signature, starting at `bpm`
+    /// and following the tempo map from there.
+    pub fn duration_seconds(&self, bpm: BPM) -> Seconds {
+        self.seconds_at(self.get_duration(), bpm)
+    }
+
+    /// Seconds from the start to `time`, starting at `bpm` and following the tempo map from there.
+    /// Each tempo holds until the next change, so a ramp's many small steps add up to its
+    /// smoothly changing tempo.
+    pub fn seconds_at(&self, time: MusicTime, bpm: BPM) -> Seconds {
+        let time_signature = self.time_signature;
+        let mut seconds = 0.;
+        let (mut from, mut current) = (MusicTime::zero(), bpm);
+        for (change, bpm) in self.tempo_map.iter().take_while(|(change, _bpm)| *change < time) {
+            seconds += (change.with(time_signature) - from).to_seconds(time_signature, current);
+            (from, current) = (*change, *bpm);
+        }
+        seconds + (time.with(time_signature) - from).to_seconds(time_signature, current)
+    }
+
     pub fn get_start(&self) -> Option<MusicTime> {
         self.tracks.iter()
             .filter_map(|t| t.get_start())
@@ -356,6 +783,23 @@ impl Composition {
     pub fn shift_by(&mut self, offset: MusicTime) {
         self.tracks.iter_mut()
             .for_each(|tr| tr.shift_by(offset, self.time_signature));
+        for (time, _bpm) in &mut self.tempo_map {
+            *time = time.with(self.time_signature) + offset;
+        }
+    }
+
+    /// The first track played on `instrument`, ex. to work on one part of a piece.
+    pub fn track(&self, instrument: Instrument) -> Option<&Track> {
+        self.tracks.iter().find(|t| t.instrument == instrument)
+    }
+
+    pub fn track_mut(&mut self, instrument: Instrument) -> Option<&mut Track> {
+        self.tracks.iter_mut().find(|t| t.instrument == instrument)
+    }
+
+    /// Like `track`, but taking the track out of the composition.
+    pub fn into_track(self, instrument: Instrument) -> Option<Track> {
+        self.tracks.into_iter().find(|t| t.instrument == instrument)
     }
 
     pub fn transpose(&mut self, semitones: i8) {
@@ -364,37 +808,378 @@ impl Composition {
         }
     }
 
+    /// Like `transpose`, but only for the tracks played on `instrument`, ex. to move a bass line
+    /// that came out in the wrong register.
+    pub fn transpose_instrument(&mut self, instrument: Instrument, semitones: i8) {
+        for track in self.tracks.iter_mut().filter(|t| t.instrument == instrument) {
+            track.transpose(semitones);
+        }
+    }
+
+    /// Reassign every track's instrument, ex. to swap out the generic `SineWave`.
+    /// Tracks that end up with the same id and instrument are merged (and re-sorted) with `Add`.
+    pub fn map_instruments(&mut self, f: impl Fn(Instrument) -> Instrument) {
+        let mut tracks: Vec<Track> = vec![];
+        for mut track in self.tracks.drain(..) {
+            track.instrument = f(track.instrument);
+            if let TrackId::Instrument(i) = track.identifier {
+                track.identifier = TrackId::Instrument(f(i));
+            }
+            let existing = tracks.iter()
+                .position(|t| t.identifier == track.identifier && t.instrument == track.instrument);
+            if let Some(index) = existing {
+                let merged = tracks.remove(index) + track;
+                tracks.insert(index, merged);
+            } else {
+                tracks.push(track);
+            }
+        }
+        self.tracks = tracks;
+    }
+
+    /// Transpose by the fewest octaves that put every note inside the MIDI range 0..=127,
+    /// so that it can be played safely. Returns the shift applied, in semitones.
+    /// If the piece is wider than the MIDI range, its lowest note is brought into range.
+    /// The shift is at most 10 octaves either way, the most `transpose` can do.
+    pub fn fit_midi_range(&mut self) -> i8 {
+        let notes = self.tracks.iter()
+            .flat_map(|t| t.events.iter().map(|e| e.pitch.midi_number()));
+        let (lowest, highest) = match (notes.clone().min(), notes.max()) {
+            (Some(lowest), Some(highest)) => (lowest, highest),
+            _ => return 0,
+        };
+        let octaves = if lowest < 0 {
+            // round up to a whole number of octaves
+            (-lowest + 11) / 12
+        } else if highest > 127 {
+            -((highest - 127 + 11) / 12)
+        } else {
+            0
+        };
+        let semitones = (octaves.clamp(-10, 10) * 12) as i8;
+        if semitones != 0 {
+            self.transpose(semitones);
+        }
+        semitones
+    }
+
+    /// How many notes there are of each pitch class across all tracks, indexed by `NoteNum`
+    /// (so 0 is A and 3 is C). Octaves are ignored.
+    pub fn pitch_class_histogram(&self) -> [usize; 12] {
+        let mut histogram = [0; 12];
+        for event in self.tracks.iter().flat_map(|t| &t.events).filter(|e| !e.is_gap()) {
+            histogram[event.pitch.1 as usize % 12] += 1;
+        }
+        histogram
+    }
+
+    /// The pitch classes that are played, most common first. Ties are in `NoteNum` order.
+    pub fn most_common_pitch_classes(&self) -> Vec<NoteNum> {
+        let histogram = self.pitch_class_histogram();
+        let mut classes: Vec<NoteNum> = (0..12).filter(|&n| histogram[n as usize] > 0).collect();
+        classes.sort_by_key(|&n| std::cmp::Reverse(histogram[n as usize]));
+        classes
+    }
+
+    /// Add `repeats` echoes of the notes of every `instrument` track to that track,
+    /// the k-th echo `delay * k` later and with its volume scaled by `decay^k`.
+    /// Echoes quieter than `ECHO_VOLUME_FLOOR` are left out.
+    pub fn echo(&mut self, instrument: Instrument, delay: MusicTime, decay: f32, repeats: usize) {
+        let time_signature = self.time_signature;
+        for track in self.tracks.iter_mut().filter(|t| t.instrument == instrument) {
+            let original = track.events.clone();
+            for k in 1..=repeats {
+                let offset = (delay.with(time_signature) * Ratio::from_integer(k as BeatUnit)).time;
+                let gain = decay.powi(k as i32);
+                track.events.extend(original.iter()
+                    .map(|e| Event {
+                        start: e.start.with(time_signature) + offset,
+                        volume: Volume((e.volume.0 as f32 * gain).round() as u32),
+                        ..*e
+                    })
+                    .filter(|e| e.volume.0 >= ECHO_VOLUME_FLOOR));
+            }
+            track.events.sort();
+        }
+    }
+
+    /// Linearly ramp the volume of every note, from `from` at the first onset
+    /// to `to` at the last onset. A lone onset just gets `to`.
+    pub fn ramp_volume(&mut self, from: Volume, to: Volume) {
+        let time_signature = self.time_signature;
+        let onsets = self.tracks.iter()
+            .flat_map(|t| t.events.iter().map(|e| e.start));
+        let (first, last) = match (onsets.clone().min(), onsets.max()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+        let span = (last.with(time_signature) - first).with(time_signature).total_beats().as_float();
+        for event in self.tracks.iter_mut().flat_map(|t| t.events.iter_mut()) {
+            let progress = if span > 0. {
+                (event.start.with(time_signature) - first).with(time_signature).total_beats().as_float() / span
+            } else {
+                1.
+            };
+            let volume = from.0 as f32 + (to.0 as f32 - from.0 as f32) * progress;
+            event.volume = Volume(volume.round() as u32);
+        }
+    }
+
+    /// Give the other tracks the feel of `template`, a groove: each note is moved toward the nearest
+    /// onset in the template, and its volume toward the volume there, by `strength` of the way.
+    /// A strength of 1 snaps notes onto the template and 0 changes nothing. The template's own
+    /// track, if it's in this piece, is left alone, and so are rests.
+    pub fn apply_groove(&mut self, template: &Track, strength: f32) {
+        let time_signature = self.time_signature;
+        let beats = |time: MusicTime| time.with(time_signature).total_beats().as_ratio();
+        let onsets: Vec<_> = template.events.iter().map(|e| (beats(e.start), e.volume)).collect();
+        let strength = strength.clamp(0., 1.);
+        // kept as a ratio so a full strength lands exactly on the onset
+        let fraction = Ratio::new((strength * GROOVE_PRECISION as f32).round() as BeatUnit, GROOVE_PRECISION);
+        for track in self.tracks.iter_mut().filter(|t| t.identifier != template.identifier) {
+            for event in &mut track.events {
+                let start = beats(event.start);
+                let distance = |onset: Ratio<BeatUnit>| if onset > start { onset - start } else { start - onset };
+                let Some(&(onset, volume)) = onsets.iter().min_by_key(|(onset, _v)| distance(*onset)) else {
+                    return;
+                };
+                let start = if onset > start {
+                    start + (onset - start) * fraction
+                } else {
+                    start - (start - onset) * fraction
+                };
+                event.start = Beat::from_ratio(start).as_music_time(time_signature);
+                let blended = event.volume.0 as f32 + (volume.0 as f32 - event.volume.0 as f32) * strength;
+                event.volume = Volume(blended.round() as u32);
+            }
+            track.events.sort();
+        }
+    }
+
+    /// Fade the volume up from silence over the first `duration` of the piece.
+    /// Each note is scaled by where its midpoint falls on the ramp.
+    pub fn fade_in(&mut self, duration: MusicTime) {
+        if let Some(start) = self.get_start() {
+            let start = start.with(self.time_signature).total_beats().as_float();
+            self.fade(duration, |midpoint| midpoint - start);
+        }
+    }
+
+    /// Fade the volume down to silence over the last `duration` of the piece.
+    /// Each note is scaled by where its midpoint falls on the ramp.
+    pub fn fade_out(&mut self, duration: MusicTime) {
+        if let Some(end) = self.get_end() {
+            let end = end.with(self.time_signature).total_beats().as_float();
+            self.fade(duration, |midpoint| end - midpoint);
+        }
+    }
+
+    /// Scale every note by `distance(midpoint) / duration`, clamped to `0..=1`,
+    /// where `distance` is how far into the fade (in beats) the note's midpoint is.
+    fn fade(&mut self, duration: MusicTime, distance: impl Fn(f32) -> f32) {
+        let time_signature = self.time_signature;
+        let span = duration.with(time_signature).total_beats().as_float();
+        if span <= 0. {
+            return;
+        }
+        for event in self.tracks.iter_mut().flat_map(|t| t.events.iter_mut()) {
+            let midpoint = event.start.with(time_signature).total_beats().as_float()
+                + event.duration.as_float() / 2.;
+            let gain = (distance(midpoint) / span).clamp(0., 1.);
+            event.volume = Volume((event.volume.0 as f32 * gain).round() as u32);
+        }
+    }
+
     /// Compress all timings by the compression factor toward the start of the track.
-    /// If the factor is negative, it will reverse the track.
+    /// If the factor is negative, it will reverse the track and scale by the absolute value.
     /// Example, if the factor is 0.5, it will compress the track to half its length.
+    /// Each track uses its own bounds, so tracks that start or end at different times
+    /// can move relative to each other. See `compress_aligned`.
     pub fn compress(&mut self, compression: TimeCompression) {
         for track in &mut self.tracks {
             track.compress(self.time_signature, compression);
         }
     }
+
+    /// Like `compress`, but every track is compressed toward the start of the whole composition
+    /// (and reversed within its bounds), so tracks stay lined up with each other.
+    pub fn compress_aligned(&mut self, compression: TimeCompression) {
+        if let (Some(start), Some(end)) = (self.get_start(), self.get_end()) {
+            for track in &mut self.tracks {
+                track.compress_within(self.time_signature, compression, start, end);
+            }
+        }
+    }
+
+    /// Cut the piece off at `end`: anything starting at or after it is dropped,
+    /// and notes and rests that cross it are shortened to end there.
+    pub fn clip(&mut self, end: MusicTime) {
+        let time_signature = self.time_signature;
+        for track in &mut self.tracks {
+            for events in [&mut track.events, &mut track.rests] {
+                events.retain(|e| e.start < end);
+                for event in events.iter_mut() {
+                    if event.get_end(time_signature) > end {
+                        event.duration = (end.with(time_signature) - event.start).with(time_signature).total_beats();
+                    }
+                }
+            }
+        }
+        self.tempo_map.retain(|(time, _bpm)| *time < end);
+    }
+
+    /// Merge `backing` in under this piece, played back to back from the very beginning until this
+    /// piece ends, ex. a one measure drum beat under a whole melody. The last time through is
+    /// clipped where this piece ends, as is a backing that is longer than it. The backing's
+    /// tempo map is ignored, since this piece's tempo map is already in charge.
+    pub fn layer_with_repeat(&mut self, backing: &Composition) -> Result<(), MergeError> {
+        if self.time_signature != backing.time_signature {
+            return Err(MergeError::DifferentTimeSignatures(self.time_signature, backing.time_signature));
+        }
+        let (Some(end), Some(period)) = (self.get_end(), backing.get_end()) else {
+            return Ok(());
+        };
+        if period == MusicTime::zero() {
+            return Ok(());
+        }
+        let mut tiled = Composition {
+            tracks: vec![],
+            time_signature: self.time_signature,
+            tempo_map: vec![],
+        };
+        let mut offset = MusicTime::zero();
+        while offset < end {
+            let mut tile = backing.clone();
+            tile.tempo_map.clear();
+            tile.shift_by(offset);
+            tiled = tiled.try_add(tile)?;
+            offset = offset.with(self.time_signature) + period;
+        }
+        tiled.clip(end);
+        *self = self.clone().try_add(tiled)?;
+        Ok(())
+    }
+
+    /// Metric modulation: what lasted `from` now lasts `to`, ex. triplet eighths (1/3 beat) becoming
+    /// straight eighths (1/2 beat). Every time, from the very beginning of the piece, and the tempo map
+    /// are scaled by exactly `to / from`, so the notes keep their relationships but the pulse is felt
+    /// at another speed. Unlike `compress`, tracks aren't scaled toward their own starts and are never
+    /// reversed. Does nothing if `from` is zero.
+    pub fn metric_modulate(&mut self, from: Beat, to: Beat) {
+        if from == Beat::zero() {
+            return;
+        }
+        let time_signature = self.time_signature;
+        let factor = to.as_ratio() / from.as_ratio();
+        let scale = |time: MusicTime| (time.with(time_signature) * factor).time;
+        for event in self.tracks.iter_mut().flat_map(|t| t.events.iter_mut().chain(t.rests.iter_mut())) {
+            event.start = scale(event.start);
+            event.duration = Beat::from_ratio(event.duration.as_ratio() * factor);
+        }
+        for (time, _bpm) in &mut self.tempo_map {
+            *time = scale(*time);
+        }
+    }
+
+    /// Check every track for things that will probably play wrong, without changing anything.
+    /// `bpm` is only used to say when each problem happens. Warnings are in order of track, then time.
+    pub fn lint(&self, bpm: BPM) -> Vec<CompositionWarning> {
+        let ts = self.time_signature;
+        let mut warnings = vec![];
+        for track in &self.tracks {
+            let mut found = vec![];
+            // gap markers are rests that were moved in with the notes
+            let (gaps, mut events): (Vec<&Event>, Vec<&Event>) = track.events.iter().partition(|e| e.is_gap());
+            events.sort();
+            for (i, event) in events.iter().enumerate() {
+                if !(0..=127).contains(&event.pitch.midi_number()) {
+                    found.push((event.start, CompositionWarningKind::OutOfRange(event.pitch)));
+                }
+                if event.duration == Beat::zero() {
+                    found.push((event.start, CompositionWarningKind::ZeroDuration(event.pitch)));
+                }
+                let end = event.get_end(ts);
+                // sorted, so only later notes can start inside this one
+                if let Some(next) = events[i + 1..].iter().take_while(|e| e.start < end).find(|e| e.pitch == event.pitch) {
+                    found.push((next.start, CompositionWarningKind::Overlap(event.pitch)));
+                }
+            }
+            for rest in track.rests.iter().chain(gaps) {
+                let rest_end = rest.get_end(ts);
+                for event in events.iter().filter(|e| e.start < rest_end && rest.start < e.get_end(ts)) {
+                    found.push((rest.start.max(event.start), CompositionWarningKind::RestOverlapsNote(event.pitch)));
+                }
+            }
+            found.sort_by_key(|(start, _kind)| *start);
+            warnings.extend(found.into_iter().map(|(start, kind)| CompositionWarning {
+                track: track.identifier,
+                at: start.to_seconds(ts, bpm),
+                kind,
+            }));
+        }
+        warnings
+    }
+
+    /// Clean up after transformations, before playback or export. See `Track::tidy`.
+    pub fn tidy(&mut self) {
+        for track in &mut self.tracks {
+            track.tidy(self.time_signature);
+        }
+    }
+
+    /// Play the whole composition backwards. Unlike `Track::reverse` on each track,
+    /// tracks are flipped within the composition's bounds, so they stay lined up with each other.
+    pub fn reverse(&mut self) {
+        if let (Some(start), Some(end)) = (self.get_start(), self.get_end()) {
+            for track in &mut self.tracks {
+                track.reverse_within(self.time_signature, start, end);
+            }
+        }
+    }
 }
 
-impl Add<Self> for Composition {
-    type Output = Self;
+impl Composition {
+    /// A compact binary encoding, ex. for caching generated pieces. Much smaller than JSON.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(self)
+    }
 
-    fn add(self, rhs: Self) -> Self::Output {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+
+    /// `Add`, but with an error instead of a panic if the time signatures differ,
+    /// or if tracks with the same id have different instruments.
+    /// Tracks with the same id are merged, and otherwise keep their order.
+    pub fn try_add(self, rhs: Self) -> Result<Self, MergeError> {
         if self.time_signature != rhs.time_signature {
-            panic!("differing time signatures!!");
-        }
-        let mut map = HashMap::new();
-        for track in self.tracks {
-            let id = track.identifier;
-            if let Some(mtrack) = map.remove(&id) {
-                let new_track = mtrack + track;
-                map.insert(id, new_track);
+            return Err(MergeError::DifferentTimeSignatures(self.time_signature, rhs.time_signature));
+        }
+        let mut tracks: Vec<Track> = vec![];
+        for track in self.tracks.into_iter().chain(rhs.tracks) {
+            if let Some(index) = tracks.iter().position(|t| t.identifier == track.identifier) {
+                let merged = tracks.remove(index).try_add(track)?;
+                tracks.insert(index, merged);
             } else {
-                map.insert(id, track);
+                tracks.push(track);
             }
         }
-        Composition {
-            tracks: map.into_values().collect(),
+        let mut tempo_map = self.tempo_map;
+        tempo_map.extend(rhs.tempo_map);
+        tempo_map.sort_by_key(|(time, _bpm)| *time);
+        Ok(Composition {
+            tracks,
             time_signature: self.time_signature,
-        }
+            tempo_map,
+        })
+    }
+}
+
+impl Add<Self> for Composition {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.try_add(rhs).unwrap_or_else(|e| panic!("can't add compositions: {e:?}"))
     }
 }
 
@@ -416,10 +1201,26 @@ impl FromStr for Instrument {
     }
 }
 
-mod composition_element_tests {
+impl Display for Composition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.timeline_table())
+    }
+}
+
+impl Display for TrackId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackId::Instrument(instrument) => write!(f, "{:?}", instrument),
+            TrackId::Custom(id) => write!(f, "Custom({})", id),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod composition_element_tests {
     use num::rational::Ratio;
     use rodio::cpal::BufferSize::Default;
-    use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume};
+    use crate::composition::{Composition, CompositionWarningKind, Event, Instrument, MergeError, Pitch, Track, TrackId, Volume};
     use crate::time::{Beat, MusicTime, TimeCompression, TimeSignature};
 
     fn assert_epsilon_close(a: f32, b: f32) {
@@ -472,6 +1273,33 @@ mod composition_element_tests {
         assert_eq!(pitch, Pitch(5, 0)); // C5
     }
 
+    #[test]
+    fn test_pitch_name_round_trip() {
+        assert_eq!(Pitch::from_name("C4").unwrap().name_with_octave(), "C4");
+        assert_eq!(Pitch::from_name("C4").unwrap().to_midi_note(), 60);
+        assert_eq!(Pitch::from_name("A4").unwrap().to_midi_note(), 69);
+        assert_eq!(Pitch::from_name("F#3").unwrap().name_with_octave(), "F#3");
+        assert_eq!(Pitch::from_name("Gb3").unwrap(), Pitch::from_name("F#3").unwrap());
+        assert_eq!(Pitch::from_name("Cb4").unwrap().name_with_octave(), "B3");
+        for midi in 0..=127 {
+            let pitch = Pitch::from_midi_note(midi);
+            assert_eq!(Pitch::from_name(&pitch.name_with_octave()).unwrap(), pitch);
+        }
+    }
+
+    #[test]
+    fn test_pitch_name_errors() {
+        assert!(Pitch::from_name("").is_err());
+        assert!(Pitch::from_name("H4").is_err());
+        assert!(Pitch::from_name("C").is_err());
+        assert!(Pitch::from_name("C#x").is_err());
+    }
+
+    /// A note at full volume, for building up tracks.
+    pub(crate) fn note(start: MusicTime, duration: Beat, pitch: Pitch) -> Event {
+        Event { start, duration, volume: Volume(100), pitch, gate: None, tag: None, gap: false }
+    }
+
     fn comp_template(events: Vec<Event>) -> Composition {
         Composition {
             tracks: vec![
@@ -483,6 +1311,7 @@ mod composition_element_tests {
                 }
             ],
             time_signature: TimeSignature::common(),
+            tempo_map: vec![],
         }
     }
 
@@ -495,6 +1324,9 @@ mod composition_element_tests {
                 duration: Beat::whole(2),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         let composition_half = comp_template(vec![
@@ -503,6 +1335,9 @@ mod composition_element_tests {
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         composition1.compress(compression);
@@ -518,6 +1353,9 @@ mod composition_element_tests {
                 duration: Beat::whole(2),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         let composition_reversed = comp_template(vec![
@@ -526,6 +1364,9 @@ mod composition_element_tests {
                 duration: Beat::whole(2),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         composition1.compress(compression);
@@ -541,12 +1382,18 @@ mod composition_element_tests {
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(1, Beat::whole(1)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         let composition_reversed = comp_template(vec![
@@ -555,12 +1402,18 @@ mod composition_element_tests {
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(1, Beat::whole(1)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         composition1.compress(compression);
@@ -576,12 +1429,18 @@ mod composition_element_tests {
                 duration: Beat::whole(2),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(1, Beat::whole(2)),
                 duration: Beat::whole(2),
                 volume: Volume(100),
                 pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         let composition_half = comp_template(vec![
@@ -590,24 +1449,665 @@ mod composition_element_tests {
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(1, Beat::whole(1)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         composition1.compress(compression);
         assert_eq!(composition1, composition_half);
     }
-}
 
-impl Display for TrackId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TrackId::Instrument(instrument) => write!(f, "{:?}", instrument),
-            TrackId::Custom(id) => write!(f, "Custom({})", id),
+    fn two_note_template() -> Composition {
+        comp_template(vec![
+            Event {
+                start: MusicTime(1, Beat::whole(0)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
+            },
+            Event {
+                start: MusicTime(1, Beat::whole(1)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
+            }
+        ])
+    }
+
+    #[test]
+    fn test_try_add_different_instruments() {
+        let sine = two_note_template().tracks.remove(0);
+        let mut piano = sine.clone();
+        piano.instrument = Instrument::Piano;
+        assert_eq!(sine.clone().try_add(piano), Err(MergeError::DifferentInstruments(Instrument::SineWave, Instrument::Piano)));
+        assert_eq!(sine.clone().try_add(sine).unwrap().events.len(), 4);
+    }
+
+    #[test]
+    fn test_try_add_different_time_signatures() {
+        let mut waltz = two_note_template();
+        waltz.time_signature = TimeSignature(3, 4);
+        assert_eq!(two_note_template().try_add(waltz),
+                   Err(MergeError::DifferentTimeSignatures(TimeSignature::common(), TimeSignature(3, 4))));
+    }
+
+    #[test]
+    fn test_span_from_zero() {
+        let note = note(MusicTime::beats(3), Beat::whole(2), Pitch(4, 0));
+        // as though a leading rest was left out
+        let mut composition = comp_template(vec![note]);
+        assert_eq!(composition.get_duration(), MusicTime::beats(2));
+        assert_eq!(composition.span_from_zero(), MusicTime(1, Beat::whole(1)));
+        // with the rest kept, the two agree
+        composition.tracks[0].rests.push(Event { start: MusicTime::zero(), duration: Beat::whole(3), volume: Volume(0), ..note });
+        assert_eq!(composition.get_duration(), composition.span_from_zero());
+        assert_eq!(comp_template(vec![]).span_from_zero(), MusicTime::zero());
+    }
+
+    #[test]
+    fn test_visualize_boundaries() {
+        let track = Track {
+            identifier: TrackId::Instrument(Instrument::Piano),
+            instrument: Instrument::Piano,
+            events: vec![note(MusicTime::beats(1), Beat::whole(1), Pitch(4, 3))],
+            rests: vec![note(MusicTime::zero(), Beat::whole(1), Pitch(4, 3))],
+        };
+        let ts = TimeSignature::common();
+        // the rest is over when the note starts
+        assert!(track.get_rests_at(MusicTime::beats(1), ts).is_empty());
+        assert_eq!(track.visualize(2, ts, MusicTime::zero(), MusicTime::beats(2)), "[-X]");
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let piano = Track {
+            identifier: TrackId::Instrument(Instrument::Piano),
+            instrument: Instrument::Piano,
+            events: vec![note(MusicTime::zero(), Beat::whole(1), Pitch(4, 3)), note(MusicTime::beats(5), Beat::whole(1), Pitch(4, 7))],
+            rests: vec![],
+        };
+        let bass = Track {
+            identifier: TrackId::Instrument(Instrument::Bass),
+            instrument: Instrument::Bass,
+            events: vec![note(MusicTime::zero(), Beat::whole(1), Pitch(2, 3))],
+            rests: vec![],
+        };
+        let mut composition = comp_template(vec![]);
+        composition.tracks = vec![piano.clone(), bass.clone()];
+        let mut reordered = comp_template(vec![]);
+        reordered.tracks = vec![bass, piano];
+        assert_eq!(composition.fingerprint(), reordered.fingerprint());
+        // the same start written another way
+        reordered.tracks[1].events[1].start = MusicTime(1, Beat::whole(1));
+        assert_eq!(composition.fingerprint(), reordered.fingerprint());
+        reordered.tracks[1].events[1].pitch = Pitch(4, 8);
+        assert_ne!(composition.fingerprint(), reordered.fingerprint());
+        // pinned, so that a change to the encoding or the hash doesn't go unnoticed
+        assert_eq!(composition.fingerprint(), 3825602931731287474);
+        assert_eq!(comp_template(vec![]).fingerprint(), 0xcbf29ce484222325);
+    }
+
+    #[test]
+    fn test_layer_with_repeat() {
+        let mut song = comp_template(vec![note(MusicTime::zero(), Beat::whole(16), Pitch(4, 3))]);
+        let mut backing = comp_template(vec![
+            note(MusicTime::zero(), Beat::whole(1), Pitch(2, 3)),
+            note(MusicTime(0, Beat::whole(2)), Beat::whole(2), Pitch(2, 10)),
+        ]);
+        backing.tracks[0].identifier = TrackId::Custom(1);
+        song.layer_with_repeat(&backing).unwrap();
+        assert_eq!(song.tracks.len(), 2);
+        let starts: Vec<_> = song.tracks[1].events.iter().map(|e| e.start).collect();
+        assert_eq!(starts, (0..4)
+            .flat_map(|m| [MusicTime(m, Beat::zero()), MusicTime(m, Beat::whole(2))])
+            .collect::<Vec<_>>());
+        assert_eq!(song.get_end(), Some(MusicTime::measures(4)));
+
+        // a backing longer than the song is clipped
+        let mut short = comp_template(vec![note(MusicTime::zero(), Beat::whole(3), Pitch(4, 3))]);
+        short.layer_with_repeat(&backing).unwrap();
+        assert_eq!(short.tracks[1].events, vec![
+            note(MusicTime::zero(), Beat::whole(1), Pitch(2, 3)),
+            note(MusicTime(0, Beat::whole(2)), Beat::whole(1), Pitch(2, 10)),
+        ]);
+
+        let mut waltz = backing.clone();
+        waltz.time_signature = TimeSignature(3, 4);
+        assert_eq!(song.layer_with_repeat(&waltz),
+                   Err(MergeError::DifferentTimeSignatures(TimeSignature::common(), TimeSignature(3, 4))));
+    }
+
+    #[test]
+    fn test_add_keeps_both_sides() {
+        let mut other = two_note_template();
+        other.tracks[0].identifier = TrackId::Custom(1);
+        other.shift_by(MusicTime::measures(1));
+        let sum = two_note_template().try_add(other).unwrap();
+        assert_eq!(sum.tracks.len(), 2);
+        let sum = sum.clone() + two_note_template();
+        assert_eq!(sum.tracks[0].events.len(), 4);
+        assert!(sum.tracks[0].events.is_sorted());
+    }
+
+    #[test]
+    fn test_duration_seconds() {
+        let mut composition = two_note_template();
+        composition.time_signature = TimeSignature(3, 4);
+        let manual = composition.get_duration().to_seconds(TimeSignature(3, 4), 90.);
+        assert_eq!(composition.duration_seconds(90.), manual);
+        // two beats at 90 bpm
+        assert!((composition.duration_seconds(90.) - 4. / 3.).abs() < 1e-6);
+        assert_eq!(composition.tracks[0].duration_seconds(TimeSignature(3, 4), 90.), manual);
+    }
+
+    #[test]
+    fn test_map_instruments_merges() {
+        let mut composition = two_note_template();
+        composition.tracks[0].identifier = TrackId::Instrument(Instrument::SineWave);
+        let mut piano = composition.tracks[0].clone();
+        piano.identifier = TrackId::Instrument(Instrument::Piano);
+        piano.instrument = Instrument::Piano;
+        piano.events = vec![Event {
+            start: MusicTime(0, Beat::whole(0)),
+            duration: Beat::whole(1),
+            volume: Volume(50),
+            pitch: Pitch(3, 0),
+            gate: None,
+            tag: None,
+            gap: false,
+        }];
+        composition.tracks.push(piano);
+        composition.map_instruments(|i| match i {
+            Instrument::SineWave => Instrument::Piano,
+            i => i,
+        });
+        assert_eq!(composition.tracks.len(), 1);
+        let track = &composition.tracks[0];
+        assert_eq!(track.identifier, TrackId::Instrument(Instrument::Piano));
+        assert_eq!(track.instrument, Instrument::Piano);
+        assert_eq!(track.events.len(), 3);
+        // the piano note was first in time, so it has to be sorted to the front
+        assert_eq!(track.events[0].pitch, Pitch(3, 0));
+        assert!(track.events.is_sorted());
+    }
+
+    #[test]
+    fn test_fit_midi_range() {
+        let mut composition = two_note_template();
+        assert_eq!(composition.fit_midi_range(), 0);
+        // MIDI 129 and 135
+        composition.tracks[0].events[0].pitch = Pitch(10, 0);
+        composition.tracks[0].events[1].pitch = Pitch(10, 6);
+        assert_eq!(composition.fit_midi_range(), -12);
+        assert_eq!(composition.tracks[0].events[0].pitch.to_midi_note(), 117);
+        assert_eq!(composition.tracks[0].events[1].pitch.to_midi_note(), 123);
+        // MIDI -3 and 57
+        composition.tracks[0].events[0].pitch = Pitch(-1, 0);
+        composition.tracks[0].events[1].pitch = Pitch(4, 0);
+        assert_eq!(composition.fit_midi_range(), 12);
+        assert_eq!(composition.tracks[0].events[0].pitch.to_midi_note(), 9);
+        assert_eq!(composition.tracks[0].events[1].pitch.to_midi_note(), 69);
+    }
+
+    #[test]
+    fn test_compression_negative_half() {
+        // reversed and played in half the time
+        let compression = TimeCompression(Ratio::new(-1, 2));
+        let mut composition1 = two_note_template();
+        let expected = comp_template(vec![
+            Event {
+                start: MusicTime(1, Beat::whole(0)),
+                duration: Beat::new(1, 2),
+                volume: Volume(100),
+                pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
+            },
+            Event {
+                start: MusicTime(1, Beat::new(1, 2)),
+                duration: Beat::new(1, 2),
+                volume: Volume(100),
+                pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
+            }
+        ]);
+        composition1.compress(compression);
+        assert_eq!(composition1, expected);
+    }
+
+    #[test]
+    fn test_compression_negative_double() {
+        // reversed and played in twice the time
+        let compression = TimeCompression(Ratio::new(-2, 1));
+        let mut composition1 = two_note_template();
+        let expected = comp_template(vec![
+            Event {
+                start: MusicTime(1, Beat::whole(0)),
+                duration: Beat::whole(2),
+                volume: Volume(100),
+                pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
+            },
+            Event {
+                start: MusicTime(1, Beat::whole(2)),
+                duration: Beat::whole(2),
+                volume: Volume(100),
+                pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
+            }
+        ]);
+        composition1.compress(compression);
+        assert_eq!(composition1, expected);
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let mut composition = two_note_template();
+        composition.tracks.push(Track {
+            identifier: TrackId::Custom(3),
+            instrument: Instrument::Piano,
+            events: vec![Event {
+                start: MusicTime(2, Beat::new(1, 3)),
+                duration: Beat::new(5, 2),
+                volume: Volume(40),
+                pitch: Pitch(-1, 11),
+                gate: None,
+                tag: None,
+                gap: false,
+            }],
+            rests: vec![Event {
+                start: MusicTime(0, Beat::zero()),
+                duration: Beat::new(1, 3),
+                volume: Volume(0),
+                pitch: Pitch(0, 0),
+                gate: None,
+                tag: None,
+                gap: false,
+            }],
+        });
+        let bytes = composition.to_bytes().unwrap();
+        assert_eq!(Composition::from_bytes(&bytes).unwrap(), composition);
+        assert!(Composition::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut composition = two_note_template();
+        composition.tracks.push(Track {
+            identifier: TrackId::Custom(3),
+            instrument: Instrument::Piano,
+            events: vec![Event {
+                start: MusicTime(0, Beat::new(3, 2)),
+                duration: Beat::new(1, 6),
+                volume: Volume(70),
+                pitch: Pitch(3, 5),
+                gate: None,
+                tag: None,
+                gap: false,
+            }],
+            rests: vec![],
+        });
+        let json = serde_json::to_value(&composition).unwrap();
+        assert_eq!(json["tracks"][1]["identifier"], serde_json::json!({"Custom": 3}));
+        assert_eq!(json["tracks"][1]["events"][0]["duration"], serde_json::json!({"numerator": 1, "denominator": 6}));
+        let back: Composition = serde_json::from_value(json).unwrap();
+        assert_eq!(back, composition);
+    }
+
+    #[test]
+    fn test_split_at_barlines() {
+        let mut track = Track {
+            identifier: TrackId::Instrument(Instrument::Piano),
+            instrument: Instrument::Piano,
+            events: vec![
+                Event {
+                    start: MusicTime(0, Beat::whole(3)),
+                    duration: Beat::whole(3),
+                    volume: Volume(60),
+                    pitch: Pitch(4, 3),
+                    gate: None,
+                    tag: None,
+                    gap: false,
+                },
+                Event {
+                    start: MusicTime(1, Beat::whole(2)),
+                    duration: Beat::whole(9),
+                    volume: Volume(80),
+                    pitch: Pitch(4, 7),
+                    gate: None,
+                    tag: None,
+                    gap: false,
+                },
+            ],
+            rests: vec![],
+        };
+        track.split_at_barlines(TimeSignature::common());
+        let pieces: Vec<_> = track.events.iter().map(|e| (e.start, e.duration, e.pitch)).collect();
+        assert_eq!(pieces, vec![
+            (MusicTime(0, Beat::whole(3)), Beat::whole(1), Pitch(4, 3)),
+            (MusicTime(1, Beat::whole(0)), Beat::whole(2), Pitch(4, 3)),
+            (MusicTime(1, Beat::whole(2)), Beat::whole(2), Pitch(4, 7)),
+            (MusicTime(2, Beat::whole(0)), Beat::whole(4), Pitch(4, 7)),
+            (MusicTime(3, Beat::whole(0)), Beat::whole(3), Pitch(4, 7)),
+        ]);
+        assert!(track.events[..2].iter().all(|e| e.volume == Volume(60)));
+    }
+
+    #[test]
+    fn test_echo() {
+        let mut composition = two_note_template();
+        composition.tracks[0].instrument = Instrument::Piano;
+        composition.echo(Instrument::Piano, MusicTime::beats(2), 0.5, 1);
+        let events: Vec<_> = composition.tracks[0].events.iter()
+            .map(|e| (e.start, e.volume, e.pitch))
+            .collect();
+        assert_eq!(events, vec![
+            (MusicTime(1, Beat::whole(0)), Volume(100), Pitch(4, 0)),
+            (MusicTime(1, Beat::whole(1)), Volume(100), Pitch(4, 1)),
+            (MusicTime(1, Beat::whole(2)), Volume(50), Pitch(4, 0)),
+            (MusicTime(1, Beat::whole(3)), Volume(50), Pitch(4, 1)),
+        ]);
+        // the fifth echo would be at 3/100 volume, so it drops out
+        let mut composition = two_note_template();
+        composition.echo(composition.tracks[0].instrument, MusicTime::measures(1), 0.5, 5);
+        assert_eq!(composition.tracks[0].events.len(), 10);
+        assert_eq!(composition.tracks[0].events.last().unwrap().volume, Volume(6));
+        // other instruments are left alone
+        composition.echo(Instrument::Snare, MusicTime::measures(1), 0.5, 5);
+        assert_eq!(composition.tracks[0].events.len(), 10);
+    }
+
+    #[test]
+    fn test_transpose_instrument() {
+        let mut composition = two_note_template();
+        composition.tracks[0].instrument = Instrument::Bass;
+        let mut piano = composition.tracks[0].clone();
+        piano.instrument = Instrument::Piano;
+        composition.tracks.push(piano);
+        composition.transpose_instrument(Instrument::Bass, 12);
+        let pitches = |t: &Track| t.events.iter().map(|e| e.pitch).collect::<Vec<_>>();
+        assert_eq!(pitches(&composition.tracks[0]), vec![Pitch(5, 0), Pitch(5, 1)]);
+        assert_eq!(pitches(&composition.tracks[1]), vec![Pitch(4, 0), Pitch(4, 1)]);
+    }
+
+    #[test]
+    fn test_track_by_instrument() {
+        let mut composition = two_note_template();
+        for instrument in [Instrument::Piano, Instrument::Bass, Instrument::Snare] {
+            let mut track = composition.tracks[0].clone();
+            track.identifier = TrackId::Instrument(instrument);
+            track.instrument = instrument;
+            composition.tracks.push(track);
         }
+        composition.tracks[2].events.truncate(1);
+        assert_eq!(composition.track(Instrument::Bass).map(|t| t.events.len()), Some(1));
+        assert!(composition.track(Instrument::HiHatOpen).is_none());
+        composition.track_mut(Instrument::Bass).unwrap().transpose(-12);
+        let bass = composition.into_track(Instrument::Bass).unwrap();
+        assert_eq!(bass.identifier, TrackId::Instrument(Instrument::Bass));
+        assert_eq!(bass.events[0].pitch, Pitch(3, 0));
+    }
+
+    #[test]
+    fn test_fade() {
+        let quarter = |i| note(MusicTime(0, Beat::new(i, 4)), Beat::new(1, 4), Pitch(4, 0));
+        let composition = comp_template((0..8).map(quarter).collect());
+        let volumes = |c: &Composition| c.tracks[0].events.iter()
+            .map(|e| e.volume.0)
+            .collect::<Vec<_>>();
+        let mut faded_in = composition.clone();
+        faded_in.fade_in(MusicTime::beats(1));
+        // midpoints at 1/8, 3/8, 5/8 and 7/8 of the way through the first beat
+        assert_eq!(volumes(&faded_in), vec![13, 38, 63, 88, 100, 100, 100, 100]);
+        let mut faded_out = composition.clone();
+        faded_out.fade_out(MusicTime::beats(1));
+        assert_eq!(volumes(&faded_out), vec![100, 100, 100, 100, 88, 63, 38, 13]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_apply_groove() {
+        let eighth = |start: Beat, volume| Event {
+            volume: Volume(volume),
+            ..note(start.as_music_time(TimeSignature::common()), Beat::new(1, 2), Pitch(4, 0))
+        };
+        // straight eighths, against a template that pushes the offbeats late and accents the beats
+        let straight = comp_template((0..4).map(|i| eighth(Beat::new(i, 2), 50)).collect());
+        let template = Track {
+            identifier: TrackId::Custom(1),
+            instrument: Instrument::SineWave,
+            events: vec![eighth(Beat::zero(), 90), eighth(Beat::new(2, 3), 40), eighth(Beat::whole(1), 90), eighth(Beat::new(5, 3), 40)],
+            rests: vec![],
+        };
+        let starts = |c: &Composition| c.tracks[0].events.iter().map(|e| (e.start, e.volume.0)).collect::<Vec<_>>();
+
+        let mut snapped = straight.clone();
+        snapped.apply_groove(&template, 1.);
+        assert_eq!(starts(&snapped), template.events.iter().map(|e| (e.start, e.volume.0)).collect::<Vec<_>>());
+
+        let mut halfway = straight.clone();
+        halfway.apply_groove(&template, 0.5);
+        assert_eq!(starts(&halfway), vec![
+            (MusicTime::zero(), 70),
+            (MusicTime(0, Beat::new(7, 12)), 45),
+            (MusicTime::beats(1), 70),
+            (MusicTime(0, Beat::new(19, 12)), 45),
+        ]);
+
+        let mut untouched = straight.clone();
+        untouched.apply_groove(&template, 0.);
+        assert_eq!(untouched, straight);
+        // the template's own track isn't moved
+        untouched.tracks[0].identifier = TrackId::Custom(1);
+        untouched.apply_groove(&template, 1.);
+        assert_eq!(untouched.tracks[0].events, straight.tracks[0].events);
+    }
+
+    #[test]
+    fn test_reverse_keeps_tracks_aligned() {
+        let track = |instrument, events| Track {
+            identifier: TrackId::Instrument(instrument),
+            instrument,
+            events,
+            rests: vec![],
+        };
+        let mut composition = Composition {
+            tracks: vec![
+                track(Instrument::SineWave, vec![note(MusicTime::beats(0), Beat::whole(1), Pitch(4, 0)), note(MusicTime::beats(3), Beat::whole(1), Pitch(4, 2))]),
+                track(Instrument::Piano, vec![note(MusicTime::beats(0), Beat::whole(1), Pitch(3, 0))]),
+            ],
+            time_signature: TimeSignature::common(),
+            tempo_map: vec![],
+        };
+        let mut per_track = composition.clone();
+        per_track.tracks.iter_mut().for_each(|t| t.reverse(TimeSignature::common()));
+        assert_ne!(per_track.tracks[0].events[1].start, per_track.tracks[1].events[0].start);
+
+        composition.reverse();
+        assert_eq!(composition.tracks[0].events[0].start, MusicTime(0, Beat::whole(0)));
+        assert_eq!(composition.tracks[0].events[1].start, MusicTime(0, Beat::whole(3)));
+        assert_eq!(composition.tracks[1].events[0].start, MusicTime(0, Beat::whole(3)));
+
+        composition.compress_aligned(TimeCompression(Ratio::new(1, 2)));
+        assert_eq!(composition.tracks[0].events[1].start, MusicTime(0, Beat::new(3, 2)));
+        assert_eq!(composition.tracks[1].events[0].start, MusicTime(0, Beat::new(3, 2)));
+    }
+
+    #[test]
+    fn test_pitch_class_histogram() {
+        let scale = ["C4", "D4", "E4", "F4", "G4", "A4", "B4", "C5", "G3"];
+        let events = scale.iter().enumerate()
+            .map(|(i, name)| note(MusicTime::beats(i as u32), Beat::whole(1), Pitch::from_name(name).unwrap()))
+            .collect();
+        let mut composition = comp_template(events);
+        composition.tracks[0].rests.push(Event {
+            start: MusicTime(3, Beat::zero()),
+            duration: Beat::whole(1),
+            volume: Volume(0),
+            pitch: Pitch(4, 1),
+            gate: None,
+            tag: None,
+            gap: false,
+        });
+        let histogram = composition.pitch_class_histogram();
+        // A, B, C, D, E, F, G
+        let scale_degrees = [0, 2, 3, 5, 7, 8, 10];
+        for (note, count) in histogram.iter().enumerate() {
+            match note {
+                3 | 10 => assert_eq!(*count, 2, "{histogram:?}"),
+                n if scale_degrees.contains(&n) => assert_eq!(*count, 1, "{histogram:?}"),
+                _ => assert_eq!(*count, 0, "{histogram:?}"),
+            }
+        }
+        assert_eq!(composition.most_common_pitch_classes(), vec![3, 10, 0, 2, 5, 7, 8]);
+    }
+
+    #[test]
+    fn test_lint() {
+        let warnings = |events, rests| {
+            let mut composition = comp_template(events);
+            composition.tracks[0].rests = rests;
+            composition.lint(60.).into_iter().map(|w| (w.at, w.kind)).collect::<Vec<_>>()
+        };
+        // chords and notes that follow each other are fine
+        assert_eq!(warnings(two_note_template().tracks.remove(0).events, vec![]), vec![]);
+        assert_eq!(warnings(vec![note(MusicTime::beats(0), Beat::whole(2), Pitch(4, 0)), note(MusicTime::beats(0), Beat::whole(2), Pitch(4, 4))], vec![]), vec![]);
+
+        assert_eq!(
+            warnings(vec![note(MusicTime::beats(0), Beat::whole(2), Pitch(4, 0)), note(MusicTime::beats(1), Beat::whole(2), Pitch(4, 0))], vec![]),
+            vec![(1., CompositionWarningKind::Overlap(Pitch(4, 0)))],
+        );
+        assert_eq!(
+            warnings(vec![note(MusicTime::beats(0), Beat::whole(1), Pitch(12, 0)), note(MusicTime::beats(1), Beat::whole(1), Pitch(-1, 0))], vec![]),
+            vec![(0., CompositionWarningKind::OutOfRange(Pitch(12, 0))), (1., CompositionWarningKind::OutOfRange(Pitch(-1, 0)))],
+        );
+        assert_eq!(
+            warnings(vec![note(MusicTime::beats(2), Beat::zero(), Pitch(4, 0))], vec![]),
+            vec![(2., CompositionWarningKind::ZeroDuration(Pitch(4, 0)))],
+        );
+        assert_eq!(
+            warnings(vec![note(MusicTime::beats(0), Beat::whole(2), Pitch(4, 0))], vec![note(MusicTime::beats(1), Beat::whole(2), Pitch(4, 0)), note(MusicTime::beats(2), Beat::whole(1), Pitch(4, 0))]),
+            vec![(1., CompositionWarningKind::RestOverlapsNote(Pitch(4, 0)))],
+        );
+    }
+
+    #[test]
+    fn test_tidy() {
+        let mut composition = two_note_template();
+        composition.tracks[0].events = vec![
+            note(MusicTime(1, Beat::whole(2)), Beat::whole(1), Pitch(4, 0)),
+            // 5 beats into the first measure is really the second beat of the next one
+            note(MusicTime(0, Beat::whole(5)), Beat::whole(1), Pitch(4, 0)),
+            note(MusicTime(0, Beat::whole(1)), Beat::zero(), Pitch(4, 0)),
+            note(MusicTime(0, Beat::whole(0)), Beat::new(1, 2), Pitch(4, 0)),
+        ];
+        composition.tracks[0].rests = vec![
+            note(MusicTime(0, Beat::new(1, 2)), Beat::new(3, 2), Pitch(4, 0)),
+            note(MusicTime(0, Beat::whole(3)), Beat::zero(), Pitch(4, 0)),
+        ];
+        composition.tidy();
+        let track = &composition.tracks[0];
+        let starts: Vec<_> = track.events.iter().map(|e| e.start).collect();
+        assert_eq!(starts, vec![
+            MusicTime(0, Beat::whole(0)),
+            MusicTime(1, Beat::whole(1)),
+            MusicTime(1, Beat::whole(2)),
+        ]);
+        assert!(track.events.iter().chain(&track.rests).all(|e| e.duration > Beat::zero()));
+        assert_eq!(track.rests.len(), 1);
+        // tidying again changes nothing
+        let tidied = composition.clone();
+        composition.tidy();
+        assert_eq!(composition, tidied);
+    }
+
+    #[test]
+    fn test_strum() {
+        let chord = [Pitch(4, 0), Pitch(4, 4), Pitch(4, 7)];
+        let mut composition = comp_template(chord.map(|pitch| note(MusicTime::beats(1), Beat::whole(2), pitch)).to_vec());
+        composition.tracks[0].strum(Beat::new(1, 8), false, TimeSignature::common());
+        let starts: Vec<_> = composition.tracks[0].events.iter().map(|e| (e.pitch, e.start)).collect();
+        assert_eq!(starts, vec![
+            (Pitch(4, 7), MusicTime(0, Beat::whole(1))),
+            (Pitch(4, 4), MusicTime(0, Beat::new(9, 8))),
+            (Pitch(4, 0), MusicTime(0, Beat::new(5, 4))),
+        ]);
+        assert!(composition.tracks[0].events.iter()
+            .all(|e| e.get_end(TimeSignature::common()) == MusicTime(0, Beat::whole(3))));
+    }
+
+    #[test]
+    fn test_fold_into_range() {
+        let mut composition = comp_template(["C6", "E4", "G1"].iter()
+            .map(|name| note(MusicTime::zero(), Beat::whole(1), Pitch::from_name(name).unwrap()))
+            .collect());
+        composition.tracks[0].fold_into_range(Pitch::from_name("C3").unwrap(), Pitch::from_name("C5").unwrap());
+        let names: Vec<_> = composition.tracks[0].events.iter().map(|e| e.pitch.name_with_octave()).collect();
+        assert_eq!(names, vec!["C5", "E4", "G3"]);
+    }
+
+    #[test]
+    fn test_metric_modulate() {
+        let mut composition = comp_template((0..6).map(|i| note(MusicTime(0, Beat::new(i, 3)), Beat::new(1, 3), Pitch(4, 0))).collect());
+        composition.tempo_map = vec![(MusicTime::beats(1), 90.)];
+        composition.metric_modulate(Beat::new(1, 3), Beat::new(1, 2));
+        let timings: Vec<_> = composition.tracks[0].events.iter().map(|e| (e.start, e.duration)).collect();
+        assert_eq!(timings, (0..6).map(|i| (MusicTime(0, Beat::new(i, 2)), Beat::new(1, 2))).collect::<Vec<_>>());
+        assert_eq!(composition.tempo_map, vec![(MusicTime(0, Beat::new(3, 2)), 90.)]);
+    }
+
+    #[test]
+    fn test_density() {
+        let mut events: Vec<_> = (0..8).map(|i| note(MusicTime(0, Beat::new(i, 2)), Beat::new(1, 2), Pitch(4, 0))).collect();
+        events.push(note(MusicTime(1, Beat::whole(0)), Beat::new(1, 2), Pitch(4, 0)));
+        events.push(note(MusicTime(1, Beat::whole(3)), Beat::new(1, 2), Pitch(4, 0)));
+        let composition = comp_template(events);
+        let density = composition.tracks[0].density(MusicTime::beats(2), TimeSignature::common());
+        assert_eq!(density, vec![
+            (MusicTime::zero(), 4),
+            (MusicTime::beats(2), 4),
+            (MusicTime::measures(1), 1),
+            (MusicTime(1, Beat::whole(2)), 1),
+        ]);
+        assert!(composition.tracks[0].density(MusicTime::zero(), TimeSignature::common()).is_empty());
+    }
+
+    #[test]
+    fn test_events_in_window() {
+        let event = |start, beats, volume| Event { volume: Volume(volume), ..note(start, Beat::whole(beats), Pitch(4, 0)) };
+        let long = event(MusicTime::zero(), 4, 100);
+        let before = event(MusicTime::zero(), 1, 100);
+        let inside = event(MusicTime::beats(2), 1, 100);
+        let at_end = event(MusicTime::beats(3), 1, 100);
+        let mut composition = comp_template(vec![long, before, inside, at_end]);
+        let rest = event(MusicTime::beats(1), 1, 0);
+        composition.tracks[0].rests = vec![rest, event(MusicTime::beats(4), 1, 0)];
+        let (notes, rests) = composition.tracks[0].events_in_window(MusicTime::beats(1), MusicTime::beats(3), TimeSignature::common());
+        // the long note started before the window but is still sounding in it,
+        // and the ones ending right at the start or starting right at the end don't count
+        assert_eq!(notes, vec![long, inside]);
+        assert_eq!(rests, vec![rest]);
+    }
+}
@@ -135,18 +135,105 @@ const MIDI_INSTRUMENTS: [(&str, u8); 128] = [
     ("gunshot", 128),
 ];
 
-pub fn get_instrument_by_fuzzy_name(name: &str) -> u8 {
+/// Below this, a name isn't considered to match any instrument.
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// The closest General MIDI instrument to `name`: its name, 1-indexed program, and similarity.
+fn closest_instrument(name: &str) -> (&'static str, u8, f64) {
     let mut similarities = MIDI_INSTRUMENTS.iter()
-        .map(|(i_name, i)| (i_name, i, normalized_levenshtein(i_name, name)))
+        .map(|(i_name, i)| (*i_name, *i, normalized_levenshtein(i_name, name)))
         .collect::<Vec<_>>();
     similarities
-        .sort_by_key(|(i_name, i, sim)| 1000 - (*sim * 1000.) as u16);
-    println!("Mapped {name} to {}", similarities[0].0);
-    similarities[0].1 - 1
+        .sort_by_key(|(_i_name, _i, sim)| 1000 - (*sim * 1000.) as u16);
+    similarities[0]
+}
+
+/// `name` the way the General MIDI names are written, so that names from anywhere compare the
+/// same: trimmed, in lowercase, with words split at capitals and numbers, ex. `HiHatOpen` is `hi hat open`.
+fn normalize_instrument_name(name: &str) -> String {
+    let mut normalized = String::new();
+    let mut last: Option<char> = None;
+    for c in name.trim().chars() {
+        let new_word = match last {
+            Some(last) => (last.is_lowercase() && c.is_uppercase())
+                || (last.is_alphabetic() && c.is_ascii_digit())
+                || (last.is_ascii_digit() && c.is_alphabetic()),
+            None => false,
+        };
+        if new_word || (c.is_whitespace() && last.is_some_and(|l| !l.is_whitespace())) {
+            normalized.push(' ');
+        }
+        if !c.is_whitespace() {
+            normalized.extend(c.to_lowercase());
+        }
+        last = Some(c);
+    }
+    normalized
+}
+
+/// The closest General MIDI instrument to `name`, as a 0-indexed program, however far off it is.
+pub fn get_instrument_by_fuzzy_name(name: &str) -> u8 {
+    let (i_name, i, _sim) = closest_instrument(&normalize_instrument_name(name));
+    debug!("Mapped {name} to {i_name}");
+    i - 1
+}
+
+/// Resolve a free-text instrument name to a 0-indexed General MIDI program, with the same
+/// matching as `get_instrument_by_fuzzy_name`, but only if it's at least `MIN_SIMILARITY` alike.
+/// Returns `None` if the name is empty or isn't close to any instrument.
+pub fn fuzzy_instrument_program(name: &str) -> Option<u8> {
+    let name = normalize_instrument_name(name);
+    if name.is_empty() {
+        return None;
+    }
+    let (i_name, i, sim) = closest_instrument(&name);
+    if sim < MIN_SIMILARITY {
+        None
+    } else {
+        debug!("Mapped {name} to {i_name}");
+        Some(i - 1)
+    }
 }
 
 pub fn get_fuzzy_mapping() -> HashMap<Instrument, u8> {
     Instrument::str_values()
         .map(|(i, i_str)| (i, get_instrument_by_fuzzy_name(&i_str)))
         .collect()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use crate::constants::fuzzy_instrument_program;
+
+    #[test]
+    fn test_fuzzy_near_misses() {
+        // acoustic grand piano
+        assert_eq!(fuzzy_instrument_program("grand piano"), Some(0));
+        assert_eq!(fuzzy_instrument_program(" Grand Piano "), Some(0));
+        // violin
+        assert_eq!(fuzzy_instrument_program("violn"), Some(40));
+        // trumpet, and marimba
+        assert_eq!(fuzzy_instrument_program("trumpt"), Some(56));
+        assert_eq!(fuzzy_instrument_program("marimbas"), Some(12));
+    }
+
+    #[test]
+    fn test_normalized_names() {
+        use crate::constants::{get_instrument_by_fuzzy_name, normalize_instrument_name};
+        assert_eq!(normalize_instrument_name("HiHatOpen"), "hi hat open");
+        assert_eq!(normalize_instrument_name(" Shaker2 "), "shaker 2");
+        assert_eq!(normalize_instrument_name("acoustic   grand piano"), "acoustic grand piano");
+        // the player's names and free text resolve the same way
+        assert_eq!(get_instrument_by_fuzzy_name("GrandPiano"), 0);
+        assert_eq!(get_instrument_by_fuzzy_name("GrandPiano"), get_instrument_by_fuzzy_name("grand piano"));
+        assert_eq!(fuzzy_instrument_program("TRUMPET"), Some(56));
+    }
+
+    #[test]
+    fn test_fuzzy_no_match() {
+        assert_eq!(fuzzy_instrument_program(""), None);
+        assert_eq!(fuzzy_instrument_program("qqqqqq"), None);
+        // there are no shakers in General MIDI, and shanai only shares a few letters
+        assert_eq!(fuzzy_instrument_program("shakers"), None);
+    }
+}
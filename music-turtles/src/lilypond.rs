@@ -0,0 +1,165 @@
+// Export to Lilypond, for engraving.
+
+use num::rational::Ratio;
+use crate::composition::{Composition, Event, Pitch, Track};
+use crate::time::{Beat, BeatUnit, MusicTime, TimeSignature, BPM};
+
+/// Lilypond's (Dutch) note names, starting from C. Sharps are used for every black key.
+const LILYPOND_NOTES: [&str; 12] = ["c", "cis", "d", "dis", "e", "f", "fis", "g", "gis", "a", "ais", "b"];
+
+/// The shortest note that is written as a plain duration. Anything finer is scaled.
+const SHORTEST_NOTE: BeatUnit = 128;
+
+impl Pitch {
+    /// Absolute Lilypond pitch, ex. `c'` for middle C and `ais,,` for B flat 1.
+    pub fn lilypond_name(&self) -> String {
+        let midi = self.midi_number();
+        let name = LILYPOND_NOTES[midi.rem_euclid(12) as usize];
+        // no marks is the octave below middle C
+        let octave = midi.div_euclid(12) - 4;
+        let marks = if octave >= 0 {
+            "'".repeat(octave as usize)
+        } else {
+            ",".repeat(-octave as usize)
+        };
+        format!("{name}{marks}")
+    }
+}
+
+/// Lilypond durations that add up to `beats`, to be tied together.
+/// Lengths that can't be made of whole, half, quarter... notes are written as a scaled whole note.
+fn lilypond_durations(beats: Beat, time_signature: TimeSignature) -> Vec<String> {
+    let mut remaining = beats.as_ratio() / time_signature.1;
+    if !remaining.denom().is_power_of_two() || *remaining.denom() > SHORTEST_NOTE {
+        return vec![format!("1*{}/{}", remaining.numer(), remaining.denom())];
+    }
+    let mut durations = vec![];
+    let mut note = 1;
+    while remaining > Ratio::from_integer(0) {
+        let length = Ratio::new(1, note);
+        if remaining >= length {
+            durations.push(note.to_string());
+            remaining -= length;
+        } else {
+            note *= 2;
+        }
+    }
+    durations
+}
+
+/// `event` written as one or more tied notes (or rests, if `pitches` is empty), split at barlines.
+fn lilypond_notes(event: Event, pitches: &[Pitch], time_signature: TimeSignature) -> Vec<String> {
+    let name = match pitches {
+        [] => "r".to_string(),
+        [pitch] => pitch.lilypond_name(),
+        pitches => format!("<{}>", pitches.iter().map(|p| p.lilypond_name()).collect::<Vec<_>>().join(" ")),
+    };
+    let tie = if pitches.is_empty() { "" } else { "~" };
+    let durations: Vec<_> = event.split_at_barlines(time_signature).into_iter()
+        .flat_map(|piece| lilypond_durations(piece.duration, time_signature))
+        .collect();
+    let last = durations.len().saturating_sub(1);
+    durations.into_iter().enumerate()
+        .map(|(i, duration)| format!("{name}{duration}{}", if i < last { tie } else { "" }))
+        .collect()
+}
+
+impl Track {
+    /// The notes of this track in Lilypond, with rests filling the gaps from the start.
+    /// Notes that start together become a chord as long as the longest of them,
+    /// and anything still sounding when the next note starts is cut short there.
+    fn to_lilypond_notes(&self, time_signature: TimeSignature) -> Vec<String> {
+        let mut events = self.events.clone();
+        events.sort();
+        let mut groups: Vec<Vec<Event>> = vec![];
+        for event in events {
+            match groups.last_mut() {
+                Some(group) if group[0].start == event.start => group.push(event),
+                _ => groups.push(vec![event]),
+            }
+        }
+        let length = |from: MusicTime, to: MusicTime| (to.with(time_signature) - from).with(time_signature).total_beats();
+        let mut notes = vec![];
+        let mut cursor = MusicTime::zero();
+        for (i, group) in groups.iter().enumerate() {
+            let start = group[0].start;
+            if start > cursor {
+                let rest = Event { start: cursor, duration: length(cursor, start), ..group[0] };
+                notes.extend(lilypond_notes(rest, &[], time_signature));
+            }
+            let mut end = group.iter().map(|e| e.get_end(time_signature)).max().unwrap_or(start);
+            if let Some(next) = groups.get(i + 1) {
+                end = end.min(next[0].start);
+            }
+            let mut pitches: Vec<_> = group.iter().map(|e| e.pitch).collect();
+            pitches.sort();
+            pitches.dedup();
+            let chord = Event { start, duration: length(start, end), ..group[0] };
+            notes.extend(lilypond_notes(chord, &pitches, time_signature));
+            cursor = end;
+        }
+        notes
+    }
+}
+
+impl Composition {
+    /// A minimal Lilypond score with a staff for each track.
+    /// Pitches are absolute rather than `\relative`, so each note can be read on its own.
+    pub fn to_lilypond(&self, bpm: BPM) -> String {
+        let TimeSignature(beats, unit) = self.time_signature;
+        let mut ly = String::new();
+        ly.push_str("\\version \"2.24.0\"\n\n\\score {\n  <<\n");
+        for (i, track) in self.tracks.iter().enumerate() {
+            ly.push_str(&format!("    \\new Staff \\with {{ instrumentName = \"{}\" }} {{\n", track.identifier));
+            if i == 0 {
+                ly.push_str(&format!("      \\tempo 4 = {}\n", bpm.round()));
+            }
+            ly.push_str(&format!("      \\time {beats}/{unit}\n"));
+            ly.push_str(&format!("      {}\n", track.to_lilypond_notes(self.time_signature).join(" ")));
+            ly.push_str("    }\n");
+        }
+        ly.push_str("  >>\n  \\layout { }\n}\n");
+        ly
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::MusicString;
+    use crate::composition::Pitch;
+    use crate::time::{Beat, TimeSignature};
+    use crate::lilypond::lilypond_durations;
+
+    #[test]
+    fn test_lilypond_name() {
+        assert_eq!(Pitch::from_name("C4").unwrap().lilypond_name(), "c'");
+        assert_eq!(Pitch::from_name("B3").unwrap().lilypond_name(), "b");
+        assert_eq!(Pitch::from_name("F#5").unwrap().lilypond_name(), "fis''");
+        assert_eq!(Pitch::from_name("Bb1").unwrap().lilypond_name(), "ais,,");
+    }
+
+    #[test]
+    fn test_lilypond_durations() {
+        let ts = TimeSignature::common();
+        assert_eq!(lilypond_durations(Beat::whole(1), ts), vec!["4"]);
+        assert_eq!(lilypond_durations(Beat::whole(3), ts), vec!["2", "4"]);
+        assert_eq!(lilypond_durations(Beat::new(1, 2), TimeSignature(6, 8)), vec!["16"]);
+        assert_eq!(lilypond_durations(Beat::new(1, 3), ts), vec!["1*1/12"]);
+    }
+
+    #[test]
+    fn test_to_lilypond() {
+        let string = MusicString::from_str("::i=piano :c :d<2> :e<3> :_ {:c | :e}").unwrap();
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        let ly = composition.to_lilypond(90.);
+        assert!(ly.starts_with("\\version"));
+        assert!(ly.contains("\\time 4/4"));
+        assert!(ly.contains("\\tempo 4 = 90"));
+        assert!(ly.contains("instrumentName = \"Piano\""));
+        // the e crosses the barline, so it's tied over it
+        assert!(ly.contains("c'4 d'2 e'4~ e'2 r4 <c' e'>4"), "{ly}");
+        assert_eq!(ly.matches('{').count(), ly.matches('}').count());
+        assert_eq!(ly.matches("<<").count(), ly.matches(">>").count());
+    }
+}
@@ -2,8 +2,9 @@ use std::ops::DerefMut;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, SystemTime};
-use crate::player::{AudioPlayer, Player};
+use crate::player::{AudioPlayer, Player, SystemClock};
 use crate::scheduler::Scheduler;
+use crate::time::LiveTempo;
 
 pub fn run<S: DerefMut<Target=Scheduler> + Send>(scheduler: S, scheduler_tick_ms: u64, player: Player) {
     let (event_send, event_recv) = mpsc::channel();
@@ -37,6 +38,9 @@ where
     P: AudioPlayer
 {
     let (event_send, event_recv) = mpsc::channel();
+    // the scheduler's bpm can be changed while playing, and sounds follow it when they're played
+    let tempo = Mutex::new(LiveTempo::new(scheduler.lock().unwrap().bpm));
+    let tempo = &tempo;
     thread::scope(move |s| {
         s.spawn(move || {
             let start_time = SystemTime::now();
@@ -48,7 +52,16 @@ where
                     break;
                 }
                 let elapsed_s = start_time.elapsed().unwrap().as_secs_f32();
-                let events = guard.get_next_events_and_update(elapsed_s);
+                let position = {
+                    let mut tempo = tempo.lock().unwrap();
+                    // follow the tempo map, which can be up to a tick late
+                    let bpm = guard.bpm_at(tempo.music_time_at(elapsed_s, guard.time_signature));
+                    if tempo.bpm() != bpm {
+                        tempo.set_bpm(bpm, elapsed_s);
+                    }
+                    tempo.music_time_at(elapsed_s, guard.time_signature)
+                };
+                let events = guard.get_next_events_at(position);
                 // info!("{events:#?}");
                 drop(guard);
                 for event in events {
@@ -57,6 +70,6 @@ where
                 thread::sleep(Duration::from_millis(scheduler_tick_ms));
             }
         });
-        player.play_from_ordered_channel(event_recv);
+        player.play_from_ordered_channel_with_tempo(event_recv, tempo, &SystemClock);
     });
 }
\ No newline at end of file
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{stdin, stdout, Write};
+use std::io::{stdin, stdout, BufRead, Write};
 use crate::time::{Beat, MusicTime, Seconds, TimeSignature, BPM};
 use rodio::Source;
 use std::ops::DerefMut;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -14,18 +15,22 @@ use midly::live::LiveEvent;
 use midly::MidiMessage;
 use rocket::http::Status;
 use rocket::State;
-use crate::cfg::{Grammar, MusicString};
+use crate::cfg::{GenerateError, Grammar, MusicString};
+use crate::cfg::library::{load_grammar, GrammarLibrary, LibraryError};
+use crate::cfg::expansion::ExpandedString;
 use crate::cfg::scan::{consume, GrammarScanner, ScanError};
 use crate::cfg::scan::Scanner;
 use rocket::serde::json::{Json, Value, json};
 use rocket::serde::{Serialize, Deserialize};
 use rocket_cors::CorsOptions;
 use crate::cfg::interactive::TracedString;
-use crate::composition::Instrument;
+use crate::composition::{Composition, Instrument};
 use crate::composition::Instrument::*;
 use crate::local_playback::{run, run_midi};
-use crate::player::{MidiPlayer, Player};
+use crate::player::{MidiChannel, MidiPlayer, MidiPort, Player};
 use crate::scheduler::Scheduler;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use simplelog::*;
 
 #[macro_use]
@@ -45,6 +50,12 @@ mod cfg;
 mod test;
 pub mod local_playback;
 mod constants;
+mod server;
+mod lilypond;
+mod midi_file;
+mod note_csv;
+mod midi_input;
+mod render;
 
 pub struct ServerConfig {
     pub data_path: String,
@@ -129,22 +140,291 @@ where
     })
 }
 
+/// Environment variable with the seed for rewriting the grammar. A random one is picked without it.
+const SEED_VAR: &str = "MUSIC_TURTLES_SEED";
+
+fn seed_from_env() -> u64 {
+    match std::env::var(SEED_VAR).ok().and_then(|seed| seed.parse().ok()) {
+        Some(seed) => seed,
+        None => {
+            let seed = rand::random();
+            info!("Using seed {seed}. Set {SEED_VAR} to hear the same thing again.");
+            seed
+        }
+    }
+}
+
+/// Loads the grammar at `path` and rewrites `axiom` with it `iterations` times, starting from `seed`.
+/// After that, each reload only rewrites again the non-terminals whose productions changed,
+/// so that an unchanged grammar always gives the same string, edits can be compared,
+/// and a small edit to a big grammar doesn't mean regenerating everything.
+fn seeded_reload(path: &str, axiom: &str, iterations: usize, seed: u64) -> impl FnMut() -> Result<(Grammar, MusicString), LibraryError> {
+    let path = path.to_string();
+    let axiom = MusicString::from_str(axiom).unwrap();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut last: Option<(Grammar, ExpandedString)> = None;
+    move || {
+        let grammar = load_grammar(&path)?;
+        let expanded = match last.take() {
+            Some((old, mut expanded)) => {
+                let changed = old.changed_non_terminals(&grammar);
+                let rewritten = expanded.update(&grammar, &changed, &mut rng);
+                info!("Rewrote {rewritten} non-terminals again for changes to {changed:?}");
+                expanded
+            }
+            None => ExpandedString::new(&axiom, &grammar, iterations, &mut rng),
+        };
+        // random walks are picked the same way every time, so unchanged parts stay the same
+        let string = expanded.to_music_string(&mut StdRng::seed_from_u64(seed));
+        last = Some((grammar.clone(), expanded));
+        Ok((grammar, string))
+    }
+}
+
+/// Like `file_watcher`, but reloads the whole `GrammarLibrary` in `dir` when any file in it changes.
+/// If a grammar fails to load, the previous library is kept.
+fn library_watcher<F>(dir: &str, mut f: F, period: Seconds) -> JoinHandle<()>
+where
+    F: FnMut(GrammarLibrary) + Send + 'static,
+{
+    let dir = dir.to_string();
+    thread::spawn(move || {
+        let read_dir = |dir: &str| -> std::io::Result<Vec<(PathBuf, String)>> {
+            let mut files = vec![];
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_file() {
+                    let contents = std::fs::read_to_string(&path).unwrap_or_default();
+                    files.push((path, contents));
+                }
+            }
+            files.sort();
+            Ok(files)
+        };
+        let mut last_contents = read_dir(&dir).unwrap_or_default();
+        loop {
+            thread::sleep(Duration::from_secs_f32(period));
+            match read_dir(&dir) {
+                Ok(contents) if contents != last_contents => {
+                    match GrammarLibrary::load_dir(&dir) {
+                        Ok(library) => {
+                            f(library);
+                            info!("reloaded {dir}");
+                        }
+                        Err(e) => warn!("Failed to reload grammars from {dir}: {e:?}"),
+                    }
+                    last_contents = contents;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to read directory {dir}: {e}"),
+            }
+        }
+    })
+}
+
+fn default_channel_mapping() -> HashMap<Instrument, (MidiPort, MidiChannel)> {
+    Instrument::values().into_iter().map(|i| (i, match i {
+        BassDrum => (2, 1),
+        HiHatOpen => (3, 1),
+        HiHatClosed => (4, 1),
+        Snare => (5, 1),
+        Snare2 => (6, 1),
+        Piano => (1, 1),
+        _ => (1, 1),
+    })).collect()
+}
+
+/// `live <dir>`: loop a grammar from a directory of grammars, reloading them when they change.
+/// Type a grammar's name to switch to it.
+fn live(dir: &str) {
+    let axiom = "S";
+    let iterations = 20;
+    let library = GrammarLibrary::load_dir(dir).unwrap();
+    let names = library.names();
+    println!("Grammars: {}", names.join(", "));
+    let current = names.first().expect("No grammars found").to_string();
+    let scheduler = Arc::new(Mutex::new(Scheduler {
+        bpm: 120.0,
+        time_signature: TimeSignature::common(),
+        tracks: vec![],
+        lookahead: MusicTime::measures(1),
+        looped: true,
+        loop_count: None,
+        loop_time: MusicTime::zero(),
+        tempo_map: vec![],
+    }));
+    let state = Arc::new(Mutex::new((library, current)));
+    let regenerate = {
+        let scheduler = Arc::clone(&scheduler);
+        move |library: &GrammarLibrary, name: &str| {
+            match library.generate(name, axiom, iterations) {
+                Ok(music) => {
+                    let mut scheduler = scheduler.lock().unwrap();
+                    scheduler.bpm = library.get(name).map_or(120.0, |g| g.bpm_or(120.0));
+                    scheduler.loop_time = music.span_from_zero();
+                    scheduler.set_composition(music);
+                    info!("Playing {name}");
+                }
+                Err(e) => warn!("Failed to generate {name}: {e:?}"),
+            }
+        }
+    };
+    {
+        let (library, current) = &*state.lock().unwrap();
+        regenerate(library, current);
+    }
+    library_watcher(dir, {
+        let state = Arc::clone(&state);
+        let regenerate = regenerate.clone();
+        move |library| {
+            let mut state = state.lock().unwrap();
+            state.0 = library;
+            regenerate(&state.0, &state.1);
+        }
+    }, 1.0);
+    thread::spawn({
+        let state = Arc::clone(&state);
+        move || {
+            for line in stdin().lines() {
+                let Ok(line) = line else { break };
+                let name = line.trim();
+                let mut state = state.lock().unwrap();
+                if state.0.get(name).is_some() {
+                    state.1 = name.to_string();
+                    regenerate(&state.0, &state.1);
+                } else {
+                    println!("Unknown grammar {name}. Grammars: {}", state.0.names().join(", "));
+                }
+            }
+        }
+    });
+    let player = MidiPlayer::new("music-turtles".to_string(), default_channel_mapping()).unwrap();
+    thread::sleep(Duration::from_millis(1000)); // give player time to get ready
+    run_midi(scheduler, 100, player);
+}
+
+/// Reads grammars from `input`, each ended by a line with just `.` or by the end of the input,
+/// rewrites `axiom` with each one `iterations` times and hands the composition to `f`.
+/// Grammars that fail to scan or compose are reported and skipped. Returns how many were composed.
+fn repl_grammars<R: BufRead>(input: R, axiom: &str, iterations: usize, mut f: impl FnMut(&Grammar, Composition)) -> usize {
+    let mut composed = 0;
+    let mut lines = input.lines();
+    loop {
+        print!("grammar (end with '.')> ");
+        let _ = stdout().flush();
+        let mut text = String::new();
+        let mut ended = true;
+        for line in lines.by_ref() {
+            let Ok(line) = line else { break };
+            if line.trim() == "." {
+                ended = false;
+                break;
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+        if !text.trim().is_empty() {
+            let music = Grammar::try_from_str(&text)
+                .map_err(GenerateError::from)
+                .and_then(|grammar| {
+                    let time_signature = grammar.time_signature_or(TimeSignature::common());
+                    grammar.generate(axiom, iterations, true, time_signature)
+                        .map(|music| (grammar, music))
+                });
+            match music {
+                Ok((grammar, music)) => {
+                    f(&grammar, music);
+                    composed += 1;
+                }
+                Err(e) => println!("Failed to generate: {e:?}"),
+            }
+        }
+        if ended {
+            return composed;
+        }
+    }
+}
+
+/// `repl [axiom] [iterations]`: loop grammars pasted into stdin, switching to each one as it's entered.
+fn repl(axiom: &str, iterations: usize) {
+    let scheduler = Arc::new(Mutex::new(Scheduler {
+        bpm: 120.0,
+        time_signature: TimeSignature::common(),
+        tracks: vec![],
+        lookahead: MusicTime::measures(1),
+        looped: true,
+        loop_count: None,
+        loop_time: MusicTime::zero(),
+        tempo_map: vec![],
+    }));
+    thread::spawn({
+        let scheduler = Arc::clone(&scheduler);
+        let axiom = axiom.to_string();
+        move || {
+            repl_grammars(stdin().lock(), &axiom, iterations, |grammar, music| {
+                let mut scheduler = scheduler.lock().unwrap();
+                scheduler.bpm = grammar.bpm_or(120.0);
+                scheduler.loop_time = music.span_from_zero();
+                scheduler.set_composition(music);
+            });
+        }
+    });
+    let player = MidiPlayer::new("music-turtles".to_string(), default_channel_mapping()).unwrap();
+    thread::sleep(Duration::from_millis(1000)); // give player time to get ready
+    run_midi(scheduler, 100, player);
+}
+
+/// `inventory <file>`: print the instruments and pitch range a grammar can play, without generating anything.
+fn print_inventory(path: &str) {
+    let grammar = match load_grammar(path) {
+        Ok(grammar) => grammar,
+        Err(e) => {
+            println!("Failed to load {path}: {e:?}");
+            return;
+        }
+    };
+    let inventory = grammar.inventory();
+    let instruments: Vec<_> = inventory.instruments.iter().map(|i| format!("{i:?}")).collect();
+    println!("Instruments: {}", instruments.join(", "));
+    match (inventory.lowest(), inventory.highest()) {
+        (Some(low), Some(high)) => println!("Pitches: {} from {} to {}", inventory.pitches.len(), low.name_with_octave(), high.name_with_octave()),
+        _ => println!("Pitches: none"),
+    }
+}
+
 pub fn main() {
     CombinedLogger::init(vec![
         TermLogger::new(LevelFilter::Warn, Config::default(), TerminalMode::Mixed, ColorChoice::Auto),
         TermLogger::new(LevelFilter::Info, Config::default(), TerminalMode::Mixed, ColorChoice::Auto),
     ]).unwrap();
+    if std::env::args().nth(1).as_deref() == Some("serve") {
+        if let Err(e) = rocket::execute(server::rocket().launch()) {
+            error!("Server failed: {e}");
+        }
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("live") {
+        let dir = std::env::args().nth(2).unwrap_or("data".to_string());
+        live(&dir);
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("inventory") {
+        let path = std::env::args().nth(2).unwrap_or("data/funky_bach.mtx".to_string());
+        print_inventory(&path);
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("repl") {
+        let axiom = std::env::args().nth(2).unwrap_or("S".to_string());
+        let iterations = std::env::args().nth(3).and_then(|n| n.parse().ok()).unwrap_or(20);
+        repl(&axiom, iterations);
+        return;
+    }
     let axiom = "S";
-    let time_signature = TimeSignature::common();
-    let bpm: BPM = 120.0;
     let mt_path = "data/funky_bach.mtx";
-    let mt_contents = std::fs::read_to_string(mt_path).unwrap();
-    let grammar = Grammar::from_str(&mt_contents).unwrap();
-    let mut string = MusicString::from_str(axiom).unwrap();
-    for i in 0..20 {
-        println!("After {} iters: {}", i, string.to_string());
-        string = string.parallel_rewrite(&grammar, true, true);
-    }
+    let mut reload = seeded_reload(mt_path, axiom, 20, seed_from_env());
+    let (grammar, string) = reload().unwrap();
+    let time_signature = grammar.time_signature_or(TimeSignature::common());
+    let bpm: BPM = grammar.bpm_or(120.0);
     info!("Final string: {}", string.to_string());
 
     let music = string.compose(time_signature, None).unwrap();
@@ -156,19 +436,37 @@ pub fn main() {
         tracks: vec![],
         lookahead: MusicTime::measures(1),
         looped: false,
-        loop_time: music.get_duration(),
+        loop_count: None,
+        loop_time: music.span_from_zero(),
+        tempo_map: vec![],
     };
-    let channel_mapping = Instrument::values().into_iter().map(|i| (i, match i {
-        BassDrum => (2, 1),
-        HiHatOpen => (3, 1),
-        HiHatClosed => (4, 1),
-        Snare => (5, 1),
-        Snare2 => (6, 1),
-        Piano => (1, 1),
-        _ => (1, 1),
-    })).collect();
+    let channel_mapping = default_channel_mapping();
     scheduler.set_composition(music);
     let sched = Arc::new(Mutex::new(scheduler));
+    file_watcher(mt_path, {
+        let sched = Arc::clone(&sched);
+        let mut last = string.to_string();
+        move |_contents| {
+            match reload() {
+                // same seed, so an unchanged grammar doesn't restart the music
+                Ok((_grammar, string)) if string.to_string() == last => {}
+                Ok((grammar, string)) => {
+                    let time_signature = grammar.time_signature_or(TimeSignature::common());
+                    match string.compose(time_signature, None) {
+                        Ok(music) => {
+                            let mut scheduler = sched.lock().unwrap();
+                            scheduler.bpm = grammar.bpm_or(120.0);
+                            scheduler.loop_time = music.span_from_zero();
+                            scheduler.set_composition(music);
+                            last = string.to_string();
+                        }
+                        Err(e) => warn!("Failed to compose {mt_path}: {e:?}"),
+                    }
+                }
+                Err(e) => warn!("Failed to reload {mt_path}: {e:?}"),
+            }
+        }
+    }, 1.0);
     let player = MidiPlayer::new("music-turtles".to_string(), channel_mapping).unwrap();
     thread::sleep(Duration::from_millis(1000)); // give player time to get ready
     run_midi(sched, 100, player);
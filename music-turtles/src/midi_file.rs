@@ -0,0 +1,217 @@
+// Export to Standard MIDI Files, for DAWs and notation programs.
+
+use midly::num::{u15, u24, u28};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use num::rational::Ratio;
+use crate::composition::{Composition, Instrument, Track};
+use crate::constants::get_fuzzy_mapping;
+use crate::player::{velocity, MidiChannel};
+use crate::time::{BeatUnit, MusicTime, TimeSignature, BPM};
+
+/// Resolution of exported files, in ticks per quarter note.
+pub const TICKS_PER_QUARTER: u16 = 480;
+
+/// How the tracks of a composition are laid out in a MIDI file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum MidiFileFormat {
+    /// Type 0: every track merged into one MIDI track, told apart by channel.
+    SingleTrack,
+    /// Type 1: a MIDI track for each `Track`.
+    #[default]
+    Parallel,
+}
+
+/// General MIDI plays everything on this channel (10, counting from 1) as drums.
+pub const PERCUSSION_CHANNEL: MidiChannel = 9;
+
+/// The channel `instrument` is exported on. Each melodic instrument has its own,
+/// so that merged tracks can still be told apart, and percussion goes on `PERCUSSION_CHANNEL`.
+pub fn export_channel(instrument: Instrument) -> MidiChannel {
+    if instrument.is_percussion() {
+        return PERCUSSION_CHANNEL;
+    }
+    let channel = Instrument::values()
+        .filter(|i| !i.is_percussion())
+        .position(|i| i == instrument)
+        .unwrap_or(0) as MidiChannel;
+    if channel >= PERCUSSION_CHANNEL { channel + 1 } else { channel }
+}
+
+/// MIDI ticks from the start of the piece to `time`, where a beat is a 1/`unit` note.
+fn ticks(time: MusicTime, time_signature: TimeSignature) -> u32 {
+    let beats = time.with(time_signature).total_beats().as_ratio();
+    let per_beat = Ratio::new(TICKS_PER_QUARTER as BeatUnit * 4, time_signature.1.max(1));
+    (beats * per_beat).round().to_integer()
+}
+
+/// Microseconds per quarter note at `bpm`, for the tempo meta event.
+fn tempo(bpm: BPM) -> u24 {
+    u24::from((60_000_000. / bpm.max(1.)).round() as u32)
+}
+
+/// The numerator and power-of-two denominator of the time signature meta event. MIDI can only
+/// write a beat as a power of two, so other units are written as the nearest one, ex. 3/6 as 3/8,
+/// and the numerator is clamped to what fits.
+fn midi_time_signature(TimeSignature(beats, unit): TimeSignature) -> (u8, u8) {
+    let power = (unit.max(1) as f64).log2().round() as u8;
+    (beats.clamp(1, u8::MAX as BeatUnit) as u8, power)
+}
+
+/// Tempo and time signature changes, which go in the first track.
+fn conductor_events(composition: &Composition, bpm: BPM) -> Vec<(u32, TrackEventKind<'static>)> {
+    let (beats, power) = midi_time_signature(composition.time_signature);
+    let mut events = vec![
+        (0, TrackEventKind::Meta(MetaMessage::TimeSignature(beats, power, 24, 8))),
+        (0, TrackEventKind::Meta(MetaMessage::Tempo(tempo(bpm)))),
+    ];
+    for (time, bpm) in &composition.tempo_map {
+        let tempo = tempo(*bpm);
+        events.push((ticks(*time, composition.time_signature), TrackEventKind::Meta(MetaMessage::Tempo(tempo))));
+    }
+    events
+}
+
+/// The program change and notes of `track` on its instrument's channel, at absolute ticks.
+/// Percussion has no program change, since the drum channel doesn't use one.
+/// Note-offs come before note-ons, so that a note repeated right away isn't cut off.
+fn track_events(track: &Track, program: Option<u8>, time_signature: TimeSignature) -> Vec<(u32, TrackEventKind<'static>)> {
+    let channel = export_channel(track.instrument).into();
+    let midi = |message| TrackEventKind::Midi { channel, message };
+    let mut events = vec![];
+    if let Some(program) = program
+        && !track.instrument.is_percussion()
+    {
+        events.push((0, midi(MidiMessage::ProgramChange { program: program.into() })));
+    }
+    // gap markers aren't notes
+    let notes: Vec<_> = track.events.iter().filter(|e| !e.is_gap()).collect();
+    for event in &notes {
+        let key = event.pitch.to_midi_note().into();
+        events.push((ticks(event.sounding_end(time_signature), time_signature), midi(MidiMessage::NoteOff { key, vel: 0.into() })));
+    }
+    for event in &notes {
+        let key = event.pitch.to_midi_note().into();
+        let vel = velocity(track.instrument, event.volume).into();
+        events.push((ticks(event.start, time_signature), midi(MidiMessage::NoteOn { key, vel })));
+    }
+    events
+}
+
+/// Order events by time, keeping the order of those at the same time, and make their times relative.
+fn to_track(mut events: Vec<(u32, TrackEventKind)>) -> Vec<TrackEvent> {
+    events.sort_by_key(|(tick, _kind)| *tick);
+    let mut last = 0;
+    let mut track: Vec<_> = events.into_iter()
+        .map(|(tick, kind)| {
+            let delta = tick - last;
+            last = tick;
+            TrackEvent { delta: u28::from(delta), kind }
+        })
+        .collect();
+    track.push(TrackEvent { delta: u28::from(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+    track
+}
+
+impl Composition {
+    /// A Standard MIDI File of this composition, starting at `bpm` and following the tempo map from there.
+    /// Rests are left out, since silence is just the lack of notes.
+    pub fn to_midi_file(&self, bpm: BPM, format: MidiFileFormat) -> std::io::Result<Vec<u8>> {
+        let programs = get_fuzzy_mapping();
+        let names: Vec<String> = self.tracks.iter().map(|track| track.identifier.to_string()).collect();
+        let mut tracks: Vec<Vec<(u32, TrackEventKind)>> = match format {
+            MidiFileFormat::SingleTrack => vec![self.tracks.iter()
+                .flat_map(|track| track_events(track, programs.get(&track.instrument).copied(), self.time_signature))
+                .collect()],
+            MidiFileFormat::Parallel => self.tracks.iter().zip(&names)
+                .map(|(track, name)| {
+                    let mut events = vec![(0, TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())))];
+                    events.extend(track_events(track, programs.get(&track.instrument).copied(), self.time_signature));
+                    events
+                })
+                .collect(),
+        };
+        if tracks.is_empty() {
+            tracks.push(vec![]);
+        }
+        let mut first = conductor_events(self, bpm);
+        first.append(&mut tracks[0]);
+        tracks[0] = first;
+
+        let header = Header::new(
+            match format {
+                MidiFileFormat::SingleTrack => Format::SingleTrack,
+                MidiFileFormat::Parallel => Format::Parallel,
+            },
+            Timing::Metrical(u15::from(TICKS_PER_QUARTER)),
+        );
+        let mut smf = Smf::new(header);
+        smf.tracks = tracks.into_iter().map(to_track).collect();
+        let mut bytes = vec![];
+        smf.write_std(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::str::FromStr;
+    use midly::{Format, MidiMessage, Smf, TrackEventKind};
+    use crate::cfg::MusicString;
+    use crate::composition::Instrument;
+    use crate::midi_file::{export_channel, midi_time_signature, ticks, MidiFileFormat, PERCUSSION_CHANNEL, TICKS_PER_QUARTER};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_ticks() {
+        assert_eq!(ticks(MusicTime(1, Beat::new(1, 2)), TimeSignature::common()), 4 * 480 + 240);
+        // in 6/8 a beat is an eighth note
+        assert_eq!(ticks(MusicTime::beats(1), TimeSignature(6, 8)), TICKS_PER_QUARTER as u32 / 2);
+    }
+
+    #[test]
+    fn test_midi_time_signature() {
+        assert_eq!(midi_time_signature(TimeSignature::common()), (4, 2));
+        assert_eq!(midi_time_signature(TimeSignature(6, 8)), (6, 3));
+        // not a power of two, so the nearest one
+        assert_eq!(midi_time_signature(TimeSignature(3, 6)), (3, 3));
+        assert_eq!(midi_time_signature(TimeSignature(5, 3)), (5, 2));
+        assert_eq!(midi_time_signature(TimeSignature(300, 4)), (255, 2));
+    }
+
+    #[test]
+    fn test_single_track_vs_parallel() {
+        let string = MusicString::from_str("{::i=piano :c :d | ::i=bass :3c<2> | ::i=snare :c :c}").unwrap();
+        let composition = string.compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(composition.tracks.len(), 3);
+
+        let parallel = composition.to_midi_file(120., MidiFileFormat::Parallel).unwrap();
+        let smf = Smf::parse(&parallel).unwrap();
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(smf.tracks.len(), 3);
+
+        let single = composition.to_midi_file(120., MidiFileFormat::SingleTrack).unwrap();
+        let smf = Smf::parse(&single).unwrap();
+        assert_eq!(smf.header.format, Format::SingleTrack);
+        assert_eq!(smf.tracks.len(), 1);
+        let channels: HashSet<_> = smf.tracks[0].iter()
+            .filter_map(|e| match e.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { .. } } => Some(channel),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(channels.len(), 3);
+        // the snare's two notes are on the drum channel, without a program change
+        assert_eq!(channels, HashSet::from([export_channel(Instrument::Piano).into(), export_channel(Instrument::Bass).into(), PERCUSSION_CHANNEL.into()]));
+        let drum_note_ons = smf.tracks[0].iter()
+            .filter(|e| matches!(e.kind, TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { .. } } if channel == PERCUSSION_CHANNEL))
+            .count();
+        assert_eq!(drum_note_ons, 2);
+        assert!(!smf.tracks[0].iter().any(|e| matches!(e.kind,
+            TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { .. } } if channel == PERCUSSION_CHANNEL)));
+        let note_ons = smf.tracks[0].iter()
+            .filter(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. }))
+            .count();
+        assert_eq!(note_ons, 5);
+    }
+}
@@ -0,0 +1,154 @@
+// Recording from a MIDI input, ex. a keyboard, into a composition.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use midly::live::LiveEvent;
+use midly::MidiMessage;
+use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume, DEFAULT_INSTRUMENT, MAX_VOLUME};
+use crate::midi_file::{export_channel, TICKS_PER_QUARTER};
+use crate::player::{MidiChannel, MidiPort};
+use crate::time::{Beat, BeatUnit, MusicTime, Seconds, TimeSignature, BPM};
+
+/// Play on MIDI input port `port` for `duration` seconds and get what was played, with times
+/// counted at a steady `bpm` from when recording started. Each channel is recorded as the
+/// instrument that `midi_file::export_channel` gives that channel, and the drum channel as
+/// the first percussion instrument.
+pub fn record_midi(port: MidiPort, duration: Seconds, bpm: BPM, time_signature: TimeSignature) -> Result<Composition, Box<dyn std::error::Error>> {
+    let midi_in = midir::MidiInput::new("music-turtles-input")?;
+    let ports = midi_in.ports();
+    let input_port = ports.get(port as usize)
+        .ok_or(format!("No MIDI input port {port}, there are {}", ports.len()))?;
+    let (sender, receiver) = mpsc::channel();
+    let start = Instant::now();
+    let connection = midi_in.connect(input_port, "music-turtles-recording", move |_stamp, message, _| {
+        // the receiver is only gone once recording is over
+        let _ = sender.send((start.elapsed().as_secs_f32(), message.to_vec()));
+    }, ())?;
+    std::thread::sleep(Duration::from_secs_f32(duration.max(0.)));
+    connection.close();
+    Ok(record(receiver.try_iter(), duration, bpm, time_signature))
+}
+
+/// The notes in `messages`, each a raw MIDI message and how many seconds after the start
+/// it came. Anything after `duration` is left out, and notes still held then end there.
+fn record(messages: impl IntoIterator<Item = (Seconds, Vec<u8>)>, duration: Seconds, bpm: BPM, time_signature: TimeSignature) -> Composition {
+    let mut recorder = Recorder::new(bpm, time_signature);
+    for (at, message) in messages {
+        if at < duration {
+            recorder.message(at, &message);
+        }
+    }
+    recorder.finish(duration)
+}
+
+/// The notes being held, and the ones that are done.
+struct Recorder {
+    bpm: BPM,
+    time_signature: TimeSignature,
+    /// when each key was pressed, and how hard
+    held: HashMap<(MidiChannel, u8), (Seconds, u8)>,
+    tracks: BTreeMap<Instrument, Track>,
+}
+
+impl Recorder {
+    fn new(bpm: BPM, time_signature: TimeSignature) -> Self {
+        Recorder { bpm, time_signature, held: HashMap::new(), tracks: BTreeMap::new() }
+    }
+
+    /// Note-ons and note-offs are recorded, and anything else is ignored.
+    /// A key pressed again while it's held ends the note it was holding.
+    fn message(&mut self, at: Seconds, message: &[u8]) {
+        let Ok(LiveEvent::Midi { channel, message }) = LiveEvent::parse(message) else {
+            return;
+        };
+        let channel = channel.as_int() as MidiChannel;
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                self.release(channel, key.as_int(), at);
+                self.held.insert((channel, key.as_int()), (at, vel.as_int()));
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                self.release(channel, key.as_int(), at);
+            }
+            _ => {}
+        }
+    }
+
+    fn release(&mut self, channel: MidiChannel, key: u8, at: Seconds) {
+        let Some((pressed, velocity)) = self.held.remove(&(channel, key)) else {
+            return;
+        };
+        let start = self.beats(pressed);
+        let event = Event {
+            start: start.as_music_time(self.time_signature),
+            duration: Beat::from_ratio(self.beats(at).as_ratio() - start.as_ratio()),
+            volume: Volume((velocity as u32 * MAX_VOLUME + 63) / 127),
+            pitch: Pitch::from_midi_note(key),
+            gate: None,
+            tag: None,
+            gap: false,
+        };
+        let instrument = Instrument::values()
+            .find(|i| export_channel(*i) == channel)
+            .unwrap_or(DEFAULT_INSTRUMENT);
+        self.tracks.entry(instrument)
+            .or_insert_with(|| Track {
+                identifier: TrackId::Instrument(instrument),
+                instrument,
+                events: vec![],
+                rests: vec![],
+            })
+            .events.push(event);
+    }
+
+    /// Beats after the start at `seconds`, to the nearest MIDI file tick.
+    fn beats(&self, seconds: Seconds) -> Beat {
+        let quarters = seconds.max(0.) * self.bpm / 60.;
+        let ticks = (quarters * TICKS_PER_QUARTER as f32).round() as BeatUnit;
+        Beat::new(ticks * self.time_signature.1, TICKS_PER_QUARTER as BeatUnit * 4)
+    }
+
+    fn finish(mut self, end: Seconds) -> Composition {
+        let held: Vec<_> = self.held.keys().copied().collect();
+        for (channel, key) in held {
+            self.release(channel, key, end);
+        }
+        let mut tracks: Vec<_> = self.tracks.into_values().collect();
+        tracks.iter_mut().for_each(|t| t.events.sort());
+        Composition {
+            tracks,
+            time_signature: self.time_signature,
+            tempo_map: vec![(MusicTime::zero(), self.bpm)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::composition::{Event, Instrument, Pitch, Volume};
+    use crate::midi_input::record;
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_record_two_notes() {
+        // at 120 bpm a beat is half a second
+        let messages = vec![
+            (0.0, vec![0x90, 60, 127]),
+            (0.5, vec![0x90, 64, 64]),
+            // a note-on with velocity 0 is a note-off
+            (0.75, vec![0x90, 60, 0]),
+            (1.0, vec![0xB0, 64, 127]),
+            // after the end
+            (3.0, vec![0x80, 64, 0]),
+        ];
+        let composition = record(messages, 2.0, 120., TimeSignature::common());
+        assert_eq!(composition.tracks.len(), 1);
+        assert_eq!(composition.tracks[0].instrument, Instrument::values().next().unwrap());
+        assert_eq!(composition.tracks[0].events, vec![
+            Event { start: MusicTime::zero(), duration: Beat::new(3, 2), volume: Volume(100), pitch: Pitch(4, 3), gate: None, tag: None, gap: false },
+            // still held at the end, so it's closed there
+            Event { start: MusicTime::beats(1), duration: Beat::whole(3), volume: Volume(50), pitch: Pitch(4, 7), gate: None, tag: None, gap: false },
+        ]);
+    }
+}
@@ -0,0 +1,138 @@
+// Import from rows of plain numbers, ex. a melody typed into a spreadsheet.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use num::rational::Ratio;
+use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume, MAX_VOLUME};
+use crate::time::{Beat, BeatUnit, MusicTime, TimeSignature, BPM};
+
+/// A row of a note CSV that couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteCsvError {
+    /// counting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for NoteCsvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for NoteCsvError {}
+
+/// A number of beats, written as a whole number, a decimal like `1.5` or a fraction like `3/2`.
+fn parse_beats(s: &str) -> Option<Beat> {
+    if let Some((numerator, denominator)) = s.split_once('/') {
+        let denominator: BeatUnit = denominator.trim().parse().ok().filter(|d| *d != 0)?;
+        return Some(Beat::new(numerator.trim().parse().ok()?, denominator));
+    }
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    let whole: BeatUnit = if whole.is_empty() { 0 } else { whole.parse().ok()? };
+    if fraction.is_empty() {
+        return Some(Beat::whole(whole));
+    }
+    let scale = (10 as BeatUnit).checked_pow(fraction.len() as u32)?;
+    let fraction: BeatUnit = fraction.parse().ok()?;
+    Some(Beat::from_ratio(Ratio::from_integer(whole) + Ratio::new(fraction, scale)))
+}
+
+/// One `start_beat,duration_beat,midi_note,velocity,instrument` row.
+fn parse_row(row: &str, time_signature: TimeSignature) -> Result<(Instrument, Event), String> {
+    let fields: Vec<_> = row.split(',').map(str::trim).collect();
+    let [start, duration, note, velocity, instrument] = fields[..] else {
+        return Err(format!("Expected 5 fields (start_beat,duration_beat,midi_note,velocity,instrument) but found {}", fields.len()));
+    };
+    let start = parse_beats(start).ok_or(format!("Invalid start beat {start}"))?;
+    let duration = parse_beats(duration).ok_or(format!("Invalid duration {duration}"))?;
+    let note: u8 = note.parse().ok().filter(|n| *n < 128).ok_or(format!("Invalid MIDI note {note}"))?;
+    let velocity: u32 = velocity.parse().ok().filter(|v| *v < 128).ok_or(format!("Invalid velocity {velocity}"))?;
+    let instrument = Instrument::from_str(instrument)?;
+    let event = Event {
+        start: start.as_music_time(time_signature),
+        duration,
+        volume: Volume((velocity * MAX_VOLUME + 63) / 127),
+        pitch: Pitch::from_midi_note(note),
+        gate: None,
+        tag: None,
+        gap: false,
+    };
+    Ok((instrument, event))
+}
+
+impl Composition {
+    /// Notes from rows of `start_beat,duration_beat,midi_note,velocity,instrument`, ex. `0,1,60,100,piano`,
+    /// with a track for each instrument. Beats can be whole numbers, decimals or fractions like `1/3`,
+    /// and velocities (0 to 127) are scaled to volumes. Blank lines, lines starting with `#`,
+    /// and a first line of column names are skipped. `bpm` becomes the tempo from the start.
+    pub fn from_note_csv(text: &str, time_signature: TimeSignature, bpm: BPM) -> Result<Composition, NoteCsvError> {
+        let mut tracks: BTreeMap<Instrument, Track> = BTreeMap::new();
+        for (i, row) in text.lines().enumerate() {
+            let row = row.trim();
+            let is_header = i == 0 && row.split(',').next().is_some_and(|f| f.trim().eq_ignore_ascii_case("start_beat"));
+            if row.is_empty() || row.starts_with('#') || is_header {
+                continue;
+            }
+            let (instrument, event) = parse_row(row, time_signature)
+                .map_err(|message| NoteCsvError { line: i + 1, message })?;
+            tracks.entry(instrument)
+                .or_insert_with(|| Track {
+                    identifier: TrackId::Instrument(instrument),
+                    instrument,
+                    events: vec![],
+                    rests: vec![],
+                })
+                .events.push(event);
+        }
+        let mut tracks: Vec<_> = tracks.into_values().collect();
+        tracks.iter_mut().for_each(|t| t.events.sort());
+        Ok(Composition {
+            tracks,
+            time_signature,
+            tempo_map: vec![(MusicTime::zero(), bpm)],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::composition::{Composition, Event, Instrument, Pitch, Volume};
+    use crate::note_csv::{parse_beats, NoteCsvError};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_from_note_csv() {
+        let text = "start_beat,duration_beat,midi_note,velocity,instrument\n\
+                    0,1,60,127,piano\n\
+                    \n\
+                    1.5, 1/2, 64, 0, piano\n\
+                    4,2,36,100,bass\n";
+        let composition = Composition::from_note_csv(text, TimeSignature::common(), 90.).unwrap();
+        assert_eq!(composition.tempo_map, vec![(MusicTime::zero(), 90.)]);
+        assert_eq!(composition.tracks.len(), 2);
+        let piano = composition.tracks.iter().find(|t| t.instrument == Instrument::Piano).unwrap();
+        assert_eq!(piano.events, vec![
+            Event { start: MusicTime::zero(), duration: Beat::whole(1), volume: Volume(100), pitch: Pitch(4, 3), gate: None, tag: None, gap: false },
+            Event { start: MusicTime(0, Beat::new(3, 2)), duration: Beat::new(1, 2), volume: Volume(0), pitch: Pitch(4, 7), gate: None, tag: None, gap: false },
+        ]);
+        let bass = composition.tracks.iter().find(|t| t.instrument == Instrument::Bass).unwrap();
+        assert_eq!(bass.events, vec![
+            Event { start: MusicTime::measures(1), duration: Beat::whole(2), volume: Volume(79), pitch: Pitch(2, 3), gate: None, tag: None, gap: false },
+        ]);
+    }
+
+    #[test]
+    fn test_note_csv_errors() {
+        let error = |text| Composition::from_note_csv(text, TimeSignature::common(), 120.).unwrap_err();
+        assert_eq!(error("0,1,60,100,piano\n0,1,200,100,piano").line, 2);
+        assert_eq!(error("\n# a comment\n0,1,60,piano"), NoteCsvError {
+            line: 3,
+            message: "Expected 5 fields (start_beat,duration_beat,midi_note,velocity,instrument) but found 4".to_string(),
+        });
+        assert_eq!(error("0,1,60,100,kazoo").to_string(), "line 1: Unknown instrument: kazoo");
+        assert_eq!(parse_beats("2.25"), Some(Beat::new(9, 4)));
+        assert_eq!(parse_beats("1/0"), None);
+    }
+}
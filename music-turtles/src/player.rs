@@ -1,111 +1,338 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 use midly::live::LiveEvent;
 use midly::MidiMessage;
 use rodio::{OutputStream, OutputStreamHandle, Source};
 use crate::composition::{Event, Instrument, Pitch, Volume};
 use crate::constants::get_fuzzy_mapping;
-use crate::time::Seconds;
+use crate::scheduler::{ScheduledSound, SynthOptions};
+use crate::time::{LiveTempo, Seconds};
 
 pub type MidiChannel = u8;
 
+/// Stereo position, from -1.0 (left) to 1.0 (right). 0.0 is centered.
+pub type Pan = f32;
+
 pub struct AtomicSound {
     pub start: Seconds,
     pub duration: Seconds,
     pub volume: Volume,
     pub pitch: Pitch,
-    pub instrument: Instrument
+    pub instrument: Instrument,
+    pub pan: Pan,
+}
+
+/// Where playback gets the time from, so that timing can be tested without actually waiting.
+pub trait Clock {
+    fn now(&self) -> Instant;
+    /// Sleep until `target`. Returns immediately if it has already passed.
+    fn sleep_until(&self, target: Instant);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, target: Instant) {
+        if let Some(wait) = target.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// When a sound should be released, measured from when playback started
+/// rather than from when it was actually played, so late notes still end on time.
+pub fn note_off_instant(start_time: Instant, event: &AtomicSound) -> Instant {
+    let end = (event.start + event.duration).max(0.);
+    start_time + Duration::from_secs_f64(end as f64)
+}
+
+/// The softest velocity a percussion hit is sent at. Drum samples tend to be
+/// inaudible (or not triggered at all) much below this.
+const PERCUSSION_MIN_VELOCITY: f32 = 40.;
+
+/// MIDI velocity for `volume` on `instrument`. Melodic instruments use the whole range,
+/// while percussion is scaled into the upper part of it. Silence is always 0.
+pub fn velocity(instrument: Instrument, volume: Volume) -> u8 {
+    let level = volume.as_f32().clamp(0., 1.);
+    if level == 0. {
+        return 0;
+    }
+    let velocity = if instrument.is_percussion() {
+        PERCUSSION_MIN_VELOCITY + level * (127. - PERCUSSION_MIN_VELOCITY)
+    } else {
+        level * 127.
+    };
+    velocity.round() as u8
 }
 
 pub trait AudioPlayer {
     fn play(&mut self, event: AtomicSound);
 
+    /// Called with the instant that `AtomicSound::start` is measured from, before anything is played.
+    fn set_start_time(&mut self, _start_time: Instant) {}
+
     fn play_from_ordered_channel<T: Into<AtomicSound>>(&mut self, queue: Receiver<T>) {
-        let start_time = SystemTime::now();
+        self.play_from_ordered_channel_with_clock(queue, &SystemClock);
+    }
+
+    /// Each event is played at `start_time + event.start`, rather than after a wait relative
+    /// to the previous event, so that oversleeping doesn't add up over long loops.
+    /// Events that are already past due are played immediately.
+    fn play_from_ordered_channel_with_clock<T: Into<AtomicSound>, C: Clock>(&mut self, queue: Receiver<T>, clock: &C) {
+        let start_time = clock.now();
+        self.set_start_time(start_time);
         let mut end = start_time;
         for event in queue {
             let event = event.into();
-            let current_time = SystemTime::now();
-            let elapsed = current_time.duration_since(start_time).unwrap().as_secs_f32();
-            let wait_time = event.start - elapsed;
-            if wait_time > 0. {
-                thread::sleep(Duration::from_secs_f32(wait_time));
-            }
-            end = SystemTime::max(end, current_time + Duration::from_secs_f32(f32::max(wait_time, 0.) + event.duration));
-            // println!("playing sound: {start:?}");
+            let target = start_time + Duration::from_secs_f64(event.start.max(0.) as f64);
+            clock.sleep_until(target);
+            let played_at = Instant::max(target, clock.now());
+            end = Instant::max(end, played_at + Duration::from_secs_f32(event.duration.max(0.)));
             self.play(event);
         }
         // wait for the last sound to finish
-        let wait_time = end.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(1)).as_secs_f32();
-        if wait_time > 0. {
-            thread::sleep(Duration::from_secs_f32(wait_time));
+        clock.sleep_until(end);
+    }
+
+    /// Like `play_from_ordered_channel_with_clock`, but each sound is converted to real time
+    /// when it's played, at whatever `tempo` is then, so tempo changes affect sounds
+    /// that were already scheduled.
+    fn play_from_ordered_channel_with_tempo<C: Clock>(&mut self, queue: Receiver<ScheduledSound>, tempo: &Mutex<LiveTempo>, clock: &C) {
+        let start_time = clock.now();
+        self.set_start_time(start_time);
+        let mut end = start_time;
+        for event in queue {
+            // wait in short steps, in case the tempo changes in the meantime
+            let (event, target) = loop {
+                let event = event.to_atomic(&tempo.lock().unwrap());
+                let target = start_time + Duration::from_secs_f64(event.start.max(0.) as f64);
+                let now = clock.now();
+                if now >= target {
+                    break (event, target);
+                }
+                clock.sleep_until(target.min(now + TEMPO_POLL));
+            };
+            let played_at = Instant::max(target, clock.now());
+            end = Instant::max(end, played_at + Duration::from_secs_f32(event.duration.max(0.)));
+            self.play(event);
         }
+        // wait for the last sound to finish
+        clock.sleep_until(end);
+    }
+}
+
+/// An `AudioPlayer` that hands each sound to a closure when it's due, ex. to drive another synth.
+pub struct CallbackPlayer<F: FnMut(AtomicSound)> {
+    callback: F,
+}
+
+impl<F: FnMut(AtomicSound)> CallbackPlayer<F> {
+    pub fn new(callback: F) -> Self {
+        CallbackPlayer { callback }
+    }
+}
+
+impl<F: FnMut(AtomicSound)> AudioPlayer for CallbackPlayer<F> {
+    fn play(&mut self, event: AtomicSound) {
+        (self.callback)(event);
+    }
+}
+
+/// How often `play_from_ordered_channel_with_tempo` checks the tempo while waiting.
+const TEMPO_POLL: Duration = Duration::from_millis(10);
+
+/// How many sounds the rodio `Player` plays at once by default.
+pub const DEFAULT_POLYPHONY: usize = 32;
+
+/// A limited set of voices, handed out to new sounds: a voice that is free if there is one,
+/// a new voice if there's room for it, and otherwise the one that was started longest ago.
+pub struct VoicePool<V> {
+    cap: usize,
+    /// in the order they were last handed out, oldest first
+    voices: VecDeque<V>,
+}
+
+impl<V> VoicePool<V> {
+    /// A cap of 0 is treated as 1.
+    pub fn new(cap: usize) -> Self {
+        VoicePool { cap: cap.max(1), voices: VecDeque::new() }
+    }
+
+    /// How many voices have been made so far. Never more than the cap.
+    pub fn voice_count(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// A voice for a new sound. `new_voice` is only called when there's no free voice and room for another.
+    pub fn allocate(&mut self, is_free: impl Fn(&V) -> bool, new_voice: impl FnOnce() -> V) -> &mut V {
+        let reused = self.voices.iter().position(is_free)
+            .or_else(|| (self.voices.len() >= self.cap).then_some(0));
+        let voice = match reused.and_then(|i| self.voices.remove(i)) {
+            Some(voice) => voice,
+            None => new_voice(),
+        };
+        self.voices.push_back(voice);
+        self.voices.back_mut().unwrap()
     }
 }
 
 pub struct Player {
     stream: OutputStream,
-    output_stream: OutputStreamHandle
+    output_stream: OutputStreamHandle,
+    synth_options: SynthOptions,
+    /// one sink per voice, so dense music can't pile up sinks without limit
+    voices: Mutex<VoicePool<rodio::Sink>>,
 }
 
 pub trait Playable {
     /// get start time, duration, and actual sound
-    fn get_source(&self) -> (Seconds, Seconds, Box<dyn Source<Item=f32> + Send + 'static>);
+    fn get_source(&self, options: &SynthOptions) -> (Seconds, Seconds, Box<dyn Source<Item=f32> + Send + 'static>);
 }
 
 impl Player {
     pub fn new() -> Self {
+        Player::with_synth_options(SynthOptions::default())
+    }
+
+    pub fn with_synth_options(synth_options: SynthOptions) -> Self {
         let (stream, output_stream) = OutputStream::try_default().unwrap();
-        Player { stream, output_stream }
+        Player { stream, output_stream, synth_options, voices: Mutex::new(VoicePool::new(DEFAULT_POLYPHONY)) }
+    }
+
+    /// Play at most `polyphony` sounds at once. Past that, the oldest sound is cut off.
+    pub fn with_polyphony(self, polyphony: usize) -> Self {
+        Player { voices: Mutex::new(VoicePool::new(polyphony)), ..self }
     }
+
     pub fn play(&self, source: impl Source<Item=f32> + Send + 'static) {
-        let sink = rodio::Sink::try_new(&self.output_stream).unwrap();
-        // thread::spawn(move || {
-        //     let source: Box<dyn Source<Item=f32> + Send> = Box::new(source);
-        //     sink.append(source);
-        //     sink.sleep_until_end();
-        // });
+        let mut voices = self.voices.lock().unwrap();
+        let sink = voices.allocate(
+            |sink| sink.empty(),
+            || rodio::Sink::try_new(&self.output_stream).unwrap(),
+        );
+        // cut off whatever the voice is still playing, if it was stolen
+        sink.skip_one();
         let source: Box<dyn Source<Item=f32> + Send> = Box::new(source);
         sink.append(source);
-        sink.detach();
     }
 
     /// Incoming events MUST BE IN ORDER
     pub fn play_from_ordered_channel<T: Playable>(&self, queue: Receiver<T>) {
         let start_pause = 0.1; // seconds
-        let start_time = SystemTime::now() - std::time::Duration::from_secs_f32(start_pause);
+        let clock = SystemClock;
+        let start_time = clock.now() - Duration::from_secs_f32(start_pause);
         let mut end = start_time;
         for event in queue {
-            let (start, duration, source) = event.get_source();
-            let current_time = SystemTime::now();
-            let elapsed = current_time.duration_since(start_time).unwrap().as_secs_f32();
-            let wait_time = start - elapsed;
-            // println!("Waiting for {wait_time} until {start}... (sound is {duration}s long)");
-            if wait_time > 0. {
-                thread::sleep(std::time::Duration::from_secs_f32(wait_time));
-            }
-            end = SystemTime::max(end, current_time + std::time::Duration::from_secs_f32(f32::max(wait_time, 0.) + duration));
+            let (start, duration, source) = event.get_source(&self.synth_options);
+            // wait until an absolute time so that lateness doesn't accumulate
+            let target = start_time + Duration::from_secs_f64(start.max(0.) as f64);
+            // println!("Waiting until {start}... (sound is {duration}s long)");
+            clock.sleep_until(target);
+            end = Instant::max(end, Instant::max(target, clock.now()) + Duration::from_secs_f32(duration.max(0.)));
             println!("playing sound: {start:?}");
             self.play(source);
         }
         // wait for the last sound to finish
-        let wait_time = end.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(1)).as_secs_f32();
-        if wait_time > 0. {
-            std::thread::sleep(std::time::Duration::from_secs_f32(wait_time));
+        clock.sleep_until(end);
+    }
+}
+
+/// Turns `LiveEvent`s into bytes, optionally using MIDI running status:
+/// a channel message with the same status byte as the one before it is sent without it.
+#[derive(Debug, Clone, Default)]
+pub struct MidiEncoder {
+    pub running_status: bool,
+    last_status: Option<u8>,
+}
+
+impl MidiEncoder {
+    pub fn new(running_status: bool) -> Self {
+        MidiEncoder { running_status, last_status: None }
+    }
+
+    pub fn encode(&mut self, event: LiveEvent) -> Vec<u8> {
+        let mut buf = Vec::new();
+        event.write(&mut buf).unwrap();
+        let status = buf[0];
+        match status {
+            // channel messages can reuse the previous status
+            0x80..=0xEF => {
+                if self.running_status && self.last_status == Some(status) {
+                    buf.remove(0);
+                }
+                self.last_status = Some(status);
+            }
+            // real-time messages can be sent in between without affecting it
+            0xF8..=0xFF => {}
+            // system common and exclusive messages cancel it
+            _ => self.last_status = None,
         }
+        buf
     }
 }
 
 pub type MidiPort = u8;
+
+/// How many times each key is being held on each channel, so that when the same note is started
+/// again before it's released, the first release doesn't cut off the second note.
+#[derive(Debug, Clone, Default)]
+pub struct HeldNotes(HashMap<(MidiPort, MidiChannel, u8), usize>);
+
+impl HeldNotes {
+    pub fn press(&mut self, port: MidiPort, channel: MidiChannel, key: u8) {
+        *self.0.entry((port, channel, key)).or_default() += 1;
+    }
+
+    /// Returns whether the note-off should be sent, which is when nothing else holds the key.
+    pub fn release(&mut self, port: MidiPort, channel: MidiChannel, key: u8) -> bool {
+        match self.0.get_mut(&(port, channel, key)) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                self.0.remove(&(port, channel, key));
+                true
+            }
+        }
+    }
+
+    /// Forget a key however many times it's held, ex. when it was cut off early.
+    /// Returns whether it was held at all.
+    pub fn release_all(&mut self, port: MidiPort, channel: MidiChannel, key: u8) -> bool {
+        self.0.remove(&(port, channel, key)).is_some()
+    }
+
+    /// Forget every key held on a channel, ex. after all of its notes were turned off.
+    pub fn release_channel(&mut self, port: MidiPort, channel: MidiChannel) {
+        self.0.retain(|(p, c, _key), _count| (*p, *c) != (port, channel));
+    }
+}
+
 pub struct MidiPlayer {
     name: String,
     port_channel_mapping: HashMap<Instrument, (MidiPort, MidiChannel)>,
     instrument_mapping: HashMap<Instrument, u8>,
-    conn: Arc<HashMap<MidiPort, Mutex<midir::MidiOutputConnection>>>,
+    conn: Arc<HashMap<MidiPort, Mutex<(midir::MidiOutputConnection, MidiEncoder)>>>,
+    /// when playback started, for timing note-offs
+    start_time: Option<Instant>,
+    running_status: bool,
+    /// where program changes go; `None` sends them on each instrument's note channel
+    control_channel: Option<MidiChannel>,
+    /// instruments whose program has already been sent
+    programs_sent: HashSet<Instrument>,
+    /// shared with the threads that send note-offs
+    held_notes: Arc<Mutex<HeldNotes>>,
+    /// the last instrument and key started in each choke group
+    choke_notes: HashMap<u8, (Instrument, u8)>,
 }
 
 impl MidiPlayer {
@@ -120,7 +347,7 @@ impl MidiPlayer {
             let port = p;
             let midi_out_i = midir::MidiOutput::new(&format!("{}-{}", name, i))?;
             let conn = midi_out_i.connect(port, &format!("midir-connection-{i}"))?;
-            conns.insert(i as MidiPort, Mutex::new(conn));
+            conns.insert(i as MidiPort, Mutex::new((conn, MidiEncoder::default())));
         }
         // // Pick a port
         // let port = &out_ports[0];
@@ -128,55 +355,537 @@ impl MidiPlayer {
         // let conn = Arc::new(Mutex::new(conn));
         // conns.insert(0, Mutex::new(midi_out.connect(&out_ports[0], "music-turtles")?));
         println!("Created {} connections", conns.len());
-        Ok(MidiPlayer { name, port_channel_mapping, conn: Arc::new(conns), instrument_mapping: get_fuzzy_mapping() })
+        Ok(MidiPlayer { name, port_channel_mapping, conn: Arc::new(conns), instrument_mapping: get_fuzzy_mapping(), start_time: None, running_status: false, control_channel: None, programs_sent: HashSet::new(), held_notes: Arc::new(Mutex::new(HeldNotes::default())), choke_notes: HashMap::new() })
+    }
+
+    /// Use running status to send fewer bytes, for slow MIDI links. Off by default.
+    /// Note-offs are sent as note-ons with velocity 0 so they can share a status with note-ons.
+    pub fn with_running_status(self, running_status: bool) -> Self {
+        for conn in self.conn.values() {
+            conn.lock().unwrap().1 = MidiEncoder::new(running_status);
+        }
+        MidiPlayer { running_status, ..self }
+    }
+
+    /// Send program changes on `control_channel` (on each instrument's port) instead of
+    /// on the instrument's note channel, for setups that route control messages separately.
+    pub fn with_control_channel(self, control_channel: Option<MidiChannel>) -> Self {
+        MidiPlayer { control_channel, ..self }
     }
 
     pub fn get_port_channel(&self, instrument: Instrument) -> Option<(MidiPort, MidiChannel)> {
         self.port_channel_mapping.get(&instrument).cloned()
     }
+
+    /// Move `instrument` to another channel on the same port (port 0 if it wasn't mapped),
+    /// ex. while a piece loops. Everything on the old channel is silenced so nothing hangs.
+    pub fn reassign_channel(&mut self, instrument: Instrument, channel: MidiChannel) {
+        let old = self.get_port_channel(instrument);
+        let port = old.map_or(0, |(port, _)| port);
+        self.port_channel_mapping.insert(instrument, (port, channel));
+        if let Some((port, old_channel)) = old.filter(|(_, old_channel)| *old_channel != channel)
+            && let Some(conn) = self.conn.get(&port)
+        {
+            self.held_notes.lock().unwrap().release_channel(port, old_channel);
+            let mut conn = conn.lock().unwrap();
+            let (conn, encoder) = &mut *conn;
+            if let Err(e) = conn.send(&encoder.encode(all_notes_off_message(old_channel))) {
+                warn!("Failed to silence channel {old_channel} on port {port}: {e}");
+            }
+        }
+    }
+
+    /// Everything to send to start a note: a note-off for another instrument in the same choke group
+    /// that's still sounding (if it's on the same port), the instrument's program change the first
+    /// time it plays (on the control channel, if there is one), then the note-on on the instrument's channel.
+    fn note_start_messages(&mut self, instrument: Instrument, key: u8, vel: u8) -> Option<(MidiPort, Vec<LiveEvent<'static>>)> {
+        let (port, channel) = self.get_port_channel(instrument)?;
+        let mut messages = vec![];
+        if let Some(group) = instrument.choke_group()
+            && let Some((choked, choked_key)) = self.choke_notes.insert(group, (instrument, key))
+            && choked != instrument
+            && let Some((choked_port, choked_channel)) = self.get_port_channel(choked)
+            && choked_port == port
+            && self.held_notes.lock().unwrap().release_all(port, choked_channel, choked_key)
+        {
+            messages.push(note_off_message(choked_channel, choked_key, 0, self.running_status));
+        }
+        if let Some(program) = self.instrument_mapping.get(&instrument)
+            && self.programs_sent.insert(instrument)
+        {
+            messages.push(program_change_message(self.control_channel.unwrap_or(channel), *program));
+        }
+        messages.push(note_on_message(channel, key, vel));
+        self.held_notes.lock().unwrap().press(port, channel, key);
+        Some((port, messages))
+    }
+}
+
+fn note_on_message(channel: MidiChannel, key: u8, vel: u8) -> LiveEvent<'static> {
+    LiveEvent::Midi {
+        channel: channel.into(),
+        message: MidiMessage::NoteOn {
+            key: key.into(),
+            vel: vel.into(),
+        },
+    }
+}
+
+/// With running status, note-offs are note-ons with velocity 0 so they can share a status with note-ons.
+fn note_off_message(channel: MidiChannel, key: u8, vel: u8, running_status: bool) -> LiveEvent<'static> {
+    LiveEvent::Midi {
+        channel: channel.into(),
+        message: if running_status {
+            MidiMessage::NoteOn {
+                key: key.into(),
+                vel: 0.into(),
+            }
+        } else {
+            MidiMessage::NoteOff {
+                key: key.into(),
+                vel: vel.into(),
+            }
+        },
+    }
+}
+
+fn program_change_message(channel: MidiChannel, program: u8) -> LiveEvent<'static> {
+    LiveEvent::Midi {
+        channel: channel.into(),
+        message: MidiMessage::ProgramChange {
+            program: program.into(),
+        },
+    }
+}
+
+/// Controller 123 releases every note on the channel.
+fn all_notes_off_message(channel: MidiChannel) -> LiveEvent<'static> {
+    LiveEvent::Midi {
+        channel: channel.into(),
+        message: MidiMessage::Controller {
+            controller: 123.into(),
+            value: 0.into(),
+        },
+    }
 }
 
 impl AudioPlayer for MidiPlayer {
+    fn set_start_time(&mut self, start_time: Instant) {
+        self.start_time = Some(start_time);
+    }
+
     fn play(&mut self, event: AtomicSound) {
         let note = event.pitch.to_midi_note();
-        let volume = ((event.volume.0 as f32 / 100.) * 128.) as u8;
+        let volume = velocity(event.instrument, event.volume);
         let (port, channel) = self.get_port_channel(event.instrument)
             .unwrap();
+        let (_port, start_messages) = self.note_start_messages(event.instrument, note, volume)
+            .unwrap();
         info!("Playing instrument {:?} on port {} channel {} at volume {}", event.instrument, port, channel, volume);
-        let note_on_message = |channel: u8, key: u8, vol: u8| {
-            let ev = LiveEvent::Midi {
-                channel: channel.into(),
-                message: MidiMessage::NoteOn {
-                    key: key.into(),
-                    vel: vol.into(),
-                },
-            };
-            let mut buf = Vec::new();
-            ev.write(&mut buf).unwrap();
-            buf
-        };
-        let note_off_message = |channel: u8, key: u8, vol: u8| {
-            let ev = LiveEvent::Midi {
-                channel: channel.into(),
-                message: MidiMessage::NoteOff {
-                    key: key.into(),
-                    vel: vol.into(),
-                },
-            };
-            let mut buf = Vec::new();
-            ev.write(&mut buf).unwrap();
-            buf
-        };
+        let running_status = self.running_status;
+        let held_notes = Arc::clone(&self.held_notes);
         let arc = Arc::clone(&self.conn);
         let thread_conn = Arc::clone(&self.conn);
         let mut conn = arc.get(&port).unwrap().lock()
             .unwrap();
-        conn.send(&note_on_message(channel, note, volume)).unwrap();
-        let duration = event.duration;
+        let (conn, encoder) = &mut *conn;
+        for message in start_messages {
+            conn.send(&encoder.encode(message)).unwrap();
+        }
+        // release at the scheduled end, so that notes that end together release together
+        let off_at = match self.start_time {
+            Some(start_time) => note_off_instant(start_time, &event),
+            None => Instant::now() + Duration::from_secs_f32(event.duration.max(0.)),
+        };
         thread::spawn(move || {
-            thread::sleep(Duration::from_secs_f32(duration));
+            thread::sleep(off_at.saturating_duration_since(Instant::now()));
+            // the same note was started again while this one was held, so leave it to that one
+            if !held_notes.lock().unwrap().release(port, channel, note) {
+                return;
+            }
             let mut conn = thread_conn.get(&port).unwrap().lock().unwrap();
-            conn.send(&note_off_message(channel, note, volume)).unwrap();
+            let (conn, encoder) = &mut *conn;
+            conn.send(&encoder.encode(note_off_message(channel, note, volume, running_status))).unwrap();
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::collections::{HashMap, HashSet};
+    use std::rc::Rc;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use crate::composition::{Composition, Instrument, Pitch, Track, TrackId, Volume};
+    use crate::composition::composition_element_tests::note;
+    use crate::scheduler::Scheduler;
+    use crate::time::{Beat, LiveTempo, MusicTime, TimeSignature};
+    use midly::live::LiveEvent;
+    use midly::MidiMessage;
+    use crate::player::{all_notes_off_message, note_off_instant, note_off_message, note_on_message, program_change_message, velocity, AtomicSound, AudioPlayer, CallbackPlayer, Clock, HeldNotes, MidiEncoder, MidiPlayer, VoicePool};
+
+    /// A clock that oversleeps every time and takes a little time for every reading.
+    struct JitteryClock {
+        now: Cell<Instant>,
+        oversleep: Duration,
+        overhead: Duration,
+    }
+
+    impl Clock for JitteryClock {
+        fn now(&self) -> Instant {
+            let now = self.now.get();
+            self.now.set(now + self.overhead);
+            now
+        }
+
+        fn sleep_until(&self, target: Instant) {
+            if target > self.now.get() {
+                self.now.set(target + self.oversleep);
+            }
+        }
+    }
+
+    struct RecordingPlayer {
+        clock: Rc<JitteryClock>,
+        /// start, when it was played, and when it will be released
+        played: Rc<RefCell<Vec<(f32, Instant, Instant)>>>,
+        start_time: Option<Instant>,
+    }
+
+    impl AudioPlayer for RecordingPlayer {
+        fn set_start_time(&mut self, start_time: Instant) {
+            self.start_time = Some(start_time);
+        }
+
+        fn play(&mut self, event: AtomicSound) {
+            let off = note_off_instant(self.start_time.unwrap(), &event);
+            self.played.borrow_mut().push((event.start, self.clock.now.get(), off));
+        }
+    }
+
+    fn sound(start: f32) -> AtomicSound {
+        AtomicSound {
+            start,
+            duration: 0.005,
+            volume: Volume(50),
+            pitch: Pitch(0, 4),
+            instrument: Instrument::SineWave,
+            pan: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_no_accumulated_drift() {
+        let start = Instant::now();
+        let clock = Rc::new(JitteryClock {
+            now: Cell::new(start),
+            oversleep: Duration::from_micros(700),
+            overhead: Duration::from_micros(30),
+        });
+        let played = Rc::new(RefCell::new(vec![]));
+        let mut player = RecordingPlayer { clock: Rc::clone(&clock), played: Rc::clone(&played), start_time: None };
+        let (send, recv) = mpsc::channel();
+        for i in 0..1000 {
+            send.send(sound(i as f32 * 0.01)).unwrap();
+        }
+        drop(send);
+        player.play_from_ordered_channel_with_clock(recv, clock.as_ref());
+        let played = played.borrow();
+        assert_eq!(played.len(), 1000);
+        let (ideal, actual, _off) = played[999];
+        let ideal = start + Duration::from_secs_f64(ideal as f64);
+        let error = actual.duration_since(ideal);
+        assert!(error < Duration::from_millis(2), "1000th event was {error:?} late");
+    }
+
+    #[test]
+    fn test_callback_player_in_order() {
+        let start = Instant::now();
+        let clock = JitteryClock {
+            now: Cell::new(start),
+            oversleep: Duration::from_millis(1),
+            overhead: Duration::from_micros(10),
+        };
+        let mut played = vec![];
+        let mut player = CallbackPlayer::new(|event: AtomicSound| played.push((event.start, event.pitch, clock.now.get())));
+        let (send, recv) = mpsc::channel();
+        for (i, pitch) in [Pitch(4, 3), Pitch(4, 5), Pitch(4, 7), Pitch(4, 8)].into_iter().enumerate() {
+            send.send(AtomicSound { pitch, ..sound(i as f32 * 0.25) }).unwrap();
+        }
+        drop(send);
+        player.play_from_ordered_channel_with_clock(recv, &clock);
+        assert_eq!(played.iter().map(|(start, pitch, _at)| (*start, *pitch)).collect::<Vec<_>>(), vec![
+            (0., Pitch(4, 3)),
+            (0.25, Pitch(4, 5)),
+            (0.5, Pitch(4, 7)),
+            (0.75, Pitch(4, 8)),
+        ]);
+        // each one waited until it was due
+        for (start_secs, _pitch, at) in &played {
+            assert!(*at >= start + Duration::from_secs_f32(*start_secs));
+        }
+    }
+
+    #[test]
+    fn test_chord_releases_together() {
+        let start = Instant::now();
+        let clock = Rc::new(JitteryClock {
+            now: Cell::new(start),
+            oversleep: Duration::from_millis(3),
+            overhead: Duration::from_millis(2),
         });
+        let played = Rc::new(RefCell::new(vec![]));
+        let mut player = RecordingPlayer { clock: Rc::clone(&clock), played: Rc::clone(&played), start_time: None };
+        let (send, recv) = mpsc::channel();
+        for pitch in [Pitch(4, 3), Pitch(4, 7), Pitch(4, 10)] {
+            send.send(AtomicSound { pitch, ..sound(0.5) }).unwrap();
+        }
+        drop(send);
+        player.play_from_ordered_channel_with_clock(recv, clock.as_ref());
+        let played = played.borrow();
+        // the notes were played at different times because of the jitter...
+        assert!(played[2].1 > played[0].1);
+        // ...but they all get released at the same time
+        let offs: Vec<_> = played.iter().map(|(_start, _at, off)| *off).collect();
+        let earliest = *offs.iter().min().unwrap();
+        let latest = *offs.iter().max().unwrap();
+        assert!(latest.duration_since(earliest) < Duration::from_micros(100));
+        let ideal = start + Duration::from_millis(505);
+        assert!(earliest.max(ideal).duration_since(earliest.min(ideal)) < Duration::from_micros(100));
+    }
+
+    #[test]
+    fn test_tempo_change_after_scheduling() {
+        let on_beat = |beat| note(MusicTime::beats(beat), Beat::whole(1), Pitch(4, 3));
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::measures(1),
+            looped: false,
+            loop_count: None,
+            loop_time: MusicTime::measures(1),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(Composition {
+            tracks: vec![Track {
+                identifier: TrackId::Instrument(Instrument::SineWave),
+                instrument: Instrument::SineWave,
+                events: vec![on_beat(0), on_beat(2)],
+                rests: vec![],
+            }],
+            time_signature: TimeSignature::common(),
+            tempo_map: vec![],
+        });
+        let sounds = scheduler.get_next_events_and_update(0.);
+        // half as fast as when the sounds were scheduled
+        let tempo = Mutex::new(LiveTempo::new(120.0));
+        tempo.lock().unwrap().set_bpm(60.0, 0.);
+
+        let start = Instant::now();
+        let clock = Rc::new(JitteryClock {
+            now: Cell::new(start),
+            oversleep: Duration::ZERO,
+            overhead: Duration::ZERO,
+        });
+        let played = Rc::new(RefCell::new(vec![]));
+        let mut player = RecordingPlayer { clock: Rc::clone(&clock), played: Rc::clone(&played), start_time: None };
+        let (send, recv) = mpsc::channel();
+        for sound in sounds {
+            send.send(sound).unwrap();
+        }
+        drop(send);
+        player.play_from_ordered_channel_with_tempo(recv, &tempo, clock.as_ref());
+        let played = played.borrow();
+        assert_eq!(played.len(), 2);
+        assert_eq!(played[1].0, 2.0);
+        assert_eq!(played[1].1.duration_since(start), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_past_due_plays_immediately() {
+        let start = Instant::now();
+        let clock = Rc::new(JitteryClock {
+            now: Cell::new(start),
+            oversleep: Duration::ZERO,
+            overhead: Duration::ZERO,
+        });
+        let played = Rc::new(RefCell::new(vec![]));
+        let mut player = RecordingPlayer { clock: Rc::clone(&clock), played: Rc::clone(&played), start_time: None };
+        let (send, recv) = mpsc::channel();
+        send.send(sound(1.0)).unwrap();
+        send.send(sound(0.5)).unwrap();
+        drop(send);
+        player.play_from_ordered_channel_with_clock(recv, clock.as_ref());
+        let played = played.borrow();
+        // the second event was already late, so it played right after the first
+        assert_eq!(played[1].1, played[0].1);
+    }
+
+    #[test]
+    fn test_velocity() {
+        assert_eq!(velocity(Instrument::Piano, Volume(100)), 127);
+        assert_eq!(velocity(Instrument::Piano, Volume(50)), 64);
+        assert_ne!(velocity(Instrument::BongoLow, Volume(50)), velocity(Instrument::Piano, Volume(50)));
+        assert!(velocity(Instrument::BongoLow, Volume(1)) >= 40);
+        assert_eq!(velocity(Instrument::BongoLow, Volume(100)), 127);
+        assert_eq!(velocity(Instrument::BongoLow, Volume(0)), 0);
+        // louder than the maximum is still a valid velocity
+        assert_eq!(velocity(Instrument::Piano, Volume(150)), 127);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_running_status() {
+        let note_on = |channel: u8, key: u8| LiveEvent::Midi {
+            channel: channel.into(),
+            message: MidiMessage::NoteOn { key: key.into(), vel: 100.into() },
+        };
+        let mut encoder = MidiEncoder::new(true);
+        assert_eq!(encoder.encode(note_on(0, 60)), vec![0x90, 60, 100]);
+        assert_eq!(encoder.encode(note_on(0, 64)), vec![64, 100]);
+        assert_eq!(encoder.encode(note_on(0, 67)), vec![67, 100]);
+        // a different channel needs its status byte again
+        assert_eq!(encoder.encode(note_on(1, 60)), vec![0x91, 60, 100]);
+        assert_eq!(encoder.encode(note_on(0, 60)), vec![0x90, 60, 100]);
+
+        let mut encoder = MidiEncoder::default();
+        assert_eq!(encoder.encode(note_on(0, 60)), vec![0x90, 60, 100]);
+        assert_eq!(encoder.encode(note_on(0, 64)), vec![0x90, 64, 100]);
+    }
+
+    #[test]
+    fn test_reassign_channel() {
+        // no connections, so nothing is actually sent
+        let mut player = MidiPlayer {
+            name: "test".to_string(),
+            port_channel_mapping: [(Instrument::Piano, (2, 1)), (Instrument::Snare, (3, 1))].into_iter().collect(),
+            instrument_mapping: HashMap::new(),
+            conn: Arc::new(HashMap::new()),
+            start_time: None,
+            running_status: false,
+            control_channel: None,
+            programs_sent: HashSet::new(),
+            held_notes: Arc::new(Mutex::new(HeldNotes::default())),
+            choke_notes: HashMap::new(),
+        };
+        player.reassign_channel(Instrument::Piano, 5);
+        assert_eq!(player.get_port_channel(Instrument::Piano), Some((2, 5)));
+        assert_eq!(player.get_port_channel(Instrument::Snare), Some((3, 1)));
+        let (_port, channel) = player.get_port_channel(Instrument::Piano).unwrap();
+        let mut encoder = MidiEncoder::default();
+        assert_eq!(encoder.encode(note_on_message(channel, 60, 100)), vec![0x95, 60, 100]);
+        assert_eq!(encoder.encode(all_notes_off_message(1)), vec![0xB1, 123, 0]);
+
+        player.reassign_channel(Instrument::Bass, 9);
+        assert_eq!(player.get_port_channel(Instrument::Bass), Some((0, 9)));
+    }
+
+    #[test]
+    fn test_retriggered_note_not_cut_off() {
+        let mut held = HeldNotes::default();
+        // a long C, then another C that starts before the first one ends
+        held.press(0, 1, 60);
+        held.press(0, 1, 60);
+        held.press(0, 2, 60);
+        // the first C ends, but the second is still sounding
+        assert!(!held.release(0, 1, 60));
+        // the same key on another channel is separate
+        assert!(held.release(0, 2, 60));
+        assert!(held.release(0, 1, 60));
+        // nothing is held anymore, so a stray release still turns the note off
+        assert!(held.release(0, 1, 60));
+
+        held.press(0, 1, 60);
+        held.press(0, 1, 60);
+        held.release_channel(0, 1);
+        assert!(held.release(0, 1, 60));
+    }
+
+    #[test]
+    fn test_choke_group() {
+        let mut player = MidiPlayer {
+            name: "test".to_string(),
+            port_channel_mapping: [(Instrument::Shaker1, (0, 9)), (Instrument::Shaker2, (0, 10)), (Instrument::HiHatOpen, (0, 11))].into_iter().collect(),
+            instrument_mapping: HashMap::new(),
+            conn: Arc::new(HashMap::new()),
+            start_time: None,
+            running_status: false,
+            control_channel: None,
+            programs_sent: HashSet::new(),
+            held_notes: Arc::new(Mutex::new(HeldNotes::default())),
+            choke_notes: HashMap::new(),
+        };
+        assert_eq!(player.note_start_messages(Instrument::Shaker1, 70, 100), Some((0, vec![
+            note_on_message(9, 70, 100),
+        ])));
+        // another group doesn't cut off the shaker
+        assert_eq!(player.note_start_messages(Instrument::HiHatOpen, 46, 100), Some((0, vec![
+            note_on_message(11, 46, 100),
+        ])));
+        assert_eq!(player.note_start_messages(Instrument::Shaker2, 82, 100), Some((0, vec![
+            note_off_message(9, 70, 0, false),
+            note_on_message(10, 82, 100),
+        ])));
+        // the same instrument again doesn't choke itself
+        assert_eq!(player.note_start_messages(Instrument::Shaker2, 82, 100), Some((0, vec![
+            note_on_message(10, 82, 100),
+        ])));
+        // once the shaker was cut off, its scheduled release has nothing left to hold
+        assert!(player.held_notes.lock().unwrap().release(0, 9, 70));
+        assert!(!player.held_notes.lock().unwrap().release(0, 10, 82));
+    }
+
+    #[test]
+    fn test_control_channel() {
+        let player = MidiPlayer {
+            name: "test".to_string(),
+            port_channel_mapping: [(Instrument::Piano, (2, 1))].into_iter().collect(),
+            instrument_mapping: [(Instrument::Piano, 4)].into_iter().collect(),
+            conn: Arc::new(HashMap::new()),
+            start_time: None,
+            running_status: false,
+            control_channel: None,
+            programs_sent: HashSet::new(),
+            held_notes: Arc::new(Mutex::new(HeldNotes::default())),
+            choke_notes: HashMap::new(),
+        };
+        let mut player = player.with_control_channel(Some(15));
+        assert_eq!(player.note_start_messages(Instrument::Piano, 60, 100), Some((2, vec![
+            program_change_message(15, 4),
+            note_on_message(1, 60, 100),
+        ])));
+        // the program is only sent once
+        assert_eq!(player.note_start_messages(Instrument::Piano, 62, 100), Some((2, vec![
+            note_on_message(1, 62, 100),
+        ])));
+
+        let mut player = player.with_control_channel(None);
+        player.programs_sent.clear();
+        assert_eq!(player.note_start_messages(Instrument::Piano, 60, 100).unwrap().1[0], program_change_message(1, 4));
+        assert_eq!(player.note_start_messages(Instrument::Snare, 60, 100), None);
+    }
+
+    #[test]
+    fn test_voice_pool_steals_oldest() {
+        // (id, still playing)
+        let mut pool = VoicePool::new(4);
+        let mut made = 0;
+        for _i in 0..4 {
+            let voice = pool.allocate(|v: &(usize, bool)| !v.1, || {
+                made += 1;
+                (made, true)
+            });
+            assert_eq!(voice.0, made);
+        }
+        assert_eq!(pool.voice_count(), 4);
+        // all four are busy, so the fifth note takes the oldest voice
+        let voice = pool.allocate(|v| !v.1, || unreachable!());
+        assert_eq!(voice.0, 1);
+        assert_eq!(pool.voice_count(), 4);
+        // a finished voice is reused before stealing
+        pool.voices[2].1 = false;
+        let finished = pool.voices[2].0;
+        assert_eq!(pool.allocate(|v| !v.1, || unreachable!()).0, finished);
+    }
+}
@@ -0,0 +1,136 @@
+use std::f32::consts::FRAC_PI_4;
+use std::path::Path;
+use rodio::source::UniformSourceIterator;
+use crate::player::{AtomicSound, Pan};
+use crate::scheduler::{get_sine_source_with, SynthOptions};
+use crate::time::Seconds;
+
+/// Samples per second of rendered audio.
+pub const RENDER_SAMPLE_RATE: u32 = 48000;
+
+/// How long a sound rings past its duration while fading out.
+/// The fade out never ends by itself; live playback cuts it off with the next sound.
+const RELEASE: Seconds = 0.04;
+
+/// Left and right gains for `pan`, with constant-power panning
+/// so that a sound is about as loud in the middle as it is to one side.
+pub fn pan_gains(pan: Pan) -> (f32, f32) {
+    let angle = (pan.clamp(-1., 1.) + 1.) * FRAC_PI_4;
+    // cos(pi/2) rounds to just below zero, which would leak hard right into the left channel
+    (angle.cos().max(0.), angle.sin())
+}
+
+/// Synthesize every sound the way local playback would and add it into `channels`
+/// at its start time, with the gain for each channel from `gains`.
+fn mix<const N: usize>(sounds: &[AtomicSound], options: &SynthOptions, gains: impl Fn(&AtomicSound) -> [f32; N]) -> [Vec<f32>; N] {
+    let mut channels: [Vec<f32>; N] = std::array::from_fn(|_| vec![]);
+    for sound in sounds {
+        let source = get_sine_source_with(sound.duration.max(0.), sound.pitch.to_frequency(), *options);
+        let source: UniformSourceIterator<_, f32> = UniformSourceIterator::new(source, 1, RENDER_SAMPLE_RATE);
+        let offset = (sound.start.max(0.) * RENDER_SAMPLE_RATE as f32).round() as usize;
+        let length = ((sound.duration.max(0.) + RELEASE) * RENDER_SAMPLE_RATE as f32).round() as usize;
+        let gains = gains(sound).map(|gain| gain * sound.volume.as_f32());
+        for (i, sample) in source.take(length).enumerate() {
+            for (channel, gain) in channels.iter_mut().zip(gains) {
+                if channel.len() <= offset + i {
+                    channel.resize(offset + i + 1, 0.);
+                }
+                channel[offset + i] += sample * gain;
+            }
+        }
+    }
+    channels
+}
+
+/// Render `sounds` to mono samples at `RENDER_SAMPLE_RATE`. Pan is ignored.
+pub fn render_samples(sounds: &[AtomicSound], options: &SynthOptions) -> Vec<f32> {
+    let [mono] = mix(sounds, options, |_sound| [1.]);
+    mono
+}
+
+/// Render `sounds` to left and right samples at `RENDER_SAMPLE_RATE`, placing each by its pan.
+pub fn render_samples_stereo(sounds: &[AtomicSound], options: &SynthOptions) -> (Vec<f32>, Vec<f32>) {
+    let [left, right] = mix(sounds, options, |sound| {
+        let (left, right) = pan_gains(sound.pan);
+        [left, right]
+    });
+    (left, right)
+}
+
+fn wav_spec(channels: u16) -> hound::WavSpec {
+    hound::WavSpec {
+        channels,
+        sample_rate: RENDER_SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    }
+}
+
+/// Render `sounds` to a mono 32-bit float WAV file.
+pub fn render_to_wav(sounds: &[AtomicSound], path: impl AsRef<Path>, options: &SynthOptions) -> Result<(), hound::Error> {
+    let mut writer = hound::WavWriter::create(path, wav_spec(1))?;
+    for sample in render_samples(sounds, options) {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()
+}
+
+/// Render `sounds` to a stereo 32-bit float WAV file, panned like they are over MIDI.
+pub fn render_to_wav_stereo(sounds: &[AtomicSound], path: impl AsRef<Path>, options: &SynthOptions) -> Result<(), hound::Error> {
+    let mut writer = hound::WavWriter::create(path, wav_spec(2))?;
+    let (left, right) = render_samples_stereo(sounds, options);
+    for (l, r) in left.into_iter().zip(right) {
+        writer.write_sample(l)?;
+        writer.write_sample(r)?;
+    }
+    writer.finalize()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::composition::{Instrument, Pitch, Volume};
+    use crate::player::AtomicSound;
+    use crate::render::{pan_gains, render_samples_stereo, render_to_wav_stereo, RENDER_SAMPLE_RATE};
+    use crate::scheduler::SynthOptions;
+
+    fn sound(start: f32, pan: f32) -> AtomicSound {
+        AtomicSound {
+            start,
+            duration: 0.25,
+            volume: Volume(100),
+            pitch: Pitch(4, 9),
+            instrument: Instrument::SineWave,
+            pan,
+        }
+    }
+
+    #[test]
+    fn test_pan_gains() {
+        assert_eq!(pan_gains(-1.), (1., 0.));
+        let (left, right) = pan_gains(0.);
+        assert!((left - right).abs() < 1e-6);
+        assert!((left * left + right * right - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_hard_left_only_in_left_channel() {
+        let (left, right) = render_samples_stereo(&[sound(0., -1.)], &SynthOptions::default());
+        assert!(left.iter().any(|s| s.abs() > 0.01));
+        assert!(right.iter().all(|s| *s == 0.));
+        // the second sound starts half a second in, so only it is heard there
+        let (left, right) = render_samples_stereo(&[sound(0., -1.), sound(0.5, 1.)], &SynthOptions::default());
+        let late = (RENDER_SAMPLE_RATE / 2) as usize;
+        assert!(left[late..].iter().all(|s| *s == 0.));
+        assert!(right[late..].iter().any(|s| s.abs() > 0.01));
+    }
+
+    #[test]
+    fn test_render_to_wav_stereo() {
+        let path = std::env::temp_dir().join(format!("music-turtles-render-{}.wav", std::process::id()));
+        render_to_wav_stereo(&[sound(0., -1.)], &path, &SynthOptions::default()).unwrap();
+        let reader = hound::WavReader::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert!(reader.duration() >= RENDER_SAMPLE_RATE / 4);
+    }
+}
@@ -1,9 +1,13 @@
+use std::cmp::Ordering;
 use std::time::Duration;
 use rodio::Source;
 use rodio::source::SineWave;
-use crate::composition::{Composition, Frequency, Instrument, Pitch, Track, Volume};
-use crate::player::{AtomicSound, Playable};
-use crate::time::{MusicTime, Seconds, TimeSignature, BPM};
+use serde::Serialize;
+use crate::composition::{Composition, Frequency, Instrument, Pitch, Track, TrackId, Volume};
+use crate::player::{AtomicSound, Pan, Playable};
+use crate::time::{Beat, LiveTempo, MusicTime, Seconds, TimeSignature, BPM};
+use num::rational::Ratio;
+use num::Zero;
 
 pub type Cursor = MusicTime;
 
@@ -13,67 +17,272 @@ pub struct Scheduler {
     pub tracks: Vec<(Track, Cursor)>,
     pub lookahead: MusicTime,
     pub looped: bool,
+    /// How many times to play through the loop before stopping, or `None` to loop forever.
+    /// Only used when `looped`.
+    pub loop_count: Option<u32>,
     pub loop_time: MusicTime,
+    /// Tempo changes, in order, from `Composition::tempo_map`. `bpm` is used before the first one.
+    pub tempo_map: Vec<(MusicTime, BPM)>,
 }
 
-#[derive(Debug, PartialOrd, PartialEq)]
+/// A cheap copy of the scheduler's playback state, so it can be inspected
+/// without holding the scheduler's lock.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SchedulerSnapshot {
+    pub bpm: BPM,
+    pub time_signature: TimeSignature,
+    pub looped: bool,
+    pub loop_time: MusicTime,
+    pub ended: bool,
+    pub cursors: Vec<TrackCursor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackCursor {
+    pub identifier: TrackId,
+    pub instrument: Instrument,
+    pub cursor: Cursor,
+}
+
+/// A sound to play, timed in music rather than seconds so that it can be
+/// converted to real time when it's played, with whatever the tempo is then.
+#[derive(Debug, Serialize)]
 pub struct ScheduledSound {
-    time: Seconds,
-    duration: Seconds,
+    /// from when playback started, counting every time around the loop
+    time: MusicTime,
+    duration: MusicTime,
+    time_signature: TimeSignature,
+    /// the tempo when it was scheduled, for converting without a `LiveTempo`
+    bpm: BPM,
     volume: Volume,
     instrument: Instrument,
-    pitch: Pitch
+    pitch: Pitch,
+    pan: Pan,
+}
+
+/// Sounds are ordered by time, then instrument, then pitch,
+/// so that simultaneous sounds always come out in the same order.
+impl Ord for ScheduledSound {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.time.cmp(&other.time)
+            .then(self.instrument.cmp(&other.instrument))
+            .then(self.pitch.cmp(&other.pitch))
+            .then(self.duration.cmp(&other.duration))
+            .then(self.volume.cmp(&other.volume))
+            .then(self.pan.total_cmp(&other.pan))
+    }
+}
+
+impl PartialOrd for ScheduledSound {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ScheduledSound {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScheduledSound {}
+
+/// How loud a sine wave is depending on its frequency.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum AmplitudeModel {
+    /// Every frequency at full amplitude.
+    Flat,
+    /// `132 / frequency`, capped at full amplitude (so everything above ~C3 gets quieter).
+    /// High notes end up much quieter than they sound.
+    #[default]
+    FrequencyWeighted,
+    /// Undo A-weighting so that notes sound about equally loud.
+    /// Frequencies the ear is most sensitive to are turned down by up to 12dB.
+    EqualLoudness,
+}
+
+impl AmplitudeModel {
+    pub fn gain(&self, frequency: Frequency) -> f32 {
+        match self {
+            AmplitudeModel::Flat => 1.0,
+            AmplitudeModel::FrequencyWeighted => (3.0 * 44.0 / frequency).clamp(0.0, 1.0),
+            AmplitudeModel::EqualLoudness => {
+                let max_cut = 12.0;
+                let weight = a_weighting(frequency).clamp(-max_cut, 0.0);
+                10f32.powf((-weight - max_cut) / 20.0)
+            }
+        }
+    }
+}
+
+/// A-weighting in decibels, relative to 1kHz.
+fn a_weighting(frequency: Frequency) -> f32 {
+    let f2 = frequency * frequency;
+    let response = 12194f32.powi(2) * f2 * f2
+        / ((f2 + 20.6f32.powi(2))
+            * ((f2 + 107.7f32.powi(2)) * (f2 + 737.9f32.powi(2))).sqrt()
+            * (f2 + 12194f32.powi(2)));
+    20.0 * response.log10() + 2.0
+}
+
+/// How sine sounds are synthesized for local playback.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SynthOptions {
+    pub amplitude: AmplitudeModel,
+    /// Fade each sound in and out over 40ms to avoid clicks.
+    pub fade: bool,
+}
+
+impl Default for SynthOptions {
+    fn default() -> Self {
+        SynthOptions {
+            amplitude: AmplitudeModel::default(),
+            fade: true,
+        }
+    }
 }
 
 pub fn get_sine_source(length: Seconds, frequency: Frequency) -> impl Source<Item=f32> {
-    let sources: Vec<Box<dyn Source<Item=f32> + Send>> = vec![
-        Box::new(
-            SineWave::new(frequency)
-                .take_duration(Duration::from_secs_f32(length))
-                .fade_in(Duration::from_millis(40))
-        ),
-        Box::new(
-            SineWave::new(frequency).fade_out(Duration::from_millis(40))
-        )
-    ];
+    get_sine_source_with(length, frequency, SynthOptions::default())
+}
+
+pub fn get_sine_source_with(length: Seconds, frequency: Frequency, options: SynthOptions) -> impl Source<Item=f32> {
+    let sources: Vec<Box<dyn Source<Item=f32> + Send>> = if options.fade {
+        vec![
+            Box::new(
+                SineWave::new(frequency)
+                    .take_duration(Duration::from_secs_f32(length))
+                    .fade_in(Duration::from_millis(40))
+            ),
+            Box::new(
+                SineWave::new(frequency).fade_out(Duration::from_millis(40))
+            )
+        ]
+    } else {
+        vec![
+            Box::new(
+                SineWave::new(frequency)
+                    .take_duration(Duration::from_secs_f32(length))
+            )
+        ]
+    };
 
     rodio::source::from_iter(sources)
-        .amplify((3.0 * 44.0 / frequency).clamp(0.0, 1.0))
+        .amplify(options.amplitude.gain(frequency))
+}
+
+impl ScheduledSound {
+    /// Convert to real time at `tempo`, which may have changed since this was scheduled.
+    pub fn to_atomic(&self, tempo: &LiveTempo) -> AtomicSound {
+        let start = tempo.to_seconds(self.time, self.time_signature);
+        let end = tempo.to_seconds(self.time.with(self.time_signature) + self.duration, self.time_signature);
+        AtomicSound {
+            start,
+            duration: end - start,
+            volume: self.volume,
+            pitch: self.pitch,
+            instrument: self.instrument,
+            pan: self.pan,
+        }
+    }
 }
 
 impl Playable for ScheduledSound {
-    /// start time, duration, and actual sound
-    fn get_source(&self) -> (Seconds, Seconds, Box<dyn Source<Item=f32> + Send + 'static>) {
-        let source = get_sine_source(self.duration, self.pitch.to_frequency());
+    /// start time, duration, and actual sound, at the tempo it was scheduled at
+    fn get_source(&self, options: &SynthOptions) -> (Seconds, Seconds, Box<dyn Source<Item=f32> + Send + 'static>) {
+        let sound = self.to_atomic(&LiveTempo::new(self.bpm));
+        let source = get_sine_source_with(sound.duration, self.pitch.to_frequency(), *options);
         (
-            self.time,
-            self.duration,
+            sound.start,
+            sound.duration,
             Box::new(source)
         )
     }
 }
 
+/// Converts at the tempo the sound was scheduled at.
 impl From<ScheduledSound> for AtomicSound {
     fn from(value: ScheduledSound) -> Self {
-        AtomicSound {
-            start: value.time,
-            duration: value.duration,
-            volume: value.volume,
-            pitch: value.pitch,
-            instrument: value.instrument,
-        }
+        value.to_atomic(&LiveTempo::new(value.bpm))
     }
 }
 
+/// `time` moved forward by whole loops of `loop_time` until it's at or after `position`.
+fn next_loop_time(time: MusicTime, position: MusicTime, loop_time: MusicTime, time_signature: TimeSignature) -> MusicTime {
+    let loop_beats = loop_time.with(time_signature).total_beats().as_ratio();
+    if time >= position || loop_beats == Ratio::zero() {
+        return time;
+    }
+    let behind = (position.with(time_signature) - time).with(time_signature).total_beats().as_ratio();
+    let loops = (behind / loop_beats).ceil();
+    time.with(time_signature) + Beat::from_ratio(loop_beats * loops).as_music_time(time_signature)
+}
+
 impl Scheduler {
 
     pub fn set_composition(&mut self, composition: Composition) {
         self.time_signature = composition.time_signature;
+        self.tempo_map = composition.tempo_map;
         self.tracks = composition.tracks.into_iter()
             .map(|t| (t, MusicTime::zero()))
             .collect();
     }
-    
+
+    /// Start the track at `track_index` from `pos` rather than the beginning, ex. for staggered entrances.
+    /// The track stays silent until playback reaches `pos`, and what comes before it is only played
+    /// when looping back around. Does nothing if there's no such track.
+    pub fn set_track_cursor(&mut self, track_index: usize, pos: MusicTime) {
+        if let Some((_, cursor)) = self.tracks.get_mut(track_index) {
+            *cursor = pos;
+        }
+    }
+
+    /// The tempo at `position` since playback started, from the tempo map, or `bpm` before any change.
+    /// When looped, the tempo map starts over every time around.
+    pub fn bpm_at(&self, position: MusicTime) -> BPM {
+        let position = if self.looped {
+            position.rem(self.loop_time, self.time_signature)
+        } else {
+            position
+        };
+        self.tempo_map.iter()
+            .take_while(|(time, _bpm)| *time <= position)
+            .last()
+            .map_or(self.bpm, |(_time, bpm)| *bpm)
+    }
+
+    /// How many full times around the loop have been played by `position` since playback started.
+    fn loops_completed(&self, position: MusicTime) -> u32 {
+        let loop_beats = self.loop_time.with(self.time_signature).total_beats().as_ratio();
+        if loop_beats == Ratio::zero() {
+            return 0;
+        }
+        let beats = position.with(self.time_signature).total_beats().as_ratio();
+        (beats / loop_beats).floor().to_integer()
+    }
+
+    /// Whether `loop_count` passes are done by `position`. A count of zero plays once, like one.
+    fn loops_finished(&self, position: MusicTime) -> bool {
+        self.looped && self.loop_count.is_some_and(|count| self.loops_completed(position) >= count.max(1))
+    }
+
+    pub fn snapshot(&self) -> SchedulerSnapshot {
+        SchedulerSnapshot {
+            bpm: self.bpm,
+            time_signature: self.time_signature,
+            looped: self.looped,
+            loop_time: self.loop_time,
+            ended: self.ended(),
+            cursors: self.tracks.iter()
+                .map(|(t, cursor)| TrackCursor {
+                    identifier: t.identifier,
+                    instrument: t.instrument,
+                    cursor: *cursor,
+                })
+                .collect(),
+        }
+    }
+
     pub fn ended(&self) -> bool {
         self.tracks.iter()
             .filter_map(|(t, cursor)| 
@@ -82,26 +291,63 @@ impl Scheduler {
             ).all(|b| b)
     }
 
+    /// What's sounding `elapsed` seconds into playback, wrapping around the loop if looped.
+    /// Doesn't move the cursors.
+    pub fn sounding_at(&self, elapsed: Seconds) -> Vec<(Instrument, Pitch)> {
+        let mut time = MusicTime::from_seconds(self.time_signature, self.bpm, elapsed);
+        if self.looped {
+            time = time.rem(self.loop_time, self.time_signature);
+        }
+        self.tracks.iter()
+            .flat_map(|(track, _)| track.get_events_at(time, self.time_signature)
+                .into_iter()
+                .map(|e| (track.instrument, e.pitch)))
+            .collect()
+    }
+
     /// get the next events and update the cursors if necessary
     pub fn get_next_events_and_update(&mut self, current_track_pos: Seconds) -> Vec<ScheduledSound> {
-        let mut current_music_time = MusicTime::from_seconds(self.time_signature, self.bpm, current_track_pos);
+        let position = MusicTime::from_seconds(self.time_signature, self.bpm, current_track_pos);
+        self.get_next_events_at(position)
+    }
+
+    /// `get_next_events_and_update`, for a position in the music since playback started,
+    /// counting every time around the loop. Ex. from a `LiveTempo`, so the tempo can change.
+    /// After the last of `loop_count` passes, nothing more is scheduled.
+    pub fn get_next_events_at(&mut self, position: MusicTime) -> Vec<ScheduledSound> {
+        if self.loops_finished(position) {
+            // so that `ended` knows every track is done
+            let end = position.with(self.time_signature) + self.lookahead;
+            self.tracks.iter_mut().for_each(|(_, cursor)| *cursor = end.max(*cursor));
+            return vec![];
+        }
+        let mut current_music_time = position;
         let loop_end = self.loop_time;
-        while self.looped && current_music_time > loop_end {
-            current_music_time = current_music_time.with(self.time_signature) - loop_end;
+        // wrap positions past the end of the loop into (0, loop_end]
+        let wrap = |time: MusicTime| match time.rem(loop_end, self.time_signature) {
+            wrapped if wrapped == MusicTime::zero() => loop_end,
+            wrapped => wrapped,
+        };
+        if self.looped && current_music_time > loop_end {
+            current_music_time = wrap(current_music_time);
         }
-        let loop_time_s = self.loop_time.to_seconds(self.time_signature, self.bpm);
         let mut end_music_time = current_music_time.with(self.time_signature) + self.lookahead;
         let end_non_looped = end_music_time;
-        let looping = if self.looped && end_music_time > loop_end {
-            while end_music_time > loop_end {
-                end_music_time = end_music_time.with(self.time_signature) - loop_end;
-            }
+        // cursors are never past the end of what's been scheduled, unless they were set there
+        let end_unwrapped = position.with(self.time_signature) + self.lookahead;
+        // on the last pass, run off the end instead of wrapping back around
+        let looping = if self.looped && end_music_time > loop_end && !self.loops_finished(end_unwrapped) {
+            end_music_time = wrap(end_music_time);
             true
         } else {
             false
         };
         let mut sounds = self.tracks.iter_mut()
             .flat_map(|(track, cursor)| {
+                if *cursor > end_unwrapped {
+                    // this track hasn't started yet
+                    return vec![];
+                }
                 let be_exclusive = false; // *cursor != MusicTime::zero();
                 let events = if looping {
                     // if end_non_looped < *cursor {
@@ -121,30 +367,32 @@ impl Scheduler {
                 *cursor = end_music_time;
                 // make sure looped sounds happen afterward
                 events.into_iter()
+                    .filter(|e| !e.is_gap())
                     .map(|e| {
-                        let start = e.start.to_seconds(self.time_signature, self.bpm);
-                        let duration = e.duration.as_music_time(self.time_signature).to_seconds(self.time_signature, self.bpm) * 0.9;
-                        let volume = e.volume;
-                        let instrument = track.instrument;
+                        // a gate is exactly how long to sound; otherwise leave a little gap before the next note
+                        let duration = match e.gate {
+                            Some(gate) => gate.as_music_time(self.time_signature),
+                            None => (e.duration.as_music_time(self.time_signature).with(self.time_signature) * Ratio::new(9, 10)).time,
+                        };
+                        let time = if self.looped {
+                            next_loop_time(e.start, position, loop_end, self.time_signature)
+                        } else {
+                            e.start
+                        };
                         ScheduledSound {
-                            time: start,
+                            time,
                             duration,
-                            volume,
-                            instrument,
+                            time_signature: self.time_signature,
+                            bpm: self.bpm,
+                            volume: e.volume,
+                            instrument: track.instrument,
                             pitch: e.pitch,
+                            pan: 0.0,
                         }
-                    })
-                    .map(|mut se| {
-                        if self.looped {
-                            while se.time < current_track_pos {
-                                se.time += loop_time_s;
-                            }
-                        }
-                        se
                     }).collect::<Vec<_>>()
             })
             .collect::<Vec<_>>();
-        sounds.sort_by(|a: &ScheduledSound, b: &ScheduledSound| a.partial_cmp(b).unwrap());
+        sounds.sort();
         sounds
     }
 }
@@ -152,8 +400,11 @@ impl Scheduler {
 #[cfg(test)]
 mod test {
     use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume};
-    use crate::scheduler::{ScheduledSound, Scheduler};
-    use crate::time::{Beat, Measure, MusicTime, Seconds, TimeSignature};
+    use crate::composition::composition_element_tests::note;
+    use rodio::Source;
+    use crate::player::AtomicSound;
+    use crate::scheduler::{get_sine_source, get_sine_source_with, AmplitudeModel, ScheduledSound, Scheduler, SynthOptions};
+    use crate::time::{Beat, LiveTempo, Measure, MusicTime, Seconds, TimeSignature};
 
     fn comp_template(events: Vec<Event>) -> Composition {
         Composition {
@@ -166,6 +417,7 @@ mod test {
                 }
             ],
             time_signature: TimeSignature::common(),
+            tempo_map: vec![],
         }
     }
 
@@ -190,24 +442,36 @@ mod test {
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(0, Beat::whole(1)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(0, Beat::whole(2)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 2),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(0, Beat::whole(3)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 3),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         let mut scheduler = Scheduler {
@@ -216,7 +480,9 @@ mod test {
             tracks: vec![],
             lookahead: MusicTime::measures(1),
             looped: false,
+            loop_count: None,
             loop_time: MusicTime::measures(4),
+            tempo_map: vec![],
         };
         scheduler.set_composition(comp);
         let sounds = simulate_play_collect_events(scheduler, 5.0, 0.05);
@@ -232,24 +498,36 @@ mod test {
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(0, Beat::whole(3)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 3),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(0, Beat::whole(2)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 2),
+                gate: None,
+                tag: None,
+                gap: false,
             },
             Event {
                 start: MusicTime(0, Beat::whole(1)),
                 duration: Beat::whole(1),
                 volume: Volume(100),
                 pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
             }
         ]);
         let mut scheduler = Scheduler {
@@ -258,7 +536,9 @@ mod test {
             tracks: vec![],
             lookahead: MusicTime::measures(1),
             looped: false,
+            loop_count: None,
             loop_time: MusicTime::measures(4),
+            tempo_map: vec![],
         };
         scheduler.set_composition(comp);
         let sounds = simulate_play_collect_events(scheduler, 5.0, 0.05);
@@ -266,4 +546,359 @@ mod test {
         assert_eq!(sounds.iter().map(|s| s.pitch).collect::<Vec<_>>(),
                    vec![Pitch(4, 0), Pitch(4, 1), Pitch(4, 2), Pitch(4, 3)]);
     }
+
+    fn peak(source: impl Source<Item=f32>) -> f32 {
+        // one channel, 0.1s in, past the fade
+        source.skip(4_800).take(4_800).fold(0.0, |peak: f32, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn test_flat_amplitude() {
+        let options = SynthOptions { amplitude: AmplitudeModel::Flat, fade: true };
+        let low = peak(get_sine_source_with(1.0, Pitch(2, 0).to_frequency(), options));
+        let high = peak(get_sine_source_with(1.0, Pitch(6, 0).to_frequency(), options));
+        assert!((low - high).abs() < 0.01, "low peak {low}, high peak {high}");
+        // the default curve makes the high note much quieter
+        let default_high = peak(get_sine_source(1.0, Pitch(6, 0).to_frequency()));
+        assert!(default_high < high / 4.);
+    }
+
+    #[test]
+    fn test_simultaneous_sounds_order() {
+        let chord = |pitch| note(MusicTime::zero(), Beat::whole(1), pitch);
+        let mut comp = comp_template(vec![chord(Pitch(4, 7)), chord(Pitch(4, 0))]);
+        comp.tracks.insert(0, Track {
+            identifier: TrackId::Instrument(Instrument::Piano),
+            instrument: Instrument::Piano,
+            events: vec![chord(Pitch(3, 0))],
+            rests: vec![],
+        });
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::measures(1),
+            looped: false,
+            loop_count: None,
+            loop_time: MusicTime::measures(1),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(comp);
+        let sounds = scheduler.get_next_events_and_update(0.);
+        assert_eq!(sounds.iter().map(|s| (s.instrument, s.pitch)).collect::<Vec<_>>(),
+                   vec![
+                       (Instrument::SineWave, Pitch(4, 0)),
+                       (Instrument::SineWave, Pitch(4, 7)),
+                       (Instrument::Piano, Pitch(3, 0)),
+                   ]);
+    }
+
+    #[test]
+    fn test_gaps_are_not_played() {
+        let gap = Event { volume: Volume(0), gap: true, ..note(MusicTime::beats(1), Beat::whole(1), Pitch(4, 0)) };
+        let silent = Event { volume: Volume(0), ..note(MusicTime::beats(2), Beat::whole(1), Pitch(4, 0)) };
+        let comp = comp_template(vec![note(MusicTime::zero(), Beat::whole(1), Pitch(4, 0)), gap, silent]);
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::measures(1),
+            looped: false,
+            loop_count: None,
+            loop_time: MusicTime::measures(1),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(comp);
+        let sounds = scheduler.get_next_events_and_update(0.);
+        // a silent note is still scheduled, only the gap is left out
+        assert_eq!(sounds.iter().map(|s| s.time).collect::<Vec<_>>(), vec![MusicTime::zero(), MusicTime::beats(2)]);
+    }
+
+    #[test]
+    fn test_into_atomic_sound() {
+        let sound = ScheduledSound {
+            time: MusicTime(0, Beat::whole(3)),
+            duration: MusicTime(0, Beat::new(1, 2)),
+            time_signature: TimeSignature::common(),
+            bpm: 120.0,
+            volume: Volume(80),
+            instrument: Instrument::Piano,
+            pitch: Pitch(4, 3),
+            pan: -0.5,
+        };
+        let atomic: AtomicSound = sound.into();
+        assert_eq!(atomic.start, 1.5);
+        assert_eq!(atomic.duration, 0.25);
+        assert_eq!(atomic.volume, Volume(80));
+        assert_eq!(atomic.instrument, Instrument::Piano);
+        assert_eq!(atomic.pitch, Pitch(4, 3));
+        assert_eq!(atomic.pan, -0.5);
+    }
+
+    #[test]
+    fn test_looped_sounds_come_after_position() {
+        let comp = comp_template(vec![
+            Event {
+                start: MusicTime(0, Beat::whole(1)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
+            },
+        ]);
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::beats(1),
+            looped: true,
+            loop_count: None,
+            loop_time: MusicTime::measures(1),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(comp);
+        // 100 loops and 2 beats in, so the note's next time around is in the next loop
+        let sounds = scheduler.get_next_events_at(MusicTime(100, Beat::whole(2)));
+        assert_eq!(sounds.len(), 1);
+        assert_eq!(sounds[0].time, MusicTime(101, Beat::whole(1)));
+        assert_eq!(sounds[0].duration, MusicTime(0, Beat::new(9, 10)));
+    }
+
+    #[test]
+    fn test_loop_count() {
+        let comp = comp_template((0..4)
+            .map(|beat| Event {
+                start: MusicTime(0, Beat::whole(beat)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, beat as u8),
+                gate: None,
+                tag: None,
+                gap: false,
+            })
+            .collect());
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::beats(1),
+            looped: true,
+            loop_count: Some(2),
+            loop_time: MusicTime::measures(1),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(comp);
+        // four times as long as the loop, half a beat at a time
+        let mut sounds = vec![];
+        for half_beats in 0..32 {
+            let position = Beat::new(half_beats, 2).as_music_time(TimeSignature::common());
+            sounds.extend(scheduler.get_next_events_at(position));
+            if half_beats == 12 {
+                // partway through the second pass
+                assert!(!scheduler.ended());
+            }
+        }
+        assert!(scheduler.ended());
+        // events on the edge of the lookahead can be scheduled twice
+        let mut played: Vec<_> = sounds.iter().map(|s| (s.time, s.pitch.1)).collect();
+        played.sort();
+        played.dedup();
+        assert_eq!(played, (0..8)
+            .map(|beat| (Beat::whole(beat).as_music_time(TimeSignature::common()), (beat % 4) as u8))
+            .collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_gate_releases_before_slot_ends() {
+        let comp = comp_template(vec![
+            Event {
+                start: MusicTime::zero(),
+                duration: Beat::whole(2),
+                volume: Volume(100),
+                pitch: Pitch(4, 0),
+                gate: Some(Beat::new(1, 2)),
+                tag: None,
+                gap: false,
+            },
+        ]);
+        let time_signature = TimeSignature::common();
+        assert_eq!(comp.tracks[0].events[0].get_end(time_signature), MusicTime::beats(2));
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature,
+            tracks: vec![],
+            lookahead: MusicTime::beats(1),
+            looped: false,
+            loop_count: None,
+            loop_time: MusicTime::measures(1),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(comp);
+        let sounds = scheduler.get_next_events_at(MusicTime::zero());
+        assert_eq!(sounds.len(), 1);
+        assert_eq!(sounds[0].duration, MusicTime(0, Beat::new(1, 2)));
+        let atomic = sounds[0].to_atomic(&LiveTempo::new(120.));
+        // released after half a beat, well before the two beat slot is over
+        assert_eq!(atomic.duration, 0.25);
+    }
+
+    #[test]
+    fn test_staggered_track_cursor() {
+        let comp = comp_template((0..4)
+            .map(|beat| Event {
+                start: MusicTime(0, Beat::whole(beat)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, beat as u8),
+                gate: None,
+                tag: None,
+                gap: false,
+            })
+            .collect());
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::beats(1),
+            looped: true,
+            loop_count: None,
+            loop_time: MusicTime::measures(1),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(comp);
+        scheduler.set_track_cursor(0, MusicTime::beats(2));
+        // twice around the loop, half a beat at a time
+        let mut sounds = vec![];
+        for half_beats in 0..16 {
+            let position = Beat::new(half_beats, 2).as_music_time(TimeSignature::common());
+            sounds.extend(scheduler.get_next_events_at(position));
+        }
+        // events on the edge of the lookahead can be scheduled twice
+        let mut played: Vec<_> = sounds.iter()
+            .map(|s| (s.time, s.pitch.1))
+            .filter(|(time, _)| *time < MusicTime::measures(2))
+            .collect();
+        played.sort();
+        played.dedup();
+        assert_eq!(played, vec![
+            (MusicTime(0, Beat::whole(2)), 2),
+            (MusicTime(0, Beat::whole(3)), 3),
+            (MusicTime(1, Beat::whole(0)), 0),
+            (MusicTime(1, Beat::whole(1)), 1),
+            (MusicTime(1, Beat::whole(2)), 2),
+            (MusicTime(1, Beat::whole(3)), 3),
+        ]);
+    }
+
+    #[test]
+    fn test_bpm_at() {
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::measures(1),
+            looped: false,
+            loop_count: None,
+            loop_time: MusicTime::measures(2),
+            tempo_map: vec![],
+        };
+        let mut composition = comp_template(vec![]);
+        composition.tempo_map = vec![(MusicTime::beats(2), 90.0), (MusicTime::measures(1), 60.0)];
+        scheduler.set_composition(composition);
+        assert_eq!(scheduler.bpm_at(MusicTime::zero()), 120.0);
+        assert_eq!(scheduler.bpm_at(MusicTime::beats(2)), 90.0);
+        assert_eq!(scheduler.bpm_at(MusicTime(1, Beat::whole(3))), 60.0);
+        assert_eq!(scheduler.bpm_at(MusicTime::measures(5)), 60.0);
+        scheduler.looped = true;
+        assert_eq!(scheduler.bpm_at(MusicTime(4, Beat::whole(1))), 120.0);
+        assert_eq!(scheduler.bpm_at(MusicTime(4, Beat::whole(3))), 90.0);
+    }
+
+    #[test]
+    fn test_snapshot_tracks_cursor() {
+        let comp = comp_template(vec![
+            Event {
+                start: MusicTime(0, Beat::whole(0)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
+            },
+            Event {
+                start: MusicTime(1, Beat::whole(0)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
+            }
+        ]);
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::measures(1),
+            looped: false,
+            loop_count: None,
+            loop_time: MusicTime::measures(2),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(comp);
+        let before = scheduler.snapshot();
+        assert_eq!(before.cursors.len(), 1);
+        assert_eq!(before.cursors[0].cursor, MusicTime::zero());
+        scheduler.get_next_events_and_update(0.0);
+        let after = scheduler.snapshot();
+        assert_eq!(after.cursors[0].cursor, MusicTime::measures(1));
+        assert!(!after.ended);
+        assert_eq!(before.bpm, after.bpm);
+    }
+
+    #[test]
+    fn test_sounding_at() {
+        let comp = comp_template(vec![
+            Event {
+                start: MusicTime(0, Beat::whole(0)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, 0),
+                gate: None,
+                tag: None,
+                gap: false,
+            },
+            Event {
+                start: MusicTime(1, Beat::whole(0)),
+                duration: Beat::whole(1),
+                volume: Volume(100),
+                pitch: Pitch(4, 1),
+                gate: None,
+                tag: None,
+                gap: false,
+            }
+        ]);
+        let mut scheduler = Scheduler {
+            bpm: 120.0,
+            time_signature: TimeSignature::common(),
+            tracks: vec![],
+            lookahead: MusicTime::measures(1),
+            looped: false,
+            loop_count: None,
+            loop_time: MusicTime::measures(2),
+            tempo_map: vec![],
+        };
+        scheduler.set_composition(comp);
+        // a beat is half a second
+        let instrument = scheduler.tracks[0].0.instrument;
+        assert_eq!(scheduler.sounding_at(0.25), vec![(instrument, Pitch(4, 0))]);
+        assert_eq!(scheduler.sounding_at(1.0), vec![]);
+        assert_eq!(scheduler.sounding_at(2.25), vec![(instrument, Pitch(4, 1))]);
+        assert_eq!(scheduler.sounding_at(4.25), vec![]);
+        scheduler.looped = true;
+        assert_eq!(scheduler.sounding_at(4.25), vec![(instrument, Pitch(4, 0))]);
+    }
 }
\ No newline at end of file
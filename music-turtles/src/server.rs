@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use rocket::http::Status;
+use rocket::response::stream::{Event as SseEvent, EventStream};
+use rocket::serde::json::Json;
+use rocket::tokio::time::{interval, Duration};
+use rocket::{post, routes, Build, Rocket, State};
+use serde::{Deserialize, Serialize};
+use crate::cfg::MusicString;
+use crate::scheduler::Scheduler;
+use crate::time::{MusicTime, TimeSignature, BPM};
+
+/// Request body for starting a live stream of scheduled sounds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamRequest {
+    pub music: String,
+    pub bpm: BPM,
+    #[serde(default)]
+    pub looped: bool,
+    #[serde(default = "default_tick_ms")]
+    pub tick_ms: u64,
+}
+
+fn default_tick_ms() -> u64 {
+    50
+}
+
+/// Keeps track of the running streams so that they can be stopped by id.
+#[derive(Default)]
+pub struct StreamControl {
+    next_id: AtomicUsize,
+    streams: Mutex<HashMap<usize, Arc<AtomicBool>>>,
+}
+
+impl StreamControl {
+    fn register(&self) -> (usize, Arc<AtomicBool>) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let stop = Arc::new(AtomicBool::new(false));
+        self.streams.lock().unwrap().insert(id, Arc::clone(&stop));
+        (id, stop)
+    }
+
+    fn unregister(&self, id: usize) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    fn stop(&self, id: usize) -> bool {
+        if let Some(stop) = self.streams.lock().unwrap().get(&id) {
+            stop.store(true, Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Unregisters a stream when it's dropped, so that streams whose client went away
+/// (which never reach the end of their loop) are forgotten too.
+struct StreamGuard {
+    control: Arc<StreamControl>,
+    id: usize,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.control.unregister(self.id);
+    }
+}
+
+/// Drives a `Scheduler` server-side and pushes each batch of `ScheduledSound`s
+/// to the client as a server-sent event. Mirrors `run_midi`, but sends over the
+/// socket instead of to a `MidiPlayer`.
+///
+/// Events sent: `start` (data is the stream id), `sounds` (a JSON array),
+/// and finally `stop` once the stream is stopped or the music ends.
+#[post("/stream", format = "json", data = "<request>")]
+fn stream(request: Json<StreamRequest>, control: &State<Arc<StreamControl>>) -> Result<EventStream![], Status> {
+    let request = request.into_inner();
+    // a zero tick can't be timed, and a tempo that doesn't move forward never ends
+    if request.tick_ms == 0 || !(request.bpm.is_finite() && request.bpm > 0.) {
+        warn!("Bad stream request: tick_ms {}, bpm {}", request.tick_ms, request.bpm);
+        return Err(Status::BadRequest);
+    }
+    let music_string = MusicString::from_str(&request.music)
+        .map_err(|e| {
+            warn!("Error parsing music string: {:?}", e);
+            Status::BadRequest
+        })?;
+    let composition = music_string.compose(TimeSignature::common(), None)
+        .map_err(|e| {
+            warn!("Error composing music string: {:?}", e);
+            Status::UnprocessableEntity
+        })?;
+    let mut scheduler = Scheduler {
+        bpm: request.bpm,
+        time_signature: composition.time_signature,
+        tracks: vec![],
+        lookahead: MusicTime::measures(1),
+        looped: request.looped,
+        loop_count: None,
+        loop_time: composition.span_from_zero(),
+        tempo_map: vec![],
+    };
+    scheduler.set_composition(composition);
+    let control = Arc::clone(control.inner());
+    let (id, stop) = control.register();
+    let guard = StreamGuard { control, id };
+    let tick = Duration::from_millis(request.tick_ms);
+    Ok(EventStream! {
+        // owned by the stream, so it's dropped with it even if it's never polled
+        let guard = guard;
+        yield SseEvent::data(guard.id.to_string()).event("start");
+        let start_time = Instant::now();
+        let mut ticker = interval(tick);
+        loop {
+            if stop.load(Ordering::SeqCst) || scheduler.ended() {
+                break;
+            }
+            let elapsed_s = start_time.elapsed().as_secs_f32();
+            let sounds = scheduler.get_next_events_and_update(elapsed_s);
+            if !sounds.is_empty() {
+                yield SseEvent::json(&sounds).event("sounds");
+            }
+            ticker.tick().await;
+        }
+        yield SseEvent::data(guard.id.to_string()).event("stop");
+    })
+}
+
+/// Stop control message for a running stream.
+#[post("/stream/<id>/stop")]
+fn stop_stream(id: usize, control: &State<Arc<StreamControl>>) -> Status {
+    if control.stop(id) {
+        Status::Ok
+    } else {
+        Status::NotFound
+    }
+}
+
+pub fn rocket() -> Rocket<Build> {
+    rocket::build()
+        .manage(Arc::new(StreamControl::default()))
+        .mount("/", routes![stream, stop_stream])
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+    use std::sync::Arc;
+    use crate::server::{rocket, StreamControl, StreamRequest};
+
+    fn looped_request() -> String {
+        serde_json::to_string(&StreamRequest {
+            music: ":c :d :e :f".to_string(),
+            bpm: 120.0,
+            looped: true,
+            tick_ms: 10,
+        }).unwrap()
+    }
+
+    fn open_streams(client: &Client) -> usize {
+        client.rocket().state::<Arc<StreamControl>>().unwrap().streams.lock().unwrap().len()
+    }
+
+    #[test]
+    fn test_stream_receives_sounds() {
+        let client = Client::tracked(rocket()).unwrap();
+        let request = StreamRequest {
+            music: ":c :d :e :f".to_string(),
+            bpm: 120.0,
+            looped: false,
+            tick_ms: 10,
+        };
+        let response = client.post("/stream")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&request).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("event:start"));
+        assert!(body.contains("event:sounds"));
+        assert!(body.contains("event:stop"));
+    }
+
+    #[test]
+    fn test_stop_looped_stream() {
+        let client = Client::tracked(rocket()).unwrap();
+        let response = client.post("/stream")
+            .header(ContentType::JSON)
+            .body(looped_request())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(client.post("/stream/0/stop").dispatch().status(), Status::Ok);
+        // a looped stream only ends when it's stopped
+        let body = response.into_string().unwrap();
+        assert!(body.contains("event:start"));
+        assert!(body.contains("event:stop"));
+        assert_eq!(open_streams(&client), 0);
+    }
+
+    #[test]
+    fn test_abandoned_stream_unregistered() {
+        let client = Client::tracked(rocket()).unwrap();
+        let response = client.post("/stream")
+            .header(ContentType::JSON)
+            .body(looped_request())
+            .dispatch();
+        assert_eq!(open_streams(&client), 1);
+        // the client goes away without reading or stopping the stream
+        drop(response);
+        assert_eq!(open_streams(&client), 0);
+        assert_eq!(client.post("/stream/0/stop").dispatch().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_bad_tick_or_bpm() {
+        let client = Client::tracked(rocket()).unwrap();
+        let status = |tick_ms, bpm| {
+            let body = format!(r#"{{"music":":c","bpm":{bpm},"tick_ms":{tick_ms}}}"#);
+            client.post("/stream").header(ContentType::JSON).body(body).dispatch().status()
+        };
+        assert_eq!(status(0, "120"), Status::BadRequest);
+        assert_eq!(status(10, "0"), Status::BadRequest);
+        assert_eq!(status(10, "-60"), Status::BadRequest);
+        // too big for an f32, so it's infinite
+        assert_eq!(status(10, "1e39"), Status::BadRequest);
+        assert_eq!(open_streams(&client), 0);
+    }
+
+    #[test]
+    fn test_stop_unknown_stream() {
+        let client = Client::tracked(rocket()).unwrap();
+        let response = client.post("/stream/1000/stop").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
@@ -1,3 +1,5 @@
 
 #[ignore]
 mod playground;
+mod reload;
+mod repl;
@@ -25,7 +25,9 @@ fn compose_something() {
         tracks: vec![],
         lookahead: MusicTime::measures(1),
         looped: false,
+        loop_count: None,
         loop_time: MusicTime::measures(1),
+        tempo_map: vec![],
     };
     scheduler.set_composition(music);
     let player = MidiPlayer::new("test".to_string(), HashMap::new()).unwrap();
@@ -57,7 +59,9 @@ fn run_file_grammar() {
         tracks: vec![],
         lookahead: MusicTime::measures(1),
         looped: false,
+        loop_count: None,
         loop_time: MusicTime::measures(1),
+        tempo_map: vec![],
     };
     scheduler.set_composition(music);
     let player = MidiPlayer::new("test".to_string(), HashMap::new()).unwrap();
@@ -83,48 +87,72 @@ fn a() {
                         duration: Beat::new(1, 1),
                         volume: Volume(20),
                         pitch: Pitch(4, 0),
+                        gate: None,
+                        tag: None,
+                        gap: false,
                     },
                     Event {
                         start: MusicTime(0, Beat::new(1, 1)),
                         duration: Beat::new(1, 1),
                         volume: Volume(20),
                         pitch: Pitch(4, 2),
+                        gate: None,
+                        tag: None,
+                        gap: false,
                     },
                     Event {
                         start: MusicTime(0, Beat::new(2, 1)),
                         duration: Beat::new(1, 1),
                         volume: Volume(20),
                         pitch: Pitch(4, 4),
+                        gate: None,
+                        tag: None,
+                        gap: false,
                     },
                     Event {
                         start: MusicTime(0, Beat::new(3, 1)),
                         duration: Beat::new(1, 1),
                         volume: Volume(20),
                         pitch: Pitch(4, 5),
+                        gate: None,
+                        tag: None,
+                        gap: false,
                     },
                     Event {
                         start: MusicTime(0, Beat::zero()),
                         duration: Beat::new(1, 1),
                         volume: Volume(20),
                         pitch: Pitch(4, 4),
+                        gate: None,
+                        tag: None,
+                        gap: false,
                     },
                     Event {
                         start: MusicTime(0, Beat::new(1, 1)),
                         duration: Beat::new(1, 1),
                         volume: Volume(20),
                         pitch: Pitch(4, 5),
+                        gate: None,
+                        tag: None,
+                        gap: false,
                     },
                     Event {
                         start: MusicTime(0, Beat::new(2, 1)),
                         duration: Beat::new(1, 1),
                         volume: Volume(20),
                         pitch: Pitch(4, 7),
+                        gate: None,
+                        tag: None,
+                        gap: false,
                     },
                     Event {
                         start: MusicTime(0, Beat::new(3, 1)),
                         duration: Beat::new(1, 1),
                         volume: Volume(20),
                         pitch: Pitch(4, 9),
+                        gate: None,
+                        tag: None,
+                        gap: false,
                     }
                 ],
                 rests: vec![],
@@ -132,7 +160,9 @@ fn a() {
         ],
         lookahead: MusicTime(1, Beat::zero()),
         looped: true,
+        loop_count: None,
         loop_time: MusicTime(1, Beat::zero()),
+        tempo_map: vec![],
     };
     run(&mut scheduler, 50, player);
 }
\ No newline at end of file
@@ -0,0 +1,16 @@
+use crate::seeded_reload;
+
+#[test]
+fn test_seeded_reload() {
+    let path = std::env::temp_dir().join(format!("music-turtles-reload-{}.mtx", std::process::id()));
+    std::fs::write(&path, "start S\nS = S S\nS = :c\nS = :d\nS = :e\nS = :f").unwrap();
+    let path = path.to_str().unwrap();
+    let mut reload = seeded_reload(path, "S", 6, 1170);
+    let (_grammar, first) = reload().unwrap();
+    let (_grammar, again) = reload().unwrap();
+    let (_grammar, other) = seeded_reload(path, "S", 6, 1171)().unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(first.to_string(), again.to_string());
+    assert_ne!(first.to_string(), other.to_string());
+}
@@ -0,0 +1,19 @@
+use std::io::Cursor;
+use crate::repl_grammars;
+
+#[test]
+fn test_repl_grammar_then_eof() {
+    let input = Cursor::new("start S\nS = :c :d :e :f\n");
+    let mut compositions = vec![];
+    let composed = repl_grammars(input, "S", 3, |_grammar, music| compositions.push(music));
+    assert_eq!(composed, 1);
+    assert_eq!(compositions.len(), 1);
+    assert_eq!(compositions[0].tracks[0].events.len(), 4);
+}
+
+#[test]
+fn test_repl_skips_bad_grammar() {
+    let input = Cursor::new("@include\nstart S\nS = :c\n.\nstart S\nS = :c\n.\n");
+    let composed = repl_grammars(input, "S", 3, |_grammar, _music| {});
+    assert_eq!(composed, 1);
+}
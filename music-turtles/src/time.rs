@@ -13,6 +13,7 @@ pub type Seconds = f32;
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct MusicTime(pub Measure, pub Beat);
 
+/// Tempo in quarter notes per minute, whatever the time signature.
 pub type BPM = f32;
 
 pub type Measure = u32;
@@ -23,12 +24,25 @@ pub type BeatUnit = u32;
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct Beat(Ratio<BeatUnit>);
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+/// Beats per measure, and the note value of a beat: 6/8 is six eighth-note beats per measure.
+/// The denominator doesn't need to be a power of two, ex. in 3/6 a beat is a sixth of a whole note.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct TimeSignature(pub BeatUnit, pub BeatUnit);
 
 #[derive(Debug, Clone, Copy)]
 pub struct TimeCompression(pub Ratio<isize>);
 
+/// A tempo that can change while music is playing. Converts between positions in the music
+/// and seconds since playback started, as if the current tempo started where it was set.
+/// Positions from before the latest change have already played, so they aren't kept track of.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LiveTempo {
+    bpm: BPM,
+    /// the position, in quarter notes, and time where the current tempo took over
+    anchor_quarters: f64,
+    anchor_seconds: f64,
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct MusicTimeWithSignature {
     pub time: MusicTime,
@@ -44,6 +58,15 @@ impl Beat {
         Beat(Ratio::new(num, 1))
     }
 
+    pub fn from_ratio(ratio: Ratio<BeatUnit>) -> Self {
+        Beat(ratio)
+    }
+
+    /// Exact value of this beat, for arithmetic that shouldn't go through floats.
+    pub fn as_ratio(&self) -> Ratio<BeatUnit> {
+        self.0
+    }
+
     pub fn as_float(&self) -> f32 {
         self.0.to_f32().unwrap_or_else(|| {
             println!("WARNING: Beat {self:?} could not be converted to f32. Defaulting to 0.");
@@ -85,8 +108,8 @@ impl MusicTime {
     }
 
     pub fn from_seconds(time_signature: TimeSignature, bpm: BPM, seconds: Seconds) -> Self {
-        let bps = bpm / 60.;
-        let beats = bps * seconds;
+        let quarters = bpm / 60. * seconds;
+        let beats = quarters / time_signature.quarters_per_beat();
         // instead of using Ratio::from_f32, I'll calculate the fraction myself
         let precision = 1000000.0; // to avoid floating point precision issues
         let numerator = (beats * precision).floor() as BeatUnit;
@@ -104,13 +127,24 @@ impl MusicTime {
     pub fn to_seconds(&self, time_signature: TimeSignature, bpm: BPM) -> Seconds {
         let MusicTime(measures, beats) = *self;
         let total_beats = (measures * time_signature.0) as f32 + beats.as_float();
-        total_beats * 60. / bpm
+        total_beats * time_signature.quarters_per_beat() * 60. / bpm
     }
 
     pub fn zero() -> Self {
         MusicTime(0, Beat::zero())
     }
 
+    /// This position modulo `modulus`, ex. a position within a loop `modulus` long.
+    /// A zero `modulus` leaves the position as it is.
+    pub fn rem(&self, modulus: MusicTime, time_signature: TimeSignature) -> MusicTime {
+        let modulus = modulus.with(time_signature).total_beats();
+        if modulus == Beat::zero() {
+            return *self;
+        }
+        let beats = self.with(time_signature).total_beats();
+        Beat(beats.0 % modulus.0).as_music_time(time_signature)
+    }
+
     pub fn beats(beats: BeatUnit) -> Self {
         MusicTime(0, Beat::whole(beats))
     }
@@ -186,10 +220,54 @@ impl MusicTimeWithSignature {
     }
 }
 
+impl LiveTempo {
+    pub fn new(bpm: BPM) -> Self {
+        LiveTempo { bpm, anchor_quarters: 0., anchor_seconds: 0. }
+    }
+
+    pub fn bpm(&self) -> BPM {
+        self.bpm
+    }
+
+    /// Switch to `bpm`, `at` seconds after playback started.
+    pub fn set_bpm(&mut self, bpm: BPM, at: Seconds) {
+        self.anchor_quarters = self.quarters_at(at);
+        self.anchor_seconds = at as f64;
+        self.bpm = bpm;
+    }
+
+    fn quarters_at(&self, seconds: Seconds) -> f64 {
+        self.anchor_quarters + (seconds as f64 - self.anchor_seconds) * self.bpm as f64 / 60.
+    }
+
+    pub fn to_seconds(&self, time: MusicTime, time_signature: TimeSignature) -> Seconds {
+        let beats = time.with(time_signature).total_beats().as_float() as f64;
+        let quarters = beats * time_signature.quarters_per_beat() as f64;
+        (self.anchor_seconds + (quarters - self.anchor_quarters) * 60. / self.bpm as f64) as Seconds
+    }
+
+    pub fn music_time_at(&self, seconds: Seconds, time_signature: TimeSignature) -> MusicTime {
+        // same precision as MusicTime::from_seconds
+        let precision = 1000000.0;
+        let beats = self.quarters_at(seconds).max(0.) / time_signature.quarters_per_beat() as f64;
+        let beats = (beats * precision).floor() as BeatUnit;
+        Beat::new(beats, precision as BeatUnit).as_music_time(time_signature)
+    }
+}
+
 impl TimeSignature {
     pub fn common() -> Self {
         TimeSignature(4, 4)
     }
+
+    /// How long a beat is in quarter notes, which is what tempos count:
+    /// 1 in 3/4, 1/2 in 6/8 and 2 in 2/2. A zero denominator is treated as 4.
+    pub fn quarters_per_beat(&self) -> f32 {
+        match self.1 {
+            0 => 1.,
+            unit => 4. / unit as f32,
+        }
+    }
 }
 
 impl Serialize for Beat {
@@ -291,4 +369,60 @@ mod test {
         let mt2 = MusicTime(0, Beat::whole(3));
         assert_eq!(mt1.with(ts) - mt2, MusicTime(1, Beat::whole(1)));
     }
+
+    #[test]
+    fn test_beat_ratio_round_trip() {
+        let b = Beat::new(3, 7);
+        assert_eq!(Beat::from_ratio(b.as_ratio()), b);
+    }
+
+    #[test]
+    fn test_beat_ratio_reduced() {
+        assert_eq!(Beat::from_ratio(Ratio::new(2, 4)), Beat::new(1, 2));
+        assert_eq!(Beat::new(2, 4).as_ratio(), Ratio::new(1, 2));
+    }
+
+    #[test]
+    fn test_live_tempo() {
+        let ts = TimeSignature::common();
+        let mut tempo = LiveTempo::new(120.);
+        assert_eq!(tempo.to_seconds(MusicTime::measures(1), ts), 2.);
+        // slow down after a measure
+        tempo.set_bpm(60., 2.);
+        assert_eq!(tempo.to_seconds(MusicTime::measures(1), ts), 2.);
+        assert_eq!(tempo.to_seconds(MusicTime::measures(2), ts), 6.);
+        assert_eq!(tempo.music_time_at(4., ts), MusicTime(1, Beat::whole(2)));
+        // changing again part way through a beat
+        tempo.set_bpm(120., 4.5);
+        assert_eq!(tempo.to_seconds(MusicTime(1, Beat::whole(3)), ts), 4.75);
+    }
+
+    #[test]
+    fn test_beat_value_from_denominator() {
+        // six beats is two measures of 3/4 or one of 6/8, but in 6/8 they're eighth notes
+        let six_beats = MusicTime::from_whole_beats(TimeSignature(3, 4), 6);
+        assert_eq!(six_beats.to_seconds(TimeSignature(3, 4), 120.), 3.);
+        let six_beats = MusicTime::from_whole_beats(TimeSignature(6, 8), 6);
+        assert_eq!(six_beats.to_seconds(TimeSignature(6, 8), 120.), 1.5);
+        assert_eq!(MusicTime::from_seconds(TimeSignature(6, 8), 120., 1.5), MusicTime::measures(1));
+        // a beat in 3/6 is a sixth of a whole note
+        assert_eq!(MusicTime::beats(3).to_seconds(TimeSignature(3, 6), 60.), 2.);
+        let tempo = LiveTempo::new(120.);
+        assert_eq!(tempo.to_seconds(MusicTime::measures(1), TimeSignature(6, 8)), 1.5);
+        assert_eq!(tempo.music_time_at(0.75, TimeSignature(6, 8)), MusicTime::beats(3));
+    }
+
+    #[test]
+    fn test_music_time_rem() {
+        let ts = TimeSignature(3, 4);
+        // a loop of one measure and a half beat, 100 loops and 2 beats in
+        let modulus = MusicTime(1, Beat::new(1, 2));
+        let position = MusicTime(117, Beat::whole(1));
+        assert_eq!(position.rem(modulus, ts), MusicTime(0, Beat::whole(2)));
+        // far enough in that counting off one loop at a time would never finish
+        let deep = MusicTime(Measure::MAX / 8, Beat::whole(1));
+        assert_eq!(deep.rem(MusicTime(1, Beat::whole(1)), ts), MusicTime(0, Beat::whole(2)));
+        assert_eq!(MusicTime(7, Beat::zero()).rem(MusicTime::measures(7), ts), MusicTime::zero());
+        assert_eq!(position.rem(MusicTime::zero(), ts), position);
+    }
 }
\ No newline at end of file